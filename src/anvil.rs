@@ -15,11 +15,11 @@
 
 use bitvec::prelude::*;
 use log::{debug, info, warn};
-use std::{fs::File, io::{Seek, SeekFrom, Write}, time::{Duration, SystemTime}};
+use std::{fmt::Display, fs::File, io::{self, ErrorKind, Seek, SeekFrom, Write}, os::unix::fs::FileExt, time::{Duration, SystemTime}};
 
 pub(crate) const SECTOR_LEN: usize = 0x1000;
 const HEADER_SECTORS: usize = 2;
-const HEADER_LEN: usize = HEADER_SECTORS * SECTOR_LEN;
+pub(crate) const HEADER_LEN: usize = HEADER_SECTORS * SECTOR_LEN;
 pub(crate) const MAX_CHUNK_LEN: usize = SECTOR_LEN * 254;
 const MAX_SECTORS: usize = 2_usize.pow(24) - 1 - HEADER_SECTORS;
 
@@ -33,6 +33,19 @@ pub(crate) fn idx_to_coords(idx: usize) -> (u8, u8) {
     ((idx & 31) as u8, ((idx >> 5) & 31) as u8)
 }
 
+/// Seconds since the epoch as stored in the timestamp table, 0 for anything earlier
+fn to_timestamp(time: SystemTime) -> u32 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs() as u32,
+        Err(_) => 0
+    }
+}
+
+/// `time` as the timestamp table will record it, with whole-second precision
+pub(crate) fn header_time(time: SystemTime) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(to_timestamp(time) as u64)
+}
+
 #[inline(always)]
 fn read_big_endian(raw: &[u8], offset: usize) -> u32 {
     return
@@ -52,11 +65,52 @@ pub(crate) struct RegionFile {
     headers: Box<[ChunkHeader; 32 * 32]>,
     chunk_data: Vec<u8>,
     occupied_sectors: BitVec,
-    dirty_sectors: BitVec
+    /// Sectors freed since the last write-out. They stay marked as occupied until the new header
+    /// is on disk, so that a crash can't leave the old header pointing at reused sectors.
+    released_sectors: BitVec,
+    dirty_sectors: BitVec,
+    /// Keep chunks whose length runs past their sectors, truncated, instead of dropping them
+    lenient: bool,
+    /// Trust a chunk's length over its header's sector count, see [`RegionFile::new_checked`]
+    tolerant: bool,
+    /// Chunks whose headers were invalidated while parsing, which the next write-out deletes
+    invalidated: Vec<(u8, u8)>,
+    /// Problems found while parsing the current header
+    load_warnings: Vec<LoadWarning>,
+    /// Log where each chunk is placed, and how fragmented that leaves the region
+    profile_alloc: bool
+}
+
+/// Result of validating the location and timestamp tables
+struct ParsedHeader {
+    headers: Box<[ChunkHeader; 32 * 32]>,
+    occupied_sectors: BitVec,
+    invalidated: Vec<(u8, u8)>,
+    warnings: Vec<LoadWarning>
 }
 
 impl RegionFile {
-    pub(crate) fn new(data: Vec<u8>) -> Self {
+    /// Parse a whole region file, panicking if it is malformed beyond repair. See
+    /// [`RegionFile::new_checked`].
+    pub(crate) fn new(data: Vec<u8>, lenient: bool) -> Self {
+        match Self::new_checked(data, lenient, false) {
+            Ok(region) => region,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Parse a whole region file. With `lenient`, chunks whose length runs past their sectors
+    /// are kept with whatever data is there, instead of being dropped. With `tolerant`, a header
+    /// giving too few sectors (or none) for its chunk's length is taken to be wrong instead, as
+    /// long as the sectors the length needs are in the file and no other header points at them;
+    /// some converters write such headers.
+    ///
+    /// Never panics: any input either parses or is rejected with an error.
+    pub(crate) fn new_checked(data: Vec<u8>, lenient: bool, tolerant: bool) -> Result<Self, RegionError> {
+        if data.len() < HEADER_LEN {
+            return Err(RegionError::Truncated(data.len()));
+        }
+
         let (header_data, chunk_data, sector_count) = {
             let mut header_data = data;
             let mut chunk_data = header_data.split_off(HEADER_LEN);
@@ -69,24 +123,84 @@ impl RegionFile {
             (header_data, chunk_data, sector_count)
         };
 
-        let mut headers: Vec<ChunkHeader> = Vec::with_capacity(32 * 32);
-        let mut occupied_sectors = false_bitvec(sector_count);
+        let ParsedHeader { headers, occupied_sectors, invalidated, warnings } = Self::parse_header(&header_data, &chunk_data, lenient, tolerant)?;
+        let released_sectors = false_bitvec(sector_count);
         let dirty_sectors = false_bitvec(sector_count);
 
+        Ok(Self {
+            headers,
+            chunk_data,
+            occupied_sectors,
+            released_sectors,
+            dirty_sectors,
+            lenient,
+            tolerant,
+            invalidated,
+            load_warnings: warnings,
+            profile_alloc: false
+        })
+    }
+
+    /// A region without any chunks
+    pub(crate) fn empty() -> Self {
+        Self::new(vec![0; HEADER_LEN], false)
+    }
+
+    /// Read the location and timestamp tables, validating them against the sectors in `chunk_data`
+    fn parse_header(header_data: &[u8], chunk_data: &[u8], lenient: bool, tolerant: bool) -> Result<ParsedHeader, RegionError> {
+        let sector_count = chunk_data.len() / SECTOR_LEN;
+
+        let mut headers = Box::new([ChunkHeader::EMPTY; 32 * 32]);
+        let mut occupied_sectors = false_bitvec(sector_count);
+        let mut invalidated = vec![];
+        let mut warnings = vec![];
+        let mut report = |x, z, problem| {
+            let warning = LoadWarning { x, z, problem };
+            warn!("{}", warning);
+            warnings.push(warning);
+        };
+
+        // sectors any header points at, which no other chunk's header may be stretched over
+        let mut claimed = false_bitvec(sector_count);
+        if tolerant {
+            for idx in 0..(32*32) {
+                let pos_info = read_big_endian(header_data, 4 * idx);
+                let offset = ((pos_info >> 8) & 0xff_ff_ff) as usize;
+                if offset >= 2 {
+                    let start = (offset - 2).min(sector_count);
+                    let end = (offset - 2 + (pos_info & 0xff) as usize).min(sector_count);
+                    claimed[start..end].fill(true);
+                }
+            }
+        }
+
         for idx in 0..(32*32) {
             let base = 4 * idx;
             let (x, z) = idx_to_coords(idx);
 
             // Read raw metadata
-            let pos_info = read_big_endian(&header_data, base);
+            let pos_info = read_big_endian(header_data, base);
             let offset = (pos_info >> 8) & 0xff_ff_ff;
-            let len = pos_info & 0xff;
-            let mtime = read_big_endian(&header_data, base + 0x1000);
+            let mut len = pos_info & 0xff;
+            let mtime = read_big_endian(header_data, base + 0x1000);
 
             // avoid displaying illegal length warning if this fact is already known
             let known_invalid = offset < 2 || len == 0;
 
-            let header = {
+            if tolerant && offset >= 2 && (offset as usize - 2) < sector_count {
+                let byte_offset = (offset as usize - 2) * SECTOR_LEN;
+                let meta = ChunkInternalMeta::read(&chunk_data[byte_offset..]);
+                let needed = (meta.length + 4).div_ceil(SECTOR_LEN);
+
+                let start = offset as usize - 2;
+                if meta.length > 1 && needed > len as usize && needed <= 0xff && start + needed <= sector_count
+                    && !claimed[start + len as usize..start + needed].any() {
+                    len = needed as u32;
+                    report(x, z, LoadProblem::SectorCount);
+                }
+            }
+
+            let mut header = {
                 let mut header = ChunkHeader::new(offset, len, mtime, sector_count as u32);
 
                 // Extended validation
@@ -102,36 +216,111 @@ impl RegionFile {
                         CompressionType::Unknown(id) if id >= 128 => true,
                         _ => false
                     } {
-                        panic!("Chunk [{x} {z}] is stored externally to the region file. Smithy cannot handle such cases.");
+                        return Err(RegionError::ExternalChunk { x, z });
                     }
 
                     // add 4 bytes for the length field itself
-                    if meta.length <= 1 || meta.length + 4 > chunk_specific_data.len() {
+                    if meta.length <= 1 {
                         header.address = None;
-                        warn!("Chunk [{x} {z}] has an illegal length and will be deleted on write");
+                        report(x, z, LoadProblem::IllegalLength);
+                    } else if meta.length + 4 > chunk_specific_data.len() {
+                        if lenient {
+                            header.truncated = true;
+                            report(x, z, LoadProblem::Truncated);
+                        } else {
+                            header.address = None;
+                            report(x, z, LoadProblem::IllegalLength);
+                        }
                     }
                 } else if !known_invalid {
-                    warn!("Chunk [{x} {z}] has an invalid header and will be deleted on write");
+                    report(x, z, LoadProblem::InvalidHeader);
                 }
 
                 header
             };
 
             if header.valid() {
-                occupied_sectors[(offset as usize - HEADER_SECTORS)..(offset as usize + len as usize - HEADER_SECTORS)].fill(true);
+                let sectors = &mut occupied_sectors[(offset as usize - HEADER_SECTORS)..(offset as usize + len as usize - HEADER_SECTORS)];
+
+                // saving either chunk would overwrite the other, so only the first claim stands
+                if sectors.any() {
+                    header.address = None;
+                    report(x, z, LoadProblem::SharedSectors);
+                } else {
+                    sectors.fill(true);
+                }
+            }
+
+            if !header.valid() && pos_info != 0 {
+                invalidated.push((x, z));
             }
 
-            headers.push(header);
+            headers[idx] = header;
         }
 
-        let headers: Box<[ChunkHeader; 32 * 32]> = headers.try_into().unwrap();
+        Ok(ParsedHeader { headers, occupied_sectors, invalidated, warnings })
+    }
 
-        Self {
-            headers,
-            chunk_data,
-            occupied_sectors,
-            dirty_sectors
+    /// Replace the location and timestamp tables with raw header bytes, validated as on load.
+    /// Chunk data is left untouched; only which sectors belong to which chunk changes.
+    pub(crate) fn replace_header(&mut self, header_data: &[u8]) -> Result<(), RegionError> {
+        if header_data.len() != HEADER_LEN {
+            return Err(RegionError::HeaderLength(header_data.len()));
         }
+
+        let ParsedHeader { headers, mut occupied_sectors, invalidated, warnings } = Self::parse_header(header_data, &self.chunk_data, self.lenient, self.tolerant)?;
+
+        let len = occupied_sectors.len().max(self.occupied_sectors.len());
+        occupied_sectors.resize(len, false);
+        self.released_sectors.resize(len, false);
+
+        // the on-disk header may still reference sectors the new one doesn't, so hold them back
+        for idx in self.occupied_sectors.iter_ones() {
+            if !occupied_sectors[idx] {
+                occupied_sectors.set(idx, true);
+                self.released_sectors.set(idx, true);
+            }
+        }
+
+        self.headers = headers;
+        self.occupied_sectors = occupied_sectors;
+        self.invalidated = invalidated;
+        self.load_warnings = warnings;
+
+        Ok(())
+    }
+
+    /// Coordinates of every chunk with a valid header, in index order
+    pub(crate) fn present_chunks(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.headers.iter()
+            .enumerate()
+            .filter(|(_, header)| header.valid())
+            .map(|(idx, _)| idx_to_coords(idx))
+    }
+
+    /// Present chunks whose timestamp is at or after `since`
+    pub(crate) fn modified_since(&self, since: SystemTime) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.present_chunks().filter(move |&(x, z)| self.lookup_header(x, z).mtime() >= since)
+    }
+
+    /// Present chunks whose timestamp is more than `margin` ahead of the clock, a sign of clock
+    /// skew or corruption. A margin too large to add to the clock leaves none.
+    pub(crate) fn future_chunks(&self, margin: Duration) -> Vec<(u8, u8)> {
+        let Some(limit) = SystemTime::now().checked_add(margin) else {
+            return vec![];
+        };
+        self.present_chunks().filter(|&(x, z)| self.lookup_header(x, z).mtime() > limit).collect()
+    }
+
+    /// Set the timestamps of [`RegionFile::future_chunks`] to now. Since the header is part of
+    /// every write-out, the next one stores them.
+    pub(crate) fn clamp_future_mtimes(&mut self, margin: Duration) -> usize {
+        let future = self.future_chunks(margin);
+        let now = SystemTime::now();
+        for &(x, z) in &future {
+            self.lookup_header_mut(x, z).set_mtime(now);
+        }
+        future.len()
     }
 
     #[inline(always)]
@@ -146,6 +335,88 @@ impl RegionFile {
         &mut self.headers[idx]
     }
 
+    /// Chunks present in the file whose headers were found invalid, and which are therefore
+    /// deleted by the next write-out unless they are written again first
+    pub(crate) fn pending_deletions(&self) -> Vec<(u8, u8)> {
+        self.invalidated.iter()
+            .copied()
+            .filter(|&(x, z)| !self.lookup_header(x, z).valid())
+            .collect()
+    }
+
+    /// Problems found while parsing the header, whether or not the chunks involved have been
+    /// rewritten since. Replacing the header replaces these too.
+    pub(crate) fn load_warnings(&self) -> &[LoadWarning] {
+        &self.load_warnings
+    }
+
+    /// Whether a chunk is one of [`RegionFile::pending_deletions`]
+    pub(crate) fn is_pending_deletion(&self, chunk_x: u8, chunk_z: u8) -> bool {
+        self.invalidated.contains(&(chunk_x & 31, chunk_z & 31)) && !self.lookup_header(chunk_x, chunk_z).valid()
+    }
+
+    pub(crate) fn stats(&self) -> RegionStats {
+        let addresses = self.headers.iter().filter_map(|header| header.address);
+
+        let mut stats = RegionStats { chunks: 0, used_sectors: 0, file_sectors: 0 };
+        for addr in addresses {
+            stats.chunks += 1;
+            stats.used_sectors += addr.len;
+            stats.file_sectors = stats.file_sectors.max(addr.offset + addr.len - HEADER_SECTORS as u32);
+        }
+        stats
+    }
+
+    /// Percentage of the sectors a region can address that are currently occupied
+    pub(crate) fn fullness(&self) -> f64 {
+        self.occupied_sectors.count_ones() as f64 * 100.0 / MAX_SECTORS as f64
+    }
+
+    /// Store every chunk back to back in index order, without free sectors in between. Returns
+    /// how many sectors shorter the region becomes.
+    ///
+    /// Every sector moves, so the result has to be written out in full to a fresh file: writing
+    /// it in place would leave the old header pointing at overwritten sectors if interrupted.
+    pub(crate) fn defrag(&mut self) -> u32 {
+        let before = self.stats().file_sectors;
+        let mut chunk_data = Vec::with_capacity(self.chunk_data.len());
+
+        for addr in self.headers.iter_mut().filter_map(|header| header.address.as_mut()) {
+            let start = (addr.offset as usize - HEADER_SECTORS) * SECTOR_LEN;
+            let len = (addr.len as usize) * SECTOR_LEN;
+
+            addr.offset = (chunk_data.len() / SECTOR_LEN + HEADER_SECTORS) as u32;
+            chunk_data.extend_from_slice(&self.chunk_data[start..start + len]);
+        }
+
+        let sector_count = chunk_data.len() / SECTOR_LEN;
+        self.chunk_data = chunk_data;
+        self.occupied_sectors = bitvec![1; sector_count];
+        self.released_sectors = false_bitvec(sector_count);
+        self.dirty_sectors = bitvec![1; sector_count];
+
+        // loading drops chunks that share sectors, but a region never grows from this either way
+        before.saturating_sub(sector_count as u32)
+    }
+
+    /// The first chunk (in index order) that is missing from, or stored differently in, one of
+    /// the two regions. Compares compressed data, so placement in the file doesn't matter.
+    pub(crate) fn first_difference(&self, other: &RegionFile) -> Option<(u8, u8)> {
+        (0..32 * 32).map(idx_to_coords).find(|&(x, z)| {
+            match (self.lookup_chunk(x, z), other.lookup_chunk(x, z)) {
+                (Some(a), Some(b)) => a.data != b.data || a.compression_type.encode() != b.compression_type.encode(),
+                (None, None) => false,
+                _ => true
+            }
+        })
+    }
+
+    /// Offset (from the start of the file) and length, in sectors, of a chunk's data as of the
+    /// last load or write-out, straight from the header without reading the chunk
+    pub(crate) fn chunk_sectors(&self, chunk_x: u8, chunk_z: u8) -> Option<(u32, u32)> {
+        self.lookup_header(chunk_x, chunk_z).address.map(|addr| (addr.offset, addr.len))
+    }
+
     pub(crate) fn lookup_chunk(&self, chunk_x: u8, chunk_z: u8) -> Option<Chunk<'_>> {
         let header = self.lookup_header(chunk_x, chunk_z);
         let addr = header.address?;
@@ -158,7 +429,8 @@ impl RegionFile {
         let meta = ChunkInternalMeta::read(&chunk_data);
 
         let start = 5;
-        let len = meta.length - 1;
+        // only lenient loading keeps chunks that run past their sectors
+        let len = (meta.length - 1).min(chunk_data.len() - start);
 
         let chunk_data = &chunk_data[start..start + len];
 
@@ -167,7 +439,8 @@ impl RegionFile {
             z: chunk_z & 31,
             mtime: header.mtime(),
             compression_type: meta.compression_type,
-            data: chunk_data
+            data: chunk_data,
+            truncated: header.truncated
         })
     }
 
@@ -180,6 +453,7 @@ impl RegionFile {
 
     pub(crate) fn free_chunk(&mut self, chunk_x: u8, chunk_z: u8) {
         let header = self.lookup_header_mut(chunk_x, chunk_z);
+        header.truncated = false;
         match header.address.take() {
             Some(addr) => {
                 let start = addr.offset as usize - HEADER_SECTORS;
@@ -187,14 +461,36 @@ impl RegionFile {
                 let end = end.min(self.occupied_sectors.len());
 
                 if start < end {
-                    self.occupied_sectors[start..end].fill(false);
+                    if end > self.released_sectors.len() {
+                        self.released_sectors.resize(end, false);
+                    }
+                    self.released_sectors[start..end].fill(true);
                 }
             }
             None => {}
         };
     }
 
+    /// Log every sector allocation at debug level, for tuning the allocator
+    pub(crate) fn set_profile_alloc(&mut self, profile_alloc: bool) {
+        self.profile_alloc = profile_alloc;
+    }
+
+    /// Free sectors between chunks, and how many separate holes they form
+    fn free_space(&self) -> (usize, usize) {
+        let holes = self.occupied_sectors.iter_zeros()
+            .filter(|&idx| idx == 0 || self.occupied_sectors[idx - 1])
+            .count();
+
+        (self.occupied_sectors.count_zeros(), holes)
+    }
+
     fn allocate_run(&mut self, len: usize) -> Option<ChunkAddress> {
+        // the location table has a single byte for the length, anything longer would be truncated
+        if len > u8::MAX as usize {
+            return None;
+        }
+
         // first, try to find a sufficient-length run
         let mut start = 0;
 
@@ -255,6 +551,7 @@ impl RegionFile {
         let container_len = data.len() + meta_len;
 
         // allocate sectors
+        let file_sectors = self.occupied_sectors.len();
         let addr = match self.allocate_run(container_len.div_ceil(SECTOR_LEN)) {
             Some(addr) => addr,
             None => {
@@ -263,6 +560,23 @@ impl RegionFile {
             }
         };
 
+        if self.profile_alloc {
+            let start = addr.offset as usize - HEADER_SECTORS;
+            let placement = if start + addr.len as usize <= file_sectors {
+                "reusing a hole"
+            } else if start < file_sectors {
+                "extending the last hole"
+            } else {
+                "appended"
+            };
+            let (free_sectors, holes) = self.free_space();
+
+            debug!(
+                "Allocated {} sectors for chunk [{} {}] at sector {} ({}), leaving {} free sectors in {} holes",
+                addr.len, chunk_x, chunk_z, addr.offset, placement, free_sectors, holes
+            );
+        }
+
         // write data
         {
             let start = (addr.offset as usize - HEADER_SECTORS) * SECTOR_LEN;
@@ -301,56 +615,74 @@ impl RegionFile {
         let header = self.lookup_header_mut(chunk_x, chunk_z);
         header.set_mtime(mtime);
         header.address = Some(addr);
+        header.truncated = false;
     }
 
-    pub(crate) fn write_out(&mut self, full_write: bool, file: &mut File) -> std::io::Result<()> {
-        // start by truncating/allocating
-        let sector_count = self.headers.iter()
-            .map(|h| h.address)
-            .filter_map(|a| a)
-            .map(|a| (a.offset as usize) + (a.len as usize) - HEADER_SECTORS)
-            .max()
-            .unwrap_or(0);
-        file.set_len((HEADER_LEN + sector_count * SECTOR_LEN) as u64)?;
-
-        // always write header
-        file.seek(SeekFrom::Start(0))?;
-
-        // write first part of header (locations)
-        for idx in 0..(32*32) {
-            let header = self.headers[idx];
+    /// Serialize the location and timestamp tables
+    pub(crate) fn encode_header(&self) -> Vec<u8> {
+        let mut data = vec![0; HEADER_LEN];
+        let (locations, timestamps) = data.split_at_mut(HEADER_LEN / 2);
 
+        for (idx, header) in self.headers.iter().enumerate() {
             let (start, len) = match header.address {
                 Some(addr) => (addr.offset, addr.len),
                 None => (0, 0),
             };
 
-            let data = [
+            locations[4 * idx..4 * idx + 4].copy_from_slice(&[
                 ((start >> 16) & 0xff) as u8,
                 ((start >>  8) & 0xff) as u8,
                 ((start >>  0) & 0xff) as u8,
-                len as u8
-            ];
+                u8::try_from(len).expect("chunk lengths are limited to one byte when allocated")
+            ]);
 
-            file.write_all(&data)?
-        }
-
-        // write second part of header (timestamps)
-        for idx in 0..(32*32) {
-            let header = self.headers[idx];
             let mtime = header.mtime;
-
-            let data = [
+            timestamps[4 * idx..4 * idx + 4].copy_from_slice(&[
                 ((mtime >> 24) & 0xff) as u8,
                 ((mtime >> 16) & 0xff) as u8,
                 ((mtime >>  8) & 0xff) as u8,
                 ((mtime >>  0) & 0xff) as u8,
-            ];
+            ]);
+        }
+
+        data
+    }
+
+    /// Writing the header is the commit point: sectors are written (and synced) first, so a crash
+    /// at any point leaves the on-disk header pointing only at fully-written data.
+    pub(crate) fn write_out(&mut self, full_write: bool, file: &mut impl RegionWriter) -> std::io::Result<()> {
+        self.write_plan(full_write).apply(file)?;
+        self.mark_written();
+        Ok(())
+    }
 
-            file.write_all(&data)?;
+    /// Sectors after the header up to the end of the last chunk, which a full write-out writes
+    fn used_sectors(&self) -> usize {
+        self.headers.iter()
+            .map(|h| h.address)
+            .filter_map(|a| a)
+            .map(|a| (a.offset as usize) + (a.len as usize) - HEADER_SECTORS)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Percentage of the sectors a full write-out writes that a partial one would write too
+    pub(crate) fn dirty_percent(&self) -> f64 {
+        let used = self.used_sectors();
+        if used == 0 {
+            return 0.0;
         }
 
-        // write (changed) sectors
+        let dirty = self.dirty_sectors.iter_ones().take_while(|idx| *idx < used).count();
+        dirty as f64 * 100.0 / used as f64
+    }
+
+    /// Copy out what [`RegionFile::write_out`] would write, for writing it elsewhere (such as on
+    /// another thread). Call [`RegionFile::mark_written`] once it has been applied.
+    pub(crate) fn write_plan(&self, full_write: bool) -> WritePlan {
+        let sector_count = self.used_sectors();
+
+        // (changed) sectors, merged into runs
 
         let sector_idx_iter: Box<dyn Iterator<Item=usize>> = if full_write {
             Box::new((0..sector_count).into_iter())
@@ -358,26 +690,321 @@ impl RegionFile {
             Box::new(self.dirty_sectors.iter_ones().take_while(|idx| *idx < sector_count))
         };
 
+        let mut runs: Vec<(usize, usize)> = vec![];
         for sector_idx in sector_idx_iter {
             if !full_write {
                 info!("> Writing sector {:#06x}", sector_idx);
             }
 
-            let start = sector_idx * SECTOR_LEN;
-            let end = start + SECTOR_LEN;
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == sector_idx => *len += 1,
+                _ => runs.push((sector_idx, 1)),
+            }
+        }
+
+        let runs = runs.into_iter()
+            .map(|(start, len)| {
+                let start = start * SECTOR_LEN;
+                ((HEADER_LEN + start) as u64, self.chunk_data[start..start + len * SECTOR_LEN].to_vec())
+            })
+            .collect();
+
+        WritePlan {
+            runs,
+            header: self.encode_header(),
+            len: (HEADER_LEN + sector_count * SECTOR_LEN) as u64,
+        }
+    }
+
+    /// Record that a [`RegionFile::write_plan`] has been written to disk
+    pub(crate) fn mark_written(&mut self) {
+        self.dirty_sectors.fill(false);
+        // the header on disk no longer references them
+        self.invalidated.clear();
+
+        // sectors freed before this write are no longer referenced on disk
+        for idx in self.released_sectors.iter_ones() {
+            self.occupied_sectors.set(idx, false);
+        }
+        self.released_sectors.fill(false);
+    }
+}
+
+/// Sectors and header of a region to be written out, see [`RegionFile::write_plan`]
+pub(crate) struct WritePlan {
+    /// Byte offsets in the file, with the sectors to write there
+    runs: Vec<(u64, Vec<u8>)>,
+    header: Vec<u8>,
+    /// Length of the file once written
+    len: u64,
+}
 
-            file.seek(SeekFrom::Start((HEADER_LEN + start) as u64))?;
-            file.write_all(&self.chunk_data[start..end])?;
+impl WritePlan {
+    pub(crate) fn apply(&self, file: &mut impl RegionWriter) -> io::Result<()> {
+        for (offset, data) in &self.runs {
+            file.seek(SeekFrom::Start(*offset))?;
+            file.write_all(data)?;
         }
 
-        file.set_modified(SystemTime::now())?;
         file.flush()?;
-        file.sync_all()?;
+        file.sync_data()?;
 
-        self.dirty_sectors.fill(false);
+        // commit by writing the whole header at once
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.header)?;
+        file.flush()?;
+        file.sync_data()?;
 
-        Ok(())
+        // only now is it safe to drop trailing sectors that the old header may have referenced
+        file.set_len(self.len)?;
+        file.finish()
+    }
+}
+
+/// Somewhere a region can be written out to: its file, or anything standing in for one
+pub(crate) trait RegionWriter: Write + Seek {
+    /// Make the data written so far durable
+    fn sync_data(&mut self) -> io::Result<()>;
+
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Mark the write-out as done, making it durable along with the metadata
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl RegionWriter for File {
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
     }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.set_modified(SystemTime::now())?;
+        self.sync_all()
+    }
+}
+
+/// A chunk read on its own by [`read_chunk`]
+pub(crate) struct OwnedChunk {
+    pub(crate) mtime: SystemTime,
+    pub(crate) compression_type: CompressionType,
+    pub(crate) data: Vec<u8>
+}
+
+/// Read a single chunk straight from a region file, touching only the header and the chunk's own
+/// sectors. Chunks that [`RegionFile::new_checked`] would drop (without `lenient`) are `None`.
+pub(crate) fn read_chunk(file: &File, chunk_x: u8, chunk_z: u8) -> io::Result<Option<OwnedChunk>> {
+    let file_len = file.metadata()?.len();
+    if file_len < HEADER_LEN as u64 {
+        return Err(io::Error::new(ErrorKind::InvalidData, RegionError::Truncated(file_len as usize)));
+    }
+
+    let mut header_data = vec![0; HEADER_LEN];
+    file.read_exact_at(&mut header_data, 0)?;
+
+    let idx = coords_to_idx(chunk_x, chunk_z);
+    let pos_info = read_big_endian(&header_data, 4 * idx);
+    let mtime = read_big_endian(&header_data, 4 * idx + HEADER_LEN / 2);
+
+    let sector_count = (file_len as usize - HEADER_LEN).div_ceil(SECTOR_LEN);
+    let header = ChunkHeader::new((pos_info >> 8) & 0xff_ff_ff, pos_info & 0xff, mtime, sector_count as u32);
+    let Some(addr) = header.address else {
+        return Ok(None);
+    };
+
+    // the last sector may be cut short by the end of the file, load() pads it with zeros too
+    let mut sectors = vec![0; addr.len as usize * SECTOR_LEN];
+    let start = addr.offset as u64 * SECTOR_LEN as u64;
+    let available = (file_len - start).min(sectors.len() as u64) as usize;
+    file.read_exact_at(&mut sectors[..available], start)?;
+
+    let meta = ChunkInternalMeta::read(&sectors);
+    if let CompressionType::Unknown(id) = meta.compression_type && id >= 128 {
+        return Err(io::Error::new(ErrorKind::InvalidData, RegionError::ExternalChunk { x: chunk_x & 31, z: chunk_z & 31 }));
+    }
+    if meta.length <= 1 || meta.length + 4 > sectors.len() {
+        return Ok(None);
+    }
+
+    sectors.truncate(ChunkInternalMeta::LEN + meta.length - 1);
+    sectors.drain(..ChunkInternalMeta::LEN);
+
+    Ok(Some(OwnedChunk { mtime: header.mtime(), compression_type: meta.compression_type, data: sectors }))
+}
+
+/// A location and timestamp table entry as stored, before any validation
+pub(crate) struct RawHeaderEntry {
+    pub(crate) x: u8,
+    pub(crate) z: u8,
+    /// In sectors from the start of the file
+    pub(crate) offset: u32,
+    pub(crate) sectors: u32,
+    /// In epoch seconds
+    pub(crate) mtime: u32,
+}
+impl RawHeaderEntry {
+    /// `empty`, `ok`, or what makes the entry invalid, for a file `file_len` bytes long
+    pub(crate) fn status(&self, file_len: u64) -> &'static str {
+        let end = (self.offset as u64 + self.sectors as u64) * SECTOR_LEN as u64;
+
+        if self.offset == 0 && self.sectors == 0 {
+            "empty"
+        } else if (self.offset as usize) < HEADER_SECTORS {
+            "in-header"
+        } else if self.sectors == 0 {
+            "no-sectors"
+        } else if end > file_len.next_multiple_of(SECTOR_LEN as u64) {
+            "past-end"
+        } else {
+            "ok"
+        }
+    }
+}
+
+/// Every entry of a raw [`HEADER_LEN`] byte header, in index order
+pub(crate) fn raw_header(header: &[u8]) -> impl Iterator<Item = RawHeaderEntry> + '_ {
+    (0..32 * 32).map(|idx| {
+        let (x, z) = idx_to_coords(idx);
+        let pos_info = read_big_endian(header, 4 * idx);
+
+        RawHeaderEntry {
+            x,
+            z,
+            offset: (pos_info >> 8) & 0xff_ff_ff,
+            sectors: pos_info & 0xff,
+            mtime: read_big_endian(header, 4 * idx + HEADER_LEN / 2),
+        }
+    })
+}
+
+/// How a region's sectors are used
+pub(crate) struct RegionStats {
+    /// Chunks with a valid header
+    pub(crate) chunks: u32,
+    /// Sectors allocated to chunks
+    pub(crate) used_sectors: u32,
+    /// Sectors from the end of the header to the end of the last chunk
+    pub(crate) file_sectors: u32,
+}
+
+#[derive(Debug)]
+pub(crate) enum RegionError {
+    /// A chunk is stored in an external `.mcc` file
+    ExternalChunk { x: u8, z: u8 },
+    /// Raw header data is not exactly [`HEADER_LEN`] bytes long
+    HeaderLength(usize),
+    /// A whole region is too short to contain a header
+    Truncated(usize),
+}
+
+impl Display for RegionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ExternalChunk { x, z } => write!(f, "Chunk [{x} {z}] is stored externally to the region file. Smithy cannot handle such cases."),
+            Self::HeaderLength(len) => write!(f, "Region header must be exactly {} bytes, got {}", HEADER_LEN, len),
+            Self::Truncated(len) => write!(f, "Region is only {} bytes long, too short to contain a {} byte header", len, HEADER_LEN),
+        }
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+/// Why a chunk's header was distrusted while parsing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LoadProblem {
+    /// The location entry points into the header or past the end of the file
+    InvalidHeader,
+    /// The chunk's length field doesn't fit its sectors
+    IllegalLength,
+    /// The chunk's length runs past its sectors, but it was kept (lenient mode)
+    Truncated,
+    /// The chunk claims sectors already used by an earlier chunk
+    SharedSectors,
+    /// The header gives too few sectors for the chunk's length, so the length was trusted
+    /// instead (tolerant mode)
+    SectorCount,
+}
+
+impl LoadProblem {
+    /// Short name, as listed in `.smithy-status`
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::InvalidHeader => "invalid_header",
+            Self::IllegalLength => "illegal_length",
+            Self::Truncated => "truncated",
+            Self::SharedSectors => "shared_sectors",
+            Self::SectorCount => "sector_count",
+        }
+    }
+}
+
+/// A problem with one chunk, found while parsing a region's header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LoadWarning {
+    pub(crate) x: u8,
+    pub(crate) z: u8,
+    pub(crate) problem: LoadProblem,
+}
+
+impl Display for LoadWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { x, z, problem } = self;
+        match problem {
+            LoadProblem::InvalidHeader => write!(f, "Chunk [{x} {z}] has an invalid header and will be deleted on write"),
+            LoadProblem::IllegalLength => write!(f, "Chunk [{x} {z}] has an illegal length and will be deleted on write"),
+            LoadProblem::Truncated => write!(f, "Chunk [{x} {z}] is longer than its sectors, keeping what's left read-only"),
+            LoadProblem::SharedSectors => write!(f, "Chunk [{x} {z}] shares sectors with an earlier chunk and will be deleted on write"),
+            LoadProblem::SectorCount => write!(f, "Chunk [{x} {z}] is longer than its header's sector count, using the sectors its length needs (the header is fixed on write)"),
+        }
+    }
+}
+
+/// A chunk found by scanning sectors rather than through the header
+pub(crate) struct ScannedChunk<'a> {
+    /// Index of the sector the chunk starts at, counting from the start of the file
+    pub(crate) sector: usize,
+    /// Number of sectors the chunk spans
+    pub(crate) sectors: usize,
+    pub(crate) compression_type: CompressionType,
+    pub(crate) data: &'a [u8]
+}
+
+/// Find every sector of a whole region file that starts with a plausible chunk framing: a length
+/// that fits in the file and a known compression type. The header is ignored entirely, so this
+/// can turn up false positives, which callers need to weed out by decoding the data.
+pub(crate) fn scan_chunks(data: &[u8]) -> Vec<ScannedChunk<'_>> {
+    let mut found = vec![];
+
+    for sector in HEADER_SECTORS..data.len().div_ceil(SECTOR_LEN) {
+        let raw = &data[sector * SECTOR_LEN..];
+        if raw.len() < ChunkInternalMeta::LEN {
+            break;
+        }
+
+        let meta = ChunkInternalMeta::read(raw);
+
+        // add 4 bytes for the length field itself
+        let total_len = meta.length + 4;
+        if meta.length <= 1 || total_len > raw.len() || total_len > MAX_CHUNK_LEN {
+            continue;
+        }
+
+        if let CompressionType::Unknown(_) = meta.compression_type {
+            continue;
+        }
+
+        found.push(ScannedChunk {
+            sector,
+            sectors: total_len.div_ceil(SECTOR_LEN),
+            compression_type: meta.compression_type,
+            data: &raw[ChunkInternalMeta::LEN..total_len]
+        });
+    }
+
+    found
 }
 
 struct ChunkInternalMeta {
@@ -420,9 +1047,13 @@ pub(crate) struct ChunkHeader {
     address: Option<ChunkAddress>,
     /// Modification time, in epoch seconds
     mtime: u32,
+    /// The chunk's length runs past its sectors, so only part of it is available
+    truncated: bool
 }
 
 impl ChunkHeader {
+    const EMPTY: Self = Self { address: None, mtime: 0, truncated: false };
+
     fn new(offset: u32, len: u32, mtime: u32, sector_count: u32) -> Self {
         let address = if offset >= 2 && len > 0 && (offset + len - 2) <= sector_count {
             Some(ChunkAddress { offset, len })
@@ -430,7 +1061,7 @@ impl ChunkHeader {
             None
         };
 
-        Self { address, mtime }
+        Self { address, mtime, truncated: false }
     }
 
     #[inline(always)]
@@ -443,14 +1074,11 @@ impl ChunkHeader {
     }
 
     fn set_mtime(&mut self, time: SystemTime) {
-        self.mtime = match time.duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(dur) => dur.as_secs() as u32,
-            Err(_) => 0
-        };
+        self.mtime = to_timestamp(time);
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum CompressionType {
     GZip,
     Zlib,
@@ -461,7 +1089,7 @@ pub(crate) enum CompressionType {
 }
 #[allow(unused)]
 impl CompressionType {
-    fn decode(id: u8) -> Self {
+    pub(crate) fn decode(id: u8) -> Self {
         match id {
              1 => Self::GZip,
              2 => Self::Zlib,
@@ -473,7 +1101,7 @@ impl CompressionType {
         }
     }
 
-    fn encode(&self) -> u8 {
+    pub(crate) fn encode(&self) -> u8 {
         match self {
             &Self::GZip => 1,
             &Self::Zlib => 2,
@@ -485,6 +1113,18 @@ impl CompressionType {
         }
     }
 
+    pub(crate) fn name(&self) -> String {
+        match *self {
+            Self::GZip => "gzip".to_owned(),
+            Self::Zlib => "zlib".to_owned(),
+            Self::None => "none".to_owned(),
+            Self::LZ4 => "lz4".to_owned(),
+            Self::Zstd => "zstd".to_owned(),
+            Self::Unknown(id) => format!("unknown({})", id)
+        }
+    }
+
+    /// Canonical contents of a `.cmp` file: every codec name, with the selected one in brackets
     pub(crate) fn make_selector_string(&self) -> String {
         let out = match self {
             &Self::GZip => "[gzip] zlib none lz4 zstd unknown(#)".to_owned(),
@@ -497,6 +1137,12 @@ impl CompressionType {
         out + "\n"
     }
 
+    /// Parse what was written to a `.cmp` file, ignoring case and surrounding whitespace. Accepts
+    /// - a codec name: `zlib`
+    /// - a numeric id, as stored in the region: `2`, or `unknown(2)`
+    /// - any text with one of the above in brackets, such as an edited selector string: `[zlib]`
+    ///
+    /// Known ids always come back as their codec, so `53` reads back as `[zstd]`.
     pub(crate) fn parse_selector_string(selector: &str) -> Option<Self> {
         let selector = selector.to_ascii_lowercase();
         let selector: &str = selector.trim();
@@ -542,5 +1188,411 @@ pub(crate) struct Chunk<'a> {
     pub(crate) z: u8,
     pub(crate) mtime: SystemTime,
     pub(crate) compression_type: CompressionType,
-    pub(crate) data: &'a [u8]
+    pub(crate) data: &'a [u8],
+    /// Only part of the chunk's data survived, see `lenient` in [`RegionFile::new`]
+    pub(crate) truncated: bool
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs::{self, OpenOptions}, io::Cursor};
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::util::TempPath;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Write { x: u8, z: u8, len: usize, fill: u8 },
+        Delete { x: u8, z: u8 },
+        WriteOut { full_write: bool },
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // a handful of coordinates, so that chunks get rewritten and deleted often
+        let coord = 0..4u8;
+        prop_oneof![
+            // an empty payload is no chunk at all, and rejected when loaded
+            4 => (coord.clone(), coord.clone(), 1..5 * SECTOR_LEN, any::<u8>())
+                .prop_map(|(x, z, len, fill)| Op::Write { x, z, len, fill }),
+            2 => (coord.clone(), coord).prop_map(|(x, z)| Op::Delete { x, z }),
+            1 => any::<bool>().prop_map(|full_write| Op::WriteOut { full_write }),
+        ]
+    }
+
+    /// A region of `sectors` sectors after the header, all zero
+    fn raw_region(sectors: usize) -> Vec<u8> {
+        vec![0; HEADER_LEN + sectors * SECTOR_LEN]
+    }
+
+    fn set_location(data: &mut [u8], x: u8, z: u8, offset: u32, len: u8) {
+        let idx = 4 * coords_to_idx(x, z);
+        data[idx..idx + 4].copy_from_slice(&(offset << 8 | len as u32).to_be_bytes());
+    }
+
+    /// Put a chunk of `len` zlib bytes (all 1) at `sector`, counted from the start of the file
+    fn put_chunk(data: &mut Vec<u8>, sector: usize, len: usize) {
+        let start = sector * SECTOR_LEN;
+        let end = start + ChunkInternalMeta::LEN + len;
+        if end > data.len() {
+            data.resize(end.next_multiple_of(SECTOR_LEN), 0);
+        }
+        ChunkInternalMeta { length: len + 1, compression_type: CompressionType::Zlib }.write(&mut data[start..]);
+        data[start + ChunkInternalMeta::LEN..end].fill(1);
+    }
+
+    fn problems(region: &RegionFile) -> Vec<(u8, u8, LoadProblem)> {
+        region.load_warnings().iter().map(|w| (w.x, w.z, w.problem)).collect()
+    }
+
+    #[test]
+    fn tolerant_extends_short_headers() {
+        let mut data = raw_region(3);
+        put_chunk(&mut data, 2, SECTOR_LEN + 100);
+        set_location(&mut data, 0, 0, 2, 1);
+
+        let region = RegionFile::new_checked(data, false, true).unwrap();
+        assert_eq!(region.chunk_sectors(0, 0), Some((2, 2)));
+        assert_eq!(problems(&region), [(0, 0, LoadProblem::SectorCount)]);
+    }
+
+    #[test]
+    fn tolerant_leaves_claimed_sectors_alone() {
+        let mut data = raw_region(3);
+        put_chunk(&mut data, 2, SECTOR_LEN + 100);
+        put_chunk(&mut data, 3, 100);
+        set_location(&mut data, 0, 0, 2, 1);
+        // a later header, so that an earlier-claim-wins check alone wouldn't see it yet
+        set_location(&mut data, 1, 0, 3, 1);
+
+        let region = RegionFile::new_checked(data, false, true).unwrap();
+        assert_eq!(region.chunk_sectors(0, 0), None);
+        assert_eq!(region.chunk_sectors(1, 0), Some((3, 1)));
+        assert_eq!(problems(&region), [(0, 0, LoadProblem::IllegalLength)]);
+    }
+
+    #[test]
+    fn short_regions_are_rejected() {
+        for len in [0, 1, SECTOR_LEN, HEADER_LEN - 1] {
+            match RegionFile::new_checked(vec![0; len], false, false) {
+                Err(RegionError::Truncated(got)) => assert_eq!(got, len),
+                other => panic!("{} byte region: {:?}", len, other.map(|_| ())),
+            }
+        }
+        let region = RegionFile::new_checked(raw_region(0), false, false).unwrap();
+        assert_eq!(region.present_chunks().count(), 0);
+    }
+
+    #[test]
+    fn bad_headers_are_dropped() {
+        // (what, region, lenient, whether [0 0] survives, warnings)
+        let cases = vec![
+            ("valid", {
+                let mut data = raw_region(1);
+                put_chunk(&mut data, 2, 100);
+                set_location(&mut data, 0, 0, 2, 1);
+                data
+            }, false, true, vec![]),
+            ("into the header", {
+                let mut data = raw_region(1);
+                set_location(&mut data, 0, 0, 1, 1);
+                data
+            }, false, false, vec![]),
+            ("past the end", {
+                let mut data = raw_region(1);
+                set_location(&mut data, 0, 0, 3, 1);
+                data
+            }, false, false, vec![LoadProblem::InvalidHeader]),
+            ("partly past the end", {
+                let mut data = raw_region(1);
+                put_chunk(&mut data, 2, 100);
+                set_location(&mut data, 0, 0, 2, 2);
+                data
+            }, false, false, vec![LoadProblem::InvalidHeader]),
+            ("far past the end", {
+                let mut data = raw_region(1);
+                set_location(&mut data, 0, 0, 0xff_ff_ff, 0xff);
+                data
+            }, false, false, vec![LoadProblem::InvalidHeader]),
+            ("zero sectors", {
+                let mut data = raw_region(1);
+                put_chunk(&mut data, 2, 100);
+                set_location(&mut data, 0, 0, 2, 0);
+                data
+            }, false, false, vec![]),
+            ("zero-length payload", {
+                let mut data = raw_region(1);
+                put_chunk(&mut data, 2, 0);
+                set_location(&mut data, 0, 0, 2, 1);
+                data
+            }, false, false, vec![LoadProblem::IllegalLength]),
+            ("payload past its sectors", {
+                let mut data = raw_region(2);
+                put_chunk(&mut data, 2, SECTOR_LEN + 100);
+                set_location(&mut data, 0, 0, 2, 1);
+                data
+            }, false, false, vec![LoadProblem::IllegalLength]),
+            ("payload past its sectors, lenient", {
+                let mut data = raw_region(2);
+                put_chunk(&mut data, 2, SECTOR_LEN + 100);
+                set_location(&mut data, 0, 0, 2, 1);
+                data
+            }, true, true, vec![LoadProblem::Truncated]),
+        ];
+
+        for (what, data, lenient, present, warnings) in cases {
+            let region = RegionFile::new_checked(data, lenient, false).unwrap();
+            assert_eq!(region.lookup_chunk(0, 0).is_some(), present, "{}", what);
+            let expected: Vec<_> = warnings.into_iter().map(|problem| (0, 0, problem)).collect();
+            assert_eq!(problems(&region), expected, "{}", what);
+        }
+    }
+
+    #[test]
+    fn shared_sectors_go_to_the_first_header() {
+        let mut data = raw_region(2);
+        put_chunk(&mut data, 2, 100);
+        set_location(&mut data, 0, 0, 2, 1);
+        // runs on into a free sector
+        set_location(&mut data, 1, 0, 2, 2);
+        // exactly the same sector
+        set_location(&mut data, 2, 0, 2, 1);
+
+        let region = RegionFile::new_checked(data, false, false).unwrap();
+        assert_eq!(region.chunk_sectors(0, 0), Some((2, 1)));
+        assert_eq!(region.chunk_sectors(1, 0), None);
+        assert_eq!(region.chunk_sectors(2, 0), None);
+        assert_eq!(problems(&region), [(1, 0, LoadProblem::SharedSectors), (2, 0, LoadProblem::SharedSectors)]);
+        assert_eq!(region.pending_deletions(), [(1, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn oversized_chunks_are_refused() {
+        let mut region = RegionFile::empty();
+        assert!(region.allocate_run(300).is_none());
+        assert!(region.allocate_run(256).is_none());
+        assert_eq!(region.allocate_run(255).map(|addr| addr.len), Some(255));
+
+        let mut region = RegionFile::empty();
+        region.write_chunk(0, 0, &vec![1; 300 * SECTOR_LEN], CompressionType::Zlib, SystemTime::UNIX_EPOCH);
+        region.write_chunk(1, 0, &[1; 100], CompressionType::Zlib, SystemTime::UNIX_EPOCH);
+        assert_eq!(region.present_chunks().collect::<Vec<_>>(), [(1, 0)]);
+
+        // and the header, with its one-byte lengths, can still be written
+        let mut file = Cursor::new(vec![]);
+        region.write_out(true, &mut file).unwrap();
+        let reloaded = RegionFile::new_checked(file.into_inner(), false, false).unwrap();
+        assert_eq!(reloaded.present_chunks().collect::<Vec<_>>(), [(1, 0)]);
+    }
+
+    #[test]
+    fn parse_selector_strings() {
+        use CompressionType::*;
+        let cases = [
+            ("gzip", Some(GZip)),
+            ("  ZLIB\n", Some(Zlib)),
+            ("none", Some(None)),
+            ("lz4", Some(LZ4)),
+            ("zstd", Some(Zstd)),
+            ("2", Some(Zlib)),
+            ("53", Some(Zstd)),
+            ("99", Some(Unknown(99))),
+            ("unknown(99)", Some(Unknown(99))),
+            ("unknown(1)", Some(GZip)),
+            ("[zlib]", Some(Zlib)),
+            ("gzip zlib none lz4 [zstd] unknown(#)", Some(Zstd)),
+            ("gzip zlib none lz4 zstd [unknown(7)]", Some(Unknown(7))),
+            ("", Option::None),
+            ("256", Option::None),
+            ("unknown(#)", Option::None),
+            ("gzip zlib", Option::None),
+            ("[]", Option::None),
+            ("[brotli]", Option::None),
+        ];
+        for (selector, expected) in cases {
+            assert_eq!(CompressionType::parse_selector_string(selector), expected, "parsing {:?}", selector);
+        }
+
+        // the canonical form always reads back as itself
+        for compression_type in [GZip, Zlib, None, LZ4, Zstd, Unknown(0), Unknown(99)] {
+            let selector = compression_type.make_selector_string();
+            assert_eq!(CompressionType::parse_selector_string(&selector), Some(compression_type), "parsing {:?}", selector);
+        }
+    }
+
+    impl RegionWriter for Cursor<Vec<u8>> {
+        fn sync_data(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.get_mut().resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An in-memory region file that fails every write once `budget` bytes have been written
+    struct FailingWriter {
+        inner: Cursor<Vec<u8>>,
+        budget: usize,
+    }
+    impl FailingWriter {
+        fn check(&self) -> io::Result<()> {
+            match self.budget {
+                0 => Err(io::Error::other("out of budget")),
+                _ => Ok(()),
+            }
+        }
+    }
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.check()?;
+            let len = buf.len().min(self.budget);
+            self.budget -= len;
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Seek for FailingWriter {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+    impl RegionWriter for FailingWriter {
+        fn sync_data(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.check()?;
+            self.inner.set_len(len)
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            self.check()
+        }
+    }
+
+    #[test]
+    fn interrupted_write_out_leaves_a_parseable_region() {
+        let mut original = RegionFile::empty();
+        for (x, len) in [(0, 100), (1, 2 * SECTOR_LEN), (2, 3 * SECTOR_LEN), (3, 50)] {
+            original.write_chunk(x, 0, &vec![x; len], CompressionType::Zlib, SystemTime::UNIX_EPOCH);
+        }
+        let mut file = Cursor::new(vec![]);
+        original.write_out(true, &mut file).unwrap();
+        let original_data = file.into_inner();
+
+        let mut region = RegionFile::new(original_data.clone(), false);
+        region.write_chunk(0, 0, &vec![10; 3 * SECTOR_LEN], CompressionType::Zlib, SystemTime::UNIX_EPOCH);
+        region.delete_chunk(1, 0);
+        region.write_chunk(4, 0, &vec![14; SECTOR_LEN], CompressionType::Zlib, SystemTime::UNIX_EPOCH);
+        let updated: Vec<_> = (0..5).map(|x| region.lookup_chunk(x, 0).map(|chunk| chunk.data.to_vec())).collect();
+
+        for full_write in [false, true] {
+            let plan = region.write_plan(full_write);
+            let mut budget = 0;
+            loop {
+                let mut file = FailingWriter { inner: Cursor::new(original_data.clone()), budget };
+                let result = plan.apply(&mut file);
+
+                let written = RegionFile::new_checked(file.inner.into_inner(), false, false)
+                    .unwrap_or_else(|e| panic!("failing after {} bytes left an unreadable region: {}", budget, e));
+                assert!(written.load_warnings().is_empty(), "failing after {} bytes: {:?}", budget, problems(&written));
+                for x in 0..5 {
+                    let chunk = written.lookup_chunk(x, 0).map(|chunk| chunk.data.to_vec());
+                    let before = original.lookup_chunk(x, 0).map(|chunk| chunk.data.to_vec());
+                    assert!(chunk == before || chunk == updated[x as usize], "chunk [{} 0] is neither old nor new after {} bytes", x, budget);
+                }
+
+                if result.is_ok() {
+                    break;
+                }
+                budget += 997;
+            }
+        }
+    }
+
+    fn live_ranges(region: &RegionFile) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<_> = region.headers.iter()
+            .filter_map(|h| h.address)
+            .map(|a| (a.offset as usize - HEADER_SECTORS, (a.offset + a.len) as usize - HEADER_SECTORS))
+            .collect();
+        ranges.sort_unstable();
+        ranges
+    }
+
+    fn check_allocation(region: &RegionFile, written: bool) {
+        let ranges = live_ranges(region);
+        for pair in ranges.windows(2) {
+            assert!(pair[0].1 <= pair[1].0, "chunks at sectors {:?} and {:?} overlap", pair[0], pair[1]);
+        }
+
+        let mut live = false_bitvec(region.occupied_sectors.len());
+        for &(start, end) in &ranges {
+            assert!(end <= region.occupied_sectors.len(), "sectors {:?} are past the allocation map", (start, end));
+            assert!(region.occupied_sectors[start..end].all(), "sectors {:?} aren't marked occupied", (start, end));
+            live[start..end].fill(true);
+        }
+
+        // freed sectors stay reserved until the header that referenced them is overwritten
+        for idx in region.occupied_sectors.iter_ones().filter(|&idx| !live[idx]) {
+            assert!(!written && region.released_sectors.get(idx).is_some_and(|b| *b), "sector {} is occupied without a chunk", idx);
+        }
+    }
+
+    fn check_chunks(region: &RegionFile, model: &HashMap<(u8, u8), Vec<u8>>) {
+        for x in 0..32 {
+            for z in 0..32 {
+                let chunk = region.lookup_chunk(x, z).map(|chunk| chunk.data);
+                assert_eq!(chunk, model.get(&(x, z)).map(Vec::as_slice), "chunk [{} {}]", x, z);
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn allocation_invariants_hold(ops in prop::collection::vec(op(), 1..40)) {
+            let path = TempPath::new("r.0.0.mca");
+            let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path.0).unwrap();
+
+            let mut region = RegionFile::empty();
+            region.write_out(true, &mut file).unwrap();
+            let mut model = HashMap::new();
+
+            for op in ops {
+                let mut written = false;
+                match op {
+                    Op::Write { x, z, len, fill } => {
+                        let data = vec![fill; len];
+                        region.write_chunk(x, z, &data, CompressionType::Zlib, SystemTime::UNIX_EPOCH);
+                        model.insert((x, z), data);
+                    }
+                    Op::Delete { x, z } => {
+                        region.delete_chunk(x, z);
+                        model.remove(&(x, z));
+                    }
+                    Op::WriteOut { full_write } => {
+                        region.write_out(full_write, &mut file).unwrap();
+                        written = true;
+
+                        let reloaded = RegionFile::new_checked(fs::read(&path.0).unwrap(), false, false).unwrap();
+                        prop_assert!(reloaded.load_warnings().is_empty());
+                        check_chunks(&reloaded, &model);
+                    }
+                }
+
+                check_allocation(&region, written);
+                check_chunks(&region, &model);
+            }
+        }
+    }
 }
@@ -0,0 +1,153 @@
+/*
+* Smithy
+* Copyright (C) 2025  Sam Wagenaar
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Workloads for `smithy bench`, run directly against [`RegionFile`] so that FUSE overhead
+//! doesn't drown out the cost of the region code itself.
+
+use std::{io::Write, time::{Duration, Instant}};
+
+use crate::{anvil::RegionFile, cli::Workload, codec};
+
+/// xorshift64*, plenty for shuffling reads reproducibly
+struct Rng(u64);
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck on a zero state
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+struct Sample {
+    latencies: Vec<Duration>,
+    bytes: usize
+}
+impl Sample {
+    fn new() -> Self {
+        Self { latencies: vec![], bytes: 0 }
+    }
+
+    fn percentile(&self, p: usize) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+
+        let idx = (self.latencies.len() - 1) * p / 100;
+        self.latencies[idx]
+    }
+
+    /// One line of `key=value` pairs, so that output can be diffed and parsed between runs
+    fn report(mut self, workload: Workload, out: &mut impl Write) -> std::io::Result<()> {
+        self.latencies.sort_unstable();
+
+        let total: Duration = self.latencies.iter().sum();
+        let mib_per_s = if total.is_zero() {
+            0.0
+        } else {
+            (self.bytes as f64 / (1024.0 * 1024.0)) / total.as_secs_f64()
+        };
+
+        writeln!(
+            out,
+            "workload={} ops={} bytes={} total_ns={} mib_per_s={:.2} p50_ns={} p90_ns={} p99_ns={} max_ns={}",
+            workload.name(),
+            self.latencies.len(),
+            self.bytes,
+            total.as_nanos(),
+            mib_per_s,
+            self.percentile(50).as_nanos(),
+            self.percentile(90).as_nanos(),
+            self.percentile(99).as_nanos(),
+            self.latencies.last().copied().unwrap_or_default().as_nanos()
+        )
+    }
+}
+
+/// Look up a chunk and decompress it, as a mount does to show it as NBT. Counts the NBT bytes;
+/// chunks that can't be decompressed count for nothing.
+fn timed_read(region: &RegionFile, x: u8, z: u8, sample: &mut Sample) {
+    let start = Instant::now();
+    let nbt = region.lookup_chunk(x, z).and_then(|chunk| codec::decompress(chunk.compression_type, chunk.data).ok());
+    sample.latencies.push(start.elapsed());
+    sample.bytes += nbt.map_or(0, |nbt| nbt.len());
+}
+
+pub(crate) fn run(
+    region: &RegionFile,
+    workloads: &[Workload],
+    iterations: usize,
+    seed: u64,
+    out: &mut impl Write
+) -> std::io::Result<()> {
+    let present: Vec<(u8, u8)> = region.present_chunks().collect();
+
+    for &workload in workloads {
+        let mut sample = Sample::new();
+
+        match workload {
+            Workload::Sequential => {
+                for _ in 0..iterations {
+                    for &(x, z) in &present {
+                        timed_read(region, x, z, &mut sample);
+                    }
+                }
+            }
+            Workload::Random => {
+                let mut rng = Rng::new(seed);
+                if !present.is_empty() {
+                    for _ in 0..(iterations * present.len()) {
+                        let (x, z) = present[(rng.next() % present.len() as u64) as usize];
+                        timed_read(region, x, z, &mut sample);
+                    }
+                }
+            }
+            Workload::Rewrite => {
+                // decompressed up front, so that only saving them is timed
+                let chunks: Vec<_> = present.iter()
+                    .filter_map(|&(x, z)| region.lookup_chunk(x, z))
+                    .filter(|chunk| codec::can_compress(chunk.compression_type))
+                    .filter_map(|chunk| {
+                        let nbt = codec::decompress(chunk.compression_type, chunk.data).ok()?;
+                        Some((chunk.x, chunk.z, nbt, chunk.compression_type, chunk.mtime))
+                    })
+                    .collect();
+
+                for _ in 0..iterations {
+                    let mut scratch = region.clone();
+
+                    // compressed and placed, as a write-back saves an edited chunk
+                    for (x, z, nbt, compression_type, mtime) in &chunks {
+                        let start = Instant::now();
+                        if let Ok(data) = codec::compress_with(*compression_type, nbt, None) {
+                            scratch.write_chunk(*x, *z, &data, *compression_type, *mtime);
+                        }
+                        sample.latencies.push(start.elapsed());
+                        sample.bytes += nbt.len();
+                    }
+                }
+            }
+        }
+
+        sample.report(workload, out)?;
+    }
+
+    Ok(())
+}
@@ -13,11 +13,15 @@
 * along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use clap::{ArgAction, Parser, ValueHint, Subcommand, Args};
+use std::time::{Duration, SystemTime};
+
+use clap::{ArgAction, Parser, ValueHint, Subcommand, Args, ValueEnum};
 use clap_complete::Shell;
+use fuser::MountOption;
 use regex::Regex;
 
-#[allow(dead_code)]
+use crate::{codec, smithy_fs::{is_reserved_name, parse_chunk_name}};
+
 #[derive(Clone, Debug)]
 pub struct ExtendedFilename {
     pub fname: String,
@@ -26,9 +30,9 @@ pub struct ExtendedFilename {
 }
 impl ExtendedFilename {
     fn parse(s: &str) -> Result<Self, String> {
-        let re = Regex::new(r"r\.(?P<x>-?\d+)\.(?P<z>-?\d+)\.mca$").unwrap();
+        let re = Regex::new(r"r\.(?P<x>-?\d+)\.(?P<z>-?\d+)\.mca(\.(gz|zst|xz))?$").unwrap();
 
-        let caps = re.captures(s).ok_or(format!("`{}` must end with r.{{x}}.{{z}}.mca", s))?;
+        let caps = re.captures(s).ok_or(format!("`{}` must end with r.{{x}}.{{z}}.mca (optionally followed by .gz, .zst or .xz)", s))?;
 
         let x = caps["x"].parse().map_err(|e| format!("x coordinate is not a number: {}", e))?;
         let z = caps["z"].parse().map_err(|e| format!("z coordinate is not a number: {}", e))?;
@@ -40,6 +44,127 @@ impl ExtendedFilename {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkName {
+    pub x: u8,
+    pub z: u8
+}
+impl ChunkName {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (x, z) = parse_chunk_name(s).ok_or(format!("`{}` is not a chunk name of the form x{{x}}z{{z}}", s))?;
+        Ok(Self { x, z })
+    }
+}
+
+/// A point in time, given as seconds since the epoch or in RFC 3339 form (`2025-06-01T12:00:00Z`)
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamp(pub SystemTime);
+impl Timestamp {
+    fn parse(s: &str) -> Result<Self, String> {
+        if let Ok(secs) = s.parse::<u64>() {
+            return Ok(Self(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)));
+        }
+
+        let re = Regex::new(
+            r"^(?P<y>\d{4})-(?P<mo>\d{2})-(?P<d>\d{2})[Tt ](?P<h>\d{2}):(?P<mi>\d{2}):(?P<s>\d{2})(\.\d+)?(?P<tz>[Zz]|[+-]\d{2}:\d{2})$"
+        ).unwrap();
+        let caps = re.captures(s).ok_or(format!("`{}` is neither seconds since the epoch nor an RFC 3339 time", s))?;
+
+        // every field is a short run of digits, so these can't fail
+        let field = |name: &str| caps[name].parse::<i64>().unwrap();
+        let (month, day, hour, minute, second) = (field("mo"), field("d"), field("h"), field("mi"), field("s"));
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+            return Err(format!("`{}` is not a valid time", s));
+        }
+
+        let offset = match &caps["tz"] {
+            "Z" | "z" => 0,
+            tz => {
+                let sign = if tz.starts_with('-') { -1 } else { 1 };
+                sign * (tz[1..3].parse::<i64>().unwrap() * 3600 + tz[4..6].parse::<i64>().unwrap() * 60)
+            }
+        };
+
+        let secs = days_from_civil(field("y"), month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+        let secs = u64::try_from(secs).map_err(|_| format!("`{}` is before 1970", s))?;
+        Ok(Self(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+}
+
+/// Days between 1970-01-01 and a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // shift the year to start in March, so the leap day comes last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+#[derive(Clone, Debug)]
+pub struct ChunkAlias {
+    pub name: String,
+    pub x: u8,
+    pub z: u8
+}
+#[derive(Clone, Debug)]
+pub enum FuseOption {
+    /// Add an option, replacing any default it contradicts
+    Set(MountOption),
+    /// Drop one of the default options
+    Unset(MountOption)
+}
+impl FuseOption {
+    fn parse(s: &str) -> Result<Self, String> {
+        let option = match s {
+            "ro" | "rw" => return Err("use -w to mount read-write".to_owned()),
+            "nodefault_permissions" => return Ok(Self::Unset(MountOption::DefaultPermissions)),
+            "auto_unmount" => MountOption::AutoUnmount,
+            "allow_other" => MountOption::AllowOther,
+            "allow_root" => MountOption::AllowRoot,
+            "default_permissions" => MountOption::DefaultPermissions,
+            "dev" => MountOption::Dev,
+            "nodev" => MountOption::NoDev,
+            "suid" => MountOption::Suid,
+            "nosuid" => MountOption::NoSuid,
+            "exec" => MountOption::Exec,
+            "noexec" => MountOption::NoExec,
+            "atime" => MountOption::Atime,
+            "noatime" => MountOption::NoAtime,
+            "dirsync" => MountOption::DirSync,
+            "sync" => MountOption::Sync,
+            "async" => MountOption::Async,
+            "" => return Err("mount options cannot be empty".to_owned()),
+            _ => match s.split_once('=') {
+                Some(("fsname", name)) => MountOption::FSName(name.to_owned()),
+                Some(("subtype", subtype)) => MountOption::Subtype(subtype.to_owned()),
+                _ => MountOption::CUSTOM(s.to_owned())
+            }
+        };
+
+        Ok(Self::Set(option))
+    }
+}
+
+impl ChunkAlias {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (name, chunk) = s.split_once('=').ok_or(format!("`{}` must be of the form NAME=x{{x}}z{{z}}", s))?;
+
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') || is_reserved_name(name) {
+            return Err(format!("`{}` cannot be used as an alias name", name));
+        }
+
+        let ChunkName { x, z } = ChunkName::parse(chunk)?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            x, z
+        })
+    }
+}
+
 #[derive(Parser)]
 #[command(name = "Smithy", bin_name="smithy")]
 #[command(author)]
@@ -54,6 +179,34 @@ pub struct Cli {
 pub enum Command {
     /// Mount a region file as a directory
     Mount(MountCmd),
+    /// List the coordinates of all chunks present in a region file
+    List(ListCmd),
+    /// Copy a chunk between regions, preserving its compression and timestamp
+    CopyChunk(CopyChunkCmd),
+    /// Create a new, empty region file
+    Format(FormatCmd),
+    /// Summarize a region, including chunks that will be deleted on the next write
+    Info(InfoCmd),
+    /// Write every chunk of a region to a directory, as the files a mount would show
+    Explode(ExplodeCmd),
+    /// Build a region from a directory of chunk files, as written by explode
+    Implode(ImplodeCmd),
+    /// Gather regions into one directory, merging copies of the same region chunk by chunk
+    Merge(MergeCmd),
+    /// Write the chunks of a region that differ from an older copy of it, as explode would
+    Delta(DeltaCmd),
+    /// Write selected chunks of a region to a directory, as explode would
+    Extract(ExtractCmd),
+    /// Print the raw location and timestamp tables of a region, flagging invalid entries
+    DumpHeader(DumpHeaderCmd),
+    /// Report groups of identical chunks and the space they take up
+    DedupReport(DedupReportCmd),
+    /// Check that every chunk of a region can be decompressed
+    Verify(VerifyCmd),
+    /// Rescue chunks from a region with a damaged header by scanning its sectors
+    Recover(RecoverCmd),
+    /// Measure region read/write performance without mounting
+    Bench(BenchCmd),
     /// Generate shell completions
     Completion(CompletionCmd),
 }
@@ -73,10 +226,475 @@ pub struct MountCmd {
     #[arg(action=ArgAction::SetTrue)]
     pub writable: bool,
 
+    /// Confirm that the region is backed up, which `--writable` requires (otherwise the mount is
+    /// read-only). Can also be given by setting SMITHY_I_HAVE_A_BACKUP=1.
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub i_have_a_backup: bool,
+
+    /// Show chunks as stored in the region, still compressed, instead of as decompressed NBT
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub raw: bool,
+
+    /// Show chunks as gzipped NBT, like standalone `.nbt` files, for NBT editors that expect it
+    #[arg(long, conflicts_with="raw")]
+    #[arg(action=ArgAction::SetTrue)]
+    pub standalone_nbt: bool,
+
+    /// The region is on a network filesystem (NFS, SMB, ...), so detect changes by other
+    /// writers without comparing the server's timestamps to the local clock. Saves are synced
+    /// the same way either way, so this doesn't change their durability
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub network_fs: bool,
+
+    /// Read the region starting this many bytes into the file, for regions embedded in a larger
+    /// container. Such mounts are read-only.
+    #[arg(long, value_name="BYTES")]
+    pub offset: Option<u64>,
+
+    /// Read at most this many bytes of the region (from `--offset`, if given)
+    #[arg(long, value_name="BYTES")]
+    pub length: Option<u64>,
+
+    /// Mount even if the mount point has files in it, hiding them until unmounted
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force_mount: bool,
+
     /// Automatically unmount on process exit
     #[arg(short='u', long)]
     #[arg(action=ArgAction::SetTrue)]
     pub auto_unmount: bool,
+
+    /// Uncompressed NBT file that new chunks start out with, instead of one holding only a DataVersion
+    #[arg(long, value_name="FILE")]
+    #[arg(value_hint=ValueHint::FilePath)]
+    pub chunk_template: Option<String>,
+
+    /// Periodically write read/write and save counters to this file, in the Prometheus text format
+    #[arg(long, value_name="FILE")]
+    #[arg(value_hint=ValueHint::FilePath)]
+    pub metrics_file: Option<String>,
+
+    /// Run this program after every successful save, with the region file's path as its only
+    /// argument. It runs in the background, with Smithy's own privileges
+    #[arg(long, value_name="PROGRAM")]
+    #[arg(value_hint=ValueHint::CommandName)]
+    pub on_save: Option<String>,
+
+    /// Give up on a save once the region file's storage hasn't answered for this long, making
+    /// the mount read-only instead of hanging it, e.g. for regions on a network filesystem
+    #[arg(long, value_name="SECONDS", value_parser=clap::value_parser!(u64).range(1..))]
+    pub io_timeout: Option<u64>,
+
+    /// Record every edit in this file until it is saved, and replay edits a crashed mount left
+    /// there. Created if missing
+    #[arg(long, value_name="FILE", conflicts_with="dry_run")]
+    #[arg(value_hint=ValueHint::FilePath)]
+    pub journal: Option<String>,
+
+    /// Replay the journal's edits even though they were made to another region file, or to this
+    /// one before it was changed by something else
+    #[arg(long, requires="journal")]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force_journal: bool,
+
+    /// Maximum number of bytes returned by a single read (the kernel will re-issue for the rest)
+    #[arg(long, value_name="BYTES", value_parser=clap::value_parser!(u32).range(1..))]
+    pub max_read: Option<u32>,
+
+    /// Try again this many times if another writer (such as a server mid-save) holds the region's lock
+    #[arg(long, value_name="N", default_value_t=0)]
+    pub open_retries: u32,
+
+    /// How long to wait before the first retry, doubling after each one
+    #[arg(long, value_name="MILLISECONDS", default_value_t=500)]
+    pub open_retry_delay: u64,
+
+    /// Compress saved chunks at this level, from 0 (fastest) to 9 (smallest), instead of each
+    /// codec's default. Only gzip and zlib have levels
+    #[arg(long, value_name="LEVEL", value_parser=clap::value_parser!(u32).range(0..=codec::MAX_LEVEL as i64))]
+    pub compression_level: Option<u32>,
+
+    /// Warn once the region's chunks take up more than this share of the sectors it can address
+    #[arg(long, value_name="PERCENT", value_parser=clap::value_parser!(u8).range(1..=100))]
+    pub warn_full: Option<u8>,
+
+    /// Write the whole region in one go instead of seeking to each changed sector once more than
+    /// this share of its sectors changed. 100 never does
+    #[arg(long, value_name="PERCENT", default_value_t=50, value_parser=clap::value_parser!(u8).range(1..=100))]
+    pub full_write_threshold: u8,
+
+    /// Expose a `latest.nbt` symlink to the most recently modified chunk
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub latest_link: bool,
+
+    /// Expose a symlink named NAME pointing at a chunk, e.g. `spawn=x0z0` (repeatable)
+    #[arg(long, value_name="NAME=CHUNK", value_parser=ChunkAlias::parse)]
+    pub alias: Vec<ChunkAlias>,
+
+    /// Expose expert-only control files, such as the raw region header. Misuse can destroy data.
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub expert: bool,
+
+    /// Name compression info files `.x#z#.cmp`, hiding them from plain `ls`
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub hidden_cmp: bool,
+
+    /// Order in which directory listings return chunk files
+    #[arg(long, value_enum, default_value_t=DirOrder::Coord)]
+    pub sort: DirOrder,
+
+    /// Compact the region file after each write-out that leaves free sectors between chunks
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub trim: bool,
+
+    /// Re-read the region after every write-out and compare it with what was written, making
+    /// the mount read-only on a mismatch
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub verify_writeback: bool,
+
+    /// Log where every saved chunk is placed in the region file, and how fragmented that leaves
+    /// it (at debug level, e.g. with `RUST_LOG=smithy::anvil=debug`)
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub profile_alloc: bool,
+
+    /// Give each chunk's files the same inode numbers on every mount, for backup tools that
+    /// track files by inode
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub stable_inos: bool,
+
+    /// Throw away changes that are still unsaved at unmount, instead of saving them
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub discard_on_exit: bool,
+
+    /// Keep changes in memory and log what saving them would write, without touching the region file
+    #[arg(long, requires="writable")]
+    #[arg(action=ArgAction::SetTrue)]
+    pub dry_run: bool,
+
+    /// Let files opened write-only be read as well, for libraries that read back what they wrote.
+    /// The kernel still refuses read(2) on such descriptors itself
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub permissive_read: bool,
+
+    /// Log progress while loading chunks, for large regions on slow disks
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub progress: bool,
+
+    /// Keep chunks that are cut off by their sectors instead of deleting them, as read-only files
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub lenient: bool,
+
+    /// Trust a chunk's length over a header that gives it too few sectors, as some world
+    /// converters write, as long as the sectors it needs are in the file and unclaimed
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub tolerant: bool,
+
+    /// Refuse regions that end partway through a sector, which Minecraft never writes, instead
+    /// of warning and padding them
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub strict: bool,
+
+    /// How far ahead of the clock a chunk's timestamp may be before it is warned about
+    #[arg(long, value_name="SECONDS", default_value_t=3600)]
+    pub future_margin: u64,
+
+    /// Set timestamps further ahead of the clock than --future-margin to the time of mounting,
+    /// stored with the next save
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub fix_future_mtime: bool,
+
+    /// Extra FUSE mount options, as for mount(8). `nodefault_permissions` turns off the kernel's permission checks.
+    #[arg(short='o', long="option", value_name="OPT", value_delimiter=',', value_parser=FuseOption::parse)]
+    pub options: Vec<FuseOption>,
+}
+
+#[derive(Args)]
+pub struct ListCmd {
+    /// Region (Anvil) file to inspect
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Print global chunk coordinates instead of region-local ones
+    #[arg(short, long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub global: bool,
+
+    /// Only list chunks saved at or after TIME (seconds since the epoch, or RFC 3339)
+    #[arg(long, value_name="TIME", value_parser=Timestamp::parse)]
+    pub since: Option<Timestamp>,
+}
+
+#[derive(Args)]
+pub struct CopyChunkCmd {
+    /// Region (Anvil) file to copy from
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub src_region: ExtendedFilename,
+
+    /// Chunk to copy, e.g. `x0z0`
+    #[arg(value_parser=ChunkName::parse)]
+    pub src_chunk: ChunkName,
+
+    /// Region (Anvil) file to copy into, created if it doesn't exist
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub dst_region: ExtendedFilename,
+
+    /// Chunk to copy to, e.g. `x5z5`
+    #[arg(value_parser=ChunkName::parse)]
+    pub dst_chunk: ChunkName,
+
+    /// Overwrite the destination chunk if it already exists
+    #[arg(short, long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct FormatCmd {
+    /// Region (Anvil) file to create
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Empty the region if it already exists, deleting all of its chunks
+    #[arg(short, long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct ExplodeCmd {
+    /// Region (Anvil) file to read
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Directory to write chunk files to, which must be empty or not exist yet
+    #[arg(value_hint=ValueHint::DirPath)]
+    pub out_dir: String,
+
+    /// Write into a directory that isn't empty, overwriting chunk files that are already there
+    #[arg(short, long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct ImplodeCmd {
+    /// Directory of `x#z#.nbt` and `x#z#.cmp` files
+    #[arg(value_hint=ValueHint::DirPath)]
+    pub in_dir: String,
+
+    /// Region (Anvil) file to create
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Replace the region if it already exists
+    #[arg(short, long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct MergeCmd {
+    /// Region (Anvil) files to gather
+    #[arg(required=true, value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub regions: Vec<ExtendedFilename>,
+
+    /// Directory to write the regions to (such as a world's `region/`), created if needed
+    #[arg(value_hint=ValueHint::DirPath)]
+    pub out_dir: String,
+
+    /// Merge regions with the same coordinates, keeping the most recently saved copy of each chunk
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub newest: bool,
+
+    /// Replace regions that already exist in the output directory
+    #[arg(short, long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct DeltaCmd {
+    /// Older copy of the region (Anvil) file to compare against
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub base_file: ExtendedFilename,
+
+    /// Region (Anvil) file to write changed chunks of
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Directory to write changed chunk files to, which must be empty or not exist yet
+    #[arg(value_hint=ValueHint::DirPath)]
+    pub out_dir: String,
+
+    /// Write into a directory that isn't empty, overwriting chunk files that are already there
+    #[arg(short, long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct ExtractCmd {
+    /// Region (Anvil) file to read
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Directory to write chunk files to, which must be empty or not exist yet. `-` writes the
+    /// chunks' decompressed NBT to standard output instead, one after another; give a directory
+    /// named `-` as `./-`
+    #[arg(value_hint=ValueHint::DirPath)]
+    pub out_dir: String,
+
+    /// Chunks to extract, e.g. `x0z0`
+    #[arg(value_parser=ChunkName::parse, required_unless_present="coords_file")]
+    pub chunks: Vec<ChunkName>,
+
+    /// Also extract the chunks listed in FILE, one `x z` pair per line (as printed by list)
+    #[arg(long, value_name="FILE")]
+    #[arg(value_hint=ValueHint::FilePath)]
+    pub coords_file: Option<String>,
+
+    /// Coordinates in the coords file are global chunk coordinates, as printed by `list --global`
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub global: bool,
+
+    /// Write into a directory that isn't empty, overwriting chunk files that are already there
+    #[arg(short, long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct InfoCmd {
+    /// Region (Anvil) file to inspect
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Also list the chunks saved at or after TIME (seconds since the epoch, or RFC 3339)
+    #[arg(long, value_name="TIME", value_parser=Timestamp::parse)]
+    pub since: Option<Timestamp>,
+}
+
+#[derive(Args)]
+pub struct DumpHeaderCmd {
+    /// Region (Anvil) file to inspect
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Print one JSON object per entry instead of a table
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct DedupReportCmd {
+    /// Region (Anvil) file to inspect
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+}
+
+#[derive(Args)]
+pub struct VerifyCmd {
+    /// Region (Anvil) file to check
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Also check that the position stored in each chunk matches its slot, and the region's file name
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub coords: bool,
+
+    /// How far ahead of the clock a chunk's timestamp may be before it counts as a problem
+    #[arg(long, value_name="SECONDS", default_value_t=3600)]
+    pub future_margin: u64,
+
+    /// Also print how many block entities and entities each chunk holds
+    #[arg(long)]
+    #[arg(action=ArgAction::SetTrue)]
+    pub stats: bool,
+
+    /// With --stats, warn about chunks holding more block entities or entities than this
+    #[arg(long, value_name="COUNT", default_value_t=500, requires="stats")]
+    pub entity_limit: usize,
+}
+
+#[derive(Args)]
+pub struct RecoverCmd {
+    /// Damaged region (Anvil) file to scan
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// New region file to write recovered chunks to (must not exist yet)
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub out_file: ExtendedFilename,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DirOrder {
+    /// By coordinates, row by row
+    Coord,
+    /// Largest first
+    Size,
+    /// Most recently modified first
+    Mtime,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Workload {
+    /// Read and decompress every chunk in index order
+    Sequential,
+    /// Read and decompress chunks in a (seeded) random order
+    Random,
+    /// Compress every chunk again and write it into an in-memory copy of the region
+    Rewrite,
+}
+impl Workload {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sequential => "sequential",
+            Self::Random => "random",
+            Self::Rewrite => "rewrite",
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct BenchCmd {
+    /// Region (Anvil) file to benchmark with (it is never modified)
+    #[arg(value_hint=ValueHint::FilePath, value_parser=ExtendedFilename::parse)]
+    pub region_file: ExtendedFilename,
+
+    /// Workloads to run, in order (default: all)
+    #[arg(short, long, value_enum)]
+    pub workload: Vec<Workload>,
+
+    /// Number of passes over the region per workload
+    #[arg(short, long, default_value_t=10)]
+    pub iterations: usize,
+
+    /// Seed for the random workload
+    #[arg(long, default_value_t=0x5eed)]
+    pub seed: u64,
 }
 
 #[derive(Args)]
@@ -0,0 +1,231 @@
+/*
+* Smithy
+* Copyright (C) 2025  Sam Wagenaar
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::{GzDecoder, MultiGzDecoder, ZlibDecoder}, write::{GzEncoder, ZlibEncoder}, Compression};
+use ruzstd::{decoding::StreamingDecoder, encoding::{compress_to_vec, CompressionLevel}};
+
+use crate::anvil::{CompressionType, HEADER_LEN, SECTOR_LEN};
+
+/// Longest decompressed chunk accepted. Anything that compresses into [`MAX_CHUNK_LEN`] in
+/// practice is far shorter.
+///
+/// [`MAX_CHUNK_LEN`]: crate::anvil::MAX_CHUNK_LEN
+pub(crate) const MAX_NBT_LEN: usize = 32 * 1024 * 1024;
+
+/// Decompress chunk data stored with `compression_type`.
+/// Codecs smithy can't decode yield an error of kind [`io::ErrorKind::Unsupported`], and data
+/// that decompresses to more than [`MAX_NBT_LEN`] bytes one of kind [`io::ErrorKind::InvalidData`],
+/// so that a decompression bomb can't exhaust memory.
+pub(crate) fn decompress(compression_type: CompressionType, data: &[u8]) -> io::Result<Vec<u8>> {
+    match compression_type {
+        CompressionType::GZip => read_bounded(GzDecoder::new(data)),
+        CompressionType::Zlib => read_bounded(ZlibDecoder::new(data)),
+        CompressionType::Zstd => read_bounded(
+            StreamingDecoder::new(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+        ),
+        CompressionType::None => read_bounded(data),
+        ct => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot decompress {} data", ct.name())
+        )),
+    }
+}
+
+/// Read all of `reader`, failing once it yields more than [`MAX_NBT_LEN`] bytes
+fn read_bounded(reader: impl Read) -> io::Result<Vec<u8>> {
+    read_limited(reader, MAX_NBT_LEN)
+}
+
+/// Read all of `reader`, failing once it yields more than `limit` bytes
+fn read_limited(reader: impl Read, limit: usize) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    reader.take(limit as u64 + 1).read_to_end(&mut out)?;
+
+    if out.len() > limit {
+        return Err(too_long(limit));
+    }
+
+    Ok(out)
+}
+
+fn too_long(limit: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("decompresses to more than {} bytes", limit))
+}
+
+/// Collects output like a `Vec`, failing once more than `limit` bytes are written to it
+struct LimitedWriter {
+    out: Vec<u8>,
+    limit: usize
+}
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.out.len() + buf.len() > self.limit {
+            return Err(too_long(self.limit));
+        }
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether [`compress`] can produce `compression_type` data
+pub(crate) fn can_compress(compression_type: CompressionType) -> bool {
+    matches!(
+        compression_type,
+        CompressionType::GZip | CompressionType::Zlib | CompressionType::Zstd | CompressionType::None
+    )
+}
+
+/// Highest level accepted by [`compress_with`]
+pub(crate) const MAX_LEVEL: u32 = 9;
+
+/// Compress NBT for storage with `compression_type`, at each codec's default level.
+/// Codecs smithy can't encode yield an error of kind [`io::ErrorKind::Unsupported`].
+pub(crate) fn compress(compression_type: CompressionType, data: &[u8]) -> io::Result<Vec<u8>> {
+    compress_with(compression_type, data, None)
+}
+
+/// [`compress`] at `level`, from 0 (fastest) to [`MAX_LEVEL`] (smallest), instead of the default.
+/// Only gzip and zlib honour it: ruzstd only implements its fastest level, so zstd ignores it.
+pub(crate) fn compress_with(compression_type: CompressionType, data: &[u8], level: Option<u32>) -> io::Result<Vec<u8>> {
+    #[cfg(test)]
+    tests::COMPRESSIONS.with(|count| count.set(count.get() + 1));
+
+    let level = level.map_or(Compression::default(), |level| Compression::new(level.min(MAX_LEVEL)));
+
+    match compression_type {
+        CompressionType::GZip => {
+            let mut encoder = GzEncoder::new(vec![], level);
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        CompressionType::Zlib => {
+            let mut encoder = ZlibEncoder::new(vec![], level);
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        CompressionType::Zstd => Ok(compress_to_vec(data, CompressionLevel::Fastest)),
+        CompressionType::None => Ok(data.to_vec()),
+        ct => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot compress {} data", ct.name())
+        )),
+    }
+}
+
+/// Longest region accepted from an archive: a header and every chunk at the most sectors a
+/// location entry can give it
+pub(crate) const MAX_REGION_LEN: usize = HEADER_LEN + 1024 * 255 * SECTOR_LEN;
+
+/// Whole-file compression of an archived region, detected from its extension
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Archive {
+    Gzip,
+    Zstd,
+    Xz
+}
+impl Archive {
+    pub(crate) fn from_fname(fname: &str) -> Option<Self> {
+        if fname.ends_with(".gz") {
+            Some(Self::Gzip)
+        } else if fname.ends_with(".zst") {
+            Some(Self::Zstd)
+        } else if fname.ends_with(".xz") {
+            Some(Self::Xz)
+        } else {
+            None
+        }
+    }
+
+    /// Decompress a whole archived region. Anything longer than [`MAX_REGION_LEN`] is refused
+    /// with an error of kind [`io::ErrorKind::InvalidData`], as no region can be.
+    pub(crate) fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.decompress_limited(data, MAX_REGION_LEN)
+    }
+
+    fn decompress_limited(self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        match self {
+            // pigz and friends write several gzip members back to back
+            Self::Gzip => read_limited(MultiGzDecoder::new(data), limit),
+            Self::Zstd => read_limited(
+                StreamingDecoder::new(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+                limit
+            ),
+            Self::Xz => {
+                let mut out = LimitedWriter { out: vec![], limit };
+                lzma_rs::xz_decompress(&mut io::BufReader::new(data), &mut out)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                Ok(out.out)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    thread_local! {
+        /// Calls to [`compress_with`] made by the current test
+        pub(crate) static COMPRESSIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[test]
+    fn decompression_bomb_is_refused() {
+        let bomb = compress(CompressionType::Zlib, &vec![0; MAX_NBT_LEN + 1]).unwrap();
+        let err = decompress(CompressionType::Zlib, &bomb).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn largest_chunk_decompresses() {
+        let nbt = vec![0; MAX_NBT_LEN];
+        for ct in [CompressionType::GZip, CompressionType::Zlib, CompressionType::Zstd, CompressionType::None] {
+            let stored = compress(ct, &nbt).unwrap();
+            assert_eq!(decompress(ct, &stored).unwrap().len(), MAX_NBT_LEN, "{}", ct.name());
+        }
+    }
+
+    #[test]
+    fn archive_bomb_is_refused() {
+        // the real limit is a gigabyte, which takes a while to compress
+        const LIMIT: usize = 1024 * 1024;
+        let region = vec![0; LIMIT + 1];
+        let gzip = compress(CompressionType::GZip, &region).unwrap();
+        let zstd = compress(CompressionType::Zstd, &region).unwrap();
+        let mut xz = vec![];
+        lzma_rs::xz_compress(&mut io::BufReader::new(&region[..]), &mut xz).unwrap();
+
+        for (archive, data) in [(Archive::Gzip, gzip), (Archive::Zstd, zstd), (Archive::Xz, xz)] {
+            let err = archive.decompress_limited(&data, LIMIT).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", archive);
+            assert_eq!(archive.decompress_limited(&data, LIMIT + 1).unwrap().len(), LIMIT + 1, "{:?}", archive);
+        }
+    }
+
+    #[test]
+    fn multi_member_gzip_is_read_whole() {
+        let mut data = compress(CompressionType::GZip, b"first ").unwrap();
+        data.extend(compress(CompressionType::GZip, b"second").unwrap());
+        assert_eq!(Archive::Gzip.decompress(&data).unwrap(), b"first second");
+    }
+}
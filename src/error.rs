@@ -0,0 +1,216 @@
+/*
+* Smithy
+* Copyright (C) 2025  Sam Wagenaar
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::{fmt::Display, io::{self, ErrorKind}};
+
+use crate::anvil::RegionError;
+
+/// Exit statuses for failed commands, so that scripts can tell failures apart. Clap already
+/// exits with [`ExitStatus::Usage`] for malformed arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExitStatus {
+    /// Anything not covered below, such as I/O errors
+    Failure = 1,
+    /// The arguments can't work together, or with the files they name
+    Usage = 2,
+    /// A region is damaged
+    Corrupt = 3,
+    /// Going ahead would have overwritten existing data
+    WouldLoseData = 4,
+}
+
+#[derive(Debug)]
+pub(crate) enum SmithyError {
+    /// Opening the region file failed
+    OpenRegion(String, io::Error),
+    /// Reading the region file failed
+    ReadRegion(String, io::Error),
+    /// The region file is too short to contain a header
+    TruncatedRegion(String, usize),
+    /// The region file ends partway through a sector
+    MisalignedRegion { path: String, expected: usize, actual: usize },
+    /// The region file can't be parsed
+    InvalidRegion(String, RegionError),
+    /// Writing the region file failed
+    WriteRegion(String, io::Error),
+    /// A compressed region archive was opened for writing
+    ArchiveReadOnly(String),
+    /// A region embedded in a larger file was opened for writing
+    WindowReadOnly(String),
+    /// A region file would be overwritten without permission
+    RegionExists(String),
+    /// The file new chunks are initialized from can't be used
+    ChunkTemplate(String, io::Error),
+    /// Reading or writing an exploded chunk directory failed
+    ChunkDir(String, io::Error),
+    /// A `.cmp` file doesn't hold a compression type
+    InvalidCompression(String),
+    /// Reading a list of chunk coordinates failed
+    CoordsFile(String, io::Error),
+    /// A line of a coordinate list isn't a chunk in the region
+    InvalidCoords { path: String, line: usize },
+    /// A chunk that should exist doesn't
+    MissingChunk { path: String, x: u8, z: u8 },
+    /// A chunk's data can't be decompressed
+    ChunkUnreadable { path: String, x: u8, z: u8, error: io::Error },
+    /// A chunk would be overwritten without permission
+    ChunkExists { path: String, x: u8, z: u8 },
+    /// Several regions to be merged have the same coordinates
+    RegionConflict { x: isize, z: isize, paths: Vec<String> },
+    /// A chunk could not be stored in the region
+    ChunkNotWritten { path: String, x: u8, z: u8 },
+    /// Checking a region's chunks found problems
+    VerifyFailed(String, usize),
+    /// The mount point would hide the region file it mounts
+    MountOverRegion { mount_point: String, path: String },
+    /// The journal of unsaved edits can't be used
+    Journal(String, io::Error),
+    /// The mount point has files that the mount would hide
+    MountPointNotEmpty(String),
+    /// Creating the FUSE session failed
+    Mount(String, io::Error),
+    /// The FUSE session stopped with an error
+    Session(io::Error),
+    /// Writing command output failed
+    Output(io::Error),
+}
+
+impl Display for SmithyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OpenRegion(path, e) => match e.kind() {
+                ErrorKind::NotFound => write!(f, "Region file `{}` does not exist", path),
+                ErrorKind::PermissionDenied => write!(f, "Permission denied while opening region file `{}`", path),
+                ErrorKind::WouldBlock => write!(f, "Region file `{}` is locked by another process, which may be writing to it", path),
+                _ => write!(f, "Failed to open region file `{}`: {}", path, e),
+            },
+            Self::ReadRegion(path, e) => write!(f, "Failed to read region file `{}`: {}", path, e),
+            Self::TruncatedRegion(path, len) => write!(
+                f,
+                "`{}` is not a region file: it is only {} bytes long, too short to contain a region header",
+                path, len
+            ),
+            Self::MisalignedRegion { path, expected, actual } => write!(
+                f,
+                "`{}` is {} bytes long, not a whole number of sectors ({} bytes expected), so it may have been truncated",
+                path, actual, expected
+            ),
+            Self::InvalidRegion(path, e) => write!(f, "`{}` is not a usable region file: {}", path, e),
+            Self::WriteRegion(path, e) => write!(f, "Failed to write region file `{}`: {}", path, e),
+            Self::ArchiveReadOnly(path) => write!(f, "`{}` is a compressed archive and can only be opened read-only", path),
+            Self::WindowReadOnly(path) => write!(f, "Only part of `{}` is mounted (--offset/--length), so it can only be opened read-only", path),
+            Self::RegionExists(path) => write!(f, "Region file `{}` already exists (use --force to overwrite it)", path),
+            Self::ChunkTemplate(path, e) => write!(f, "Can't use chunk template `{}`: {}", path, e),
+            Self::ChunkDir(path, e) => match e.kind() {
+                ErrorKind::DirectoryNotEmpty => write!(f, "`{}` is not empty (use --force to write into it anyway)", path),
+                _ => write!(f, "Failed to access chunk directory `{}`: {}", path, e),
+            },
+            Self::InvalidCompression(path) => write!(f, "`{}` does not contain a compression type", path),
+            Self::CoordsFile(path, e) => write!(f, "Failed to read coordinates from `{}`: {}", path, e),
+            Self::InvalidCoords { path, line } => write!(f, "Line {} of `{}` is not an `x z` pair of a chunk in the region", line, path),
+            Self::MissingChunk { path, x, z } => write!(f, "Chunk [{} {}] is not present in `{}`", x, z, path),
+            Self::ChunkUnreadable { path, x, z, error } => write!(f, "Chunk [{} {}] of `{}` can't be decompressed: {}", x, z, path, error),
+            Self::ChunkExists { path, x, z } => write!(f, "Chunk [{} {}] already exists in `{}` (use --force to overwrite it)", x, z, path),
+            Self::RegionConflict { x, z, paths } => write!(
+                f,
+                "`{}` are copies of the same region ({} {}), use --newest to merge them, keeping the newest copy of each chunk",
+                paths.join("`, `"), x, z
+            ),
+            Self::ChunkNotWritten { path, x, z } => write!(f, "Chunk [{} {}] could not be stored in `{}`", x, z, path),
+            Self::VerifyFailed(path, count) => write!(f, "Found {} problems with chunks in `{}`", count, path),
+            Self::MountOverRegion { mount_point, path } => write!(
+                f,
+                "Mounting at `{}` would hide `{}` behind its own mount, choose a mount point outside the region's directory",
+                mount_point, path
+            ),
+            Self::MountPointNotEmpty(mount_point) => write!(
+                f,
+                "Mount point `{}` is not empty, and its files would be hidden while mounted (use --force-mount to mount there anyway)",
+                mount_point
+            ),
+            Self::Journal(path, e) => write!(f, "Can't use journal `{}`: {}", path, e),
+            Self::Mount(mount_point, e) => write!(f, "Failed to mount at `{}`: {}", mount_point, e),
+            Self::Session(e) => write!(f, "FUSE session failed: {}", e),
+            Self::Output(e) => write!(f, "Failed to write output: {}", e),
+        }
+    }
+}
+
+impl SmithyError {
+    pub(crate) fn exit_status(&self) -> ExitStatus {
+        match self {
+            Self::TruncatedRegion(_, _)
+            | Self::MisalignedRegion { .. }
+            | Self::InvalidRegion(_, _)
+            | Self::ChunkUnreadable { .. }
+            | Self::VerifyFailed(_, _) => ExitStatus::Corrupt,
+            Self::RegionExists(_)
+            | Self::ChunkExists { .. }
+            | Self::RegionConflict { .. } => ExitStatus::WouldLoseData,
+            Self::ChunkDir(_, e) if e.kind() == ErrorKind::DirectoryNotEmpty => ExitStatus::WouldLoseData,
+            Self::ArchiveReadOnly(_)
+            | Self::WindowReadOnly(_)
+            | Self::MountOverRegion { .. }
+            | Self::MountPointNotEmpty(_)
+            | Self::InvalidCoords { .. } => ExitStatus::Usage,
+            Self::OpenRegion(_, _)
+            | Self::ReadRegion(_, _)
+            | Self::WriteRegion(_, _)
+            | Self::ChunkTemplate(_, _)
+            | Self::ChunkDir(_, _)
+            | Self::InvalidCompression(_)
+            | Self::CoordsFile(_, _)
+            | Self::MissingChunk { .. }
+            | Self::ChunkNotWritten { .. }
+            | Self::Journal(_, _)
+            | Self::Mount(_, _)
+            | Self::Session(_)
+            | Self::Output(_) => ExitStatus::Failure,
+        }
+    }
+}
+
+impl std::error::Error for SmithyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::OpenRegion(_, e)
+            | Self::ReadRegion(_, e)
+            | Self::WriteRegion(_, e)
+            | Self::ChunkTemplate(_, e)
+            | Self::ChunkDir(_, e)
+            | Self::CoordsFile(_, e)
+            | Self::Journal(_, e)
+            | Self::Mount(_, e)
+            | Self::Session(e)
+            | Self::Output(e) => Some(e),
+            Self::InvalidRegion(_, e) => Some(e),
+            Self::ChunkUnreadable { error, .. } => Some(error),
+            Self::TruncatedRegion(_, _)
+            | Self::ArchiveReadOnly(_)
+            | Self::WindowReadOnly(_)
+            | Self::RegionExists(_)
+            | Self::InvalidCompression(_)
+            | Self::InvalidCoords { .. }
+            | Self::MisalignedRegion { .. }
+            | Self::VerifyFailed(_, _)
+            | Self::MountOverRegion { .. }
+            | Self::MountPointNotEmpty(_)
+            | Self::MissingChunk { .. }
+            | Self::ChunkExists { .. }
+            | Self::RegionConflict { .. }
+            | Self::ChunkNotWritten { .. } => None,
+        }
+    }
+}
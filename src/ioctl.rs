@@ -0,0 +1,61 @@
+/*
+* Smithy
+* Copyright (C) 2025  Sam Wagenaar
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+
+//! ioctl commands understood by every file and directory of a mount, for programs that want a
+//! typed channel instead of parsing control files. Numbers use the generic Linux encoding.
+
+use std::mem::size_of;
+
+/// `type` byte shared by all smithy ioctls
+pub(crate) const IOCTL_TYPE: u8 = b'S';
+
+const IOC_NONE: u32 = 0;
+const IOC_READ: u32 = 2;
+
+const fn ioc(dir: u32, nr: u8, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((IOCTL_TYPE as u32) << 8) | nr as u32
+}
+
+/// Write all unsaved changes to the region file
+pub(crate) const FLUSH: u32 = ioc(IOC_NONE, 1, 0);
+/// Save, then compact the region file. Returns the number of sectors freed.
+pub(crate) const DEFRAG: u32 = ioc(IOC_NONE, 2, 0);
+/// Read a [`Stats`]
+pub(crate) const GET_STATS: u32 = ioc(IOC_READ, 3, size_of::<Stats>());
+/// Discard unsaved changes to the chunk of the file it is called on, going back to its last
+/// saved state
+pub(crate) const RESET: u32 = ioc(IOC_NONE, 4, 0);
+
+/// Reply to [`GET_STATS`], as native-endian `u32`s
+#[repr(C)]
+pub(crate) struct Stats {
+    pub(crate) chunks: u32,
+    /// Sectors allocated to chunks
+    pub(crate) used_sectors: u32,
+    /// Sectors from the end of the header to the end of the last chunk
+    pub(crate) file_sectors: u32,
+    /// Chunks with changes that haven't been written yet
+    pub(crate) unsaved_chunks: u32,
+}
+impl Stats {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        [self.chunks, self.used_sectors, self.file_sectors, self.unsaved_chunks]
+            .into_iter()
+            .flat_map(u32::to_ne_bytes)
+            .collect()
+    }
+}
@@ -0,0 +1,344 @@
+/*
+* Smithy
+* Copyright (C) 2025  Sam Wagenaar
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+
+//! Journal of unsaved chunk edits for `--journal`, so that edits which never made it into the
+//! region (because Smithy crashed, or saving kept failing) can be replayed by the next mount.
+//!
+//! The file starts with a header naming what the edits apply to: the magic `SMJ2`, a byte naming
+//! the chunk view the edits were made in, the `u64` length of the region file and a `u64` FNV-1a
+//! hash of its chunk table as last saved, and the region's canonical path (`u16` length, then
+//! its bytes). One record per edit follows: a tag byte, the chunk's region-local `x` and `z`, and
+//! the tag's fields. Numbers are big-endian throughout.
+//!
+//! | Tag | Edit | Fields |
+//! |-----|------|--------|
+//! | 1 | create | |
+//! | 2 | write | `u64` offset, `u32` length, data |
+//! | 3 | truncate | `u64` length |
+//! | 4 | delete | |
+//! | 5 | set compression | `u8` compression id |
+//! | 6 | reset | |
+//!
+//! Edits only make sense against the region state they were made on, so a journal whose region
+//! path, length or chunk table no longer match is refused unless replaying is forced. Whenever a
+//! save leaves no unsaved chunks behind, the edits are dropped and the header names the new state.
+
+use std::{ffi::OsStr, fs::{File, OpenOptions}, io::{self, ErrorKind, Read, Write}, os::unix::{ffi::OsStrExt, fs::FileExt}, path::{Path, PathBuf}};
+
+use log::warn;
+
+use crate::{anvil::{CompressionType, HEADER_LEN as REGION_HEADER_LEN}, smithy_fs::ChunkView};
+
+const MAGIC: &[u8; 4] = b"SMJ2";
+/// Length of the header up to the region path's bytes
+const FIXED_HEADER_LEN: usize = MAGIC.len() + 1 + 8 + 8 + 2;
+
+/// An edit of a chunk, in the form `.nbt` files of the journal's view show it
+#[derive(Debug)]
+pub(crate) enum Entry {
+    Create { x: u8, z: u8 },
+    Write { x: u8, z: u8, offset: u64, data: Vec<u8> },
+    Truncate { x: u8, z: u8, len: u64 },
+    Delete { x: u8, z: u8 },
+    Compression { x: u8, z: u8, compression_type: CompressionType },
+    Reset { x: u8, z: u8 },
+}
+impl Entry {
+    fn encode(&self) -> Vec<u8> {
+        let (tag, x, z) = match *self {
+            Self::Create { x, z } => (1, x, z),
+            Self::Write { x, z, .. } => (2, x, z),
+            Self::Truncate { x, z, .. } => (3, x, z),
+            Self::Delete { x, z } => (4, x, z),
+            Self::Compression { x, z, .. } => (5, x, z),
+            Self::Reset { x, z } => (6, x, z),
+        };
+
+        let mut out = vec![tag, x, z];
+        match self {
+            Self::Write { offset, data, .. } => {
+                out.extend_from_slice(&offset.to_be_bytes());
+                out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                out.extend_from_slice(data);
+            }
+            Self::Truncate { len, .. } => out.extend_from_slice(&len.to_be_bytes()),
+            Self::Compression { compression_type, .. } => out.push(compression_type.encode()),
+            Self::Create { .. } | Self::Delete { .. } | Self::Reset { .. } => {}
+        }
+        out
+    }
+
+    /// Parse the record at the start of `data`, returning it with its length. `Ok(None)` means the
+    /// record is cut off, as the last one is if Smithy died while appending it.
+    fn decode(data: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        let Some(&[tag, x, z]) = data.first_chunk() else {
+            return Ok(None);
+        };
+        if x >= 32 || z >= 32 {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("chunk [{} {}] is outside the region", x, z)));
+        }
+
+        let fields = &data[3..];
+        let entry = match tag {
+            1 => (Self::Create { x, z }, 0),
+            2 => {
+                let (Some(offset), Some(len)) = (fields.first_chunk::<8>(), fields.get(8..).and_then(<[u8]>::first_chunk::<4>)) else {
+                    return Ok(None);
+                };
+                let len = u32::from_be_bytes(*len) as usize;
+                let Some(data) = fields.get(12..12 + len) else {
+                    return Ok(None);
+                };
+                (Self::Write { x, z, offset: u64::from_be_bytes(*offset), data: data.to_vec() }, 12 + len)
+            }
+            3 => match fields.first_chunk::<8>() {
+                Some(len) => (Self::Truncate { x, z, len: u64::from_be_bytes(*len) }, 8),
+                None => return Ok(None),
+            },
+            4 => (Self::Delete { x, z }, 0),
+            5 => match fields.first() {
+                Some(&id) => (Self::Compression { x, z, compression_type: CompressionType::decode(id) }, 1),
+                None => return Ok(None),
+            },
+            6 => (Self::Reset { x, z }, 0),
+            tag => return Err(io::Error::new(ErrorKind::InvalidData, format!("unknown record type {}", tag))),
+        };
+
+        Ok(Some((entry.0, 3 + entry.1)))
+    }
+}
+
+fn view_id(view: ChunkView) -> u8 {
+    match view {
+        ChunkView::Stored => 0,
+        ChunkView::Nbt => 1,
+        ChunkView::Standalone => 2,
+    }
+}
+
+/// FNV-1a, which unlike std's hashers is guaranteed to hash the same way in every build
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// The state of a region file that journalled edits were made against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RegionState {
+    path: PathBuf,
+    len: u64,
+    header_hash: u64,
+}
+impl RegionState {
+    /// Read the current state of `file`, the region at `path`
+    pub(crate) fn read(path: &Path, file: &File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        let mut header = vec![0; REGION_HEADER_LEN.min(len as usize)];
+        file.read_exact_at(&mut header, 0)?;
+        Ok(Self { path: path.canonicalize()?, len, header_hash: fnv1a(&header) })
+    }
+
+    fn encode(&self, view: u8) -> io::Result<Vec<u8>> {
+        let path = self.path.as_os_str().as_bytes();
+        let path_len = u16::try_from(path.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "the region's path is too long to journal"))?;
+
+        let mut out = Vec::with_capacity(FIXED_HEADER_LEN + path.len());
+        out.extend_from_slice(MAGIC);
+        out.push(view);
+        out.extend_from_slice(&self.len.to_be_bytes());
+        out.extend_from_slice(&self.header_hash.to_be_bytes());
+        out.extend_from_slice(&path_len.to_be_bytes());
+        out.extend_from_slice(path);
+        Ok(out)
+    }
+
+    /// Parse a journal header, returning its view byte, the state it names and its length
+    fn decode(data: &[u8]) -> Option<(u8, Self, usize)> {
+        let fixed = data.strip_prefix(MAGIC)?.first_chunk::<{ FIXED_HEADER_LEN - MAGIC.len() }>()?;
+        let view = fixed[0];
+        let len = u64::from_be_bytes(fixed[1..9].try_into().unwrap());
+        let header_hash = u64::from_be_bytes(fixed[9..17].try_into().unwrap());
+        let path_len = u16::from_be_bytes(fixed[17..19].try_into().unwrap()) as usize;
+        let path = data.get(FIXED_HEADER_LEN..FIXED_HEADER_LEN + path_len)?;
+
+        let path = Path::new(OsStr::from_bytes(path)).to_owned();
+        Some((view, Self { path, len, header_hash }, FIXED_HEADER_LEN + path_len))
+    }
+}
+
+pub(crate) struct Journal {
+    file: File,
+    path: PathBuf,
+    view: u8,
+    region: RegionState,
+}
+impl Journal {
+    /// Open (or create) the journal at `path`, returning the edits it already holds. Edits made
+    /// in a different view than `view` can't be replayed, so such journals are refused, as are
+    /// ones made against a different region or region state than `region` unless `force` is set.
+    pub(crate) fn open(path: &Path, view: ChunkView, region: RegionState, force: bool) -> io::Result<(Self, Vec<Entry>)> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let mut journal = Self { file, path: path.to_owned(), view: view_id(view), region };
+        if data.is_empty() {
+            journal.write_header()?;
+            return Ok((journal, vec![]));
+        }
+
+        let Some((journal_view, journal_region, header_len)) = RegionState::decode(&data) else {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a smithy journal"));
+        };
+        if journal_view != journal.view {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "its edits were made with a different --raw or --standalone-nbt setting, mount with the same one to replay them"
+            ));
+        }
+        if data.len() == header_len {
+            // nothing to replay, so it can just take on the region as it is now
+            journal.clear(journal.region.clone())?;
+            return Ok((journal, vec![]));
+        }
+        if journal_region.path != journal.region.path {
+            let message = format!("its edits were made to `{}`, pass --force-journal to replay them anyway", journal_region.path.display());
+            if !force {
+                return Err(io::Error::new(ErrorKind::InvalidData, message));
+            }
+            warn!("Journal `{}`: {}", path.display(), message);
+        } else if journal_region != journal.region {
+            let message = "the region was changed since its edits were made, pass --force-journal to replay them anyway";
+            if !force {
+                return Err(io::Error::new(ErrorKind::InvalidData, message));
+            }
+            warn!("Journal `{}`: {}", path.display(), message);
+        }
+
+        let mut entries = vec![];
+        let mut pos = header_len;
+        while pos < data.len() {
+            match Entry::decode(&data[pos..])? {
+                Some((entry, len)) => {
+                    entries.push(entry);
+                    pos += len;
+                }
+                None => {
+                    warn!("Dropping an incomplete edit at the end of journal `{}`", path.display());
+                    journal.file.set_len(pos as u64)?;
+                    break;
+                }
+            }
+        }
+
+        Ok((journal, entries))
+    }
+
+    pub(crate) fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        self.file.write_all(&entry.encode())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.region.encode(self.view)?)
+    }
+
+    /// Forget every edit, so that new ones are made against `region`
+    fn clear(&mut self, region: RegionState) -> io::Result<()> {
+        self.region = region;
+        // appends land at the end, so the header can only be rewritten from scratch
+        self.file.set_len(0)?;
+        self.write_header()
+    }
+
+    /// Forget every edit, taking on `region_file`'s current state. Done once they are all saved,
+    /// or can no longer apply
+    pub(crate) fn reset(&mut self, region_file: &File) -> io::Result<()> {
+        let region = RegionState::read(&self.region.path, region_file)?;
+        self.clear(region)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::util::TempPath;
+
+    fn region(path: &Path, header: &[u8]) -> RegionState {
+        fs::write(path, header).unwrap();
+        RegionState::read(path, &File::open(path).unwrap()).unwrap()
+    }
+
+    fn journal_with_edit(journal_path: &Path, state: RegionState) {
+        let (mut journal, entries) = Journal::open(journal_path, ChunkView::Nbt, state, false).unwrap();
+        assert!(entries.is_empty());
+        journal.append(&Entry::Delete { x: 1, z: 2 }).unwrap();
+    }
+
+    #[test]
+    fn edits_replay_onto_the_same_state() {
+        let (region_path, journal_path) = (TempPath::new("r.0.0.mca"), TempPath::new("edits.smj"));
+        journal_with_edit(&journal_path.0, region(&region_path.0, &[1; 64]));
+
+        let (_, entries) = Journal::open(&journal_path.0, ChunkView::Nbt, region(&region_path.0, &[1; 64]), false).unwrap();
+        assert!(matches!(entries[..], [Entry::Delete { x: 1, z: 2 }]));
+    }
+
+    #[test]
+    fn changed_region_is_refused_unless_forced() {
+        let (region_path, journal_path) = (TempPath::new("r.0.0.mca"), TempPath::new("edits.smj"));
+        journal_with_edit(&journal_path.0, region(&region_path.0, &[1; 64]));
+
+        let changed = region(&region_path.0, &[2; 64]);
+        let err = Journal::open(&journal_path.0, ChunkView::Nbt, changed.clone(), false).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let grown = region(&region_path.0, &[1; 65]);
+        assert!(Journal::open(&journal_path.0, ChunkView::Nbt, grown, false).is_err());
+
+        let (_, entries) = Journal::open(&journal_path.0, ChunkView::Nbt, changed, true).unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn other_region_is_refused() {
+        let (region_path, other_path, journal_path) = (TempPath::new("r.0.0.mca"), TempPath::new("r.0.1.mca"), TempPath::new("edits.smj"));
+        journal_with_edit(&journal_path.0, region(&region_path.0, &[1; 64]));
+
+        assert!(Journal::open(&journal_path.0, ChunkView::Nbt, region(&other_path.0, &[1; 64]), false).is_err());
+    }
+
+    #[test]
+    fn reset_takes_on_the_saved_state() {
+        let (region_path, journal_path) = (TempPath::new("r.0.0.mca"), TempPath::new("edits.smj"));
+        let (mut journal, _) = Journal::open(&journal_path.0, ChunkView::Nbt, region(&region_path.0, &[1; 64]), false).unwrap();
+        journal.append(&Entry::Delete { x: 1, z: 2 }).unwrap();
+
+        region(&region_path.0, &[2; 64]);
+        journal.reset(&File::open(&region_path.0).unwrap()).unwrap();
+        journal.append(&Entry::Create { x: 3, z: 4 }).unwrap();
+        drop(journal);
+
+        let (_, entries) = Journal::open(&journal_path.0, ChunkView::Nbt, region(&region_path.0, &[2; 64]), false).unwrap();
+        assert!(matches!(entries[..], [Entry::Create { x: 3, z: 4 }]));
+    }
+}
@@ -13,23 +13,34 @@
 * along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::{io::Read, sync::Arc};
+use std::{collections::HashMap, fs::{self, File}, hash::{DefaultHasher, Hash, Hasher}, io::{self, ErrorKind, Read, Seek, SeekFrom, Write}, path::Path, process::ExitCode, sync::{Arc, PoisonError}, time::{Duration, SystemTime}};
 
-use anvil::RegionFile;
+use anvil::{CompressionType, RegionFile, HEADER_LEN, MAX_CHUNK_LEN, SECTOR_LEN};
 use clap::{CommandFactory, Parser};
+use codec::Archive;
 use clap_complete::{generate, generate_to};
+use error::SmithyError;
 use fuser::MountOption;
 use libc::{getegid, geteuid};
-use log::{debug, error, info};
-use smithy_fs::SmithyFS;
+use log::{debug, error, info, warn};
+use journal::{Journal, RegionState};
+use metrics::Metrics;
+use smithy_fs::{chunk_file_names, chunk_list, warning_list, ChunkView, FsOptions, LinkTarget, SmithyFS};
 use util::GuardedFile;
 
 mod util;
 mod smithy_fs;
 mod cli;
 mod anvil;
+mod error;
+mod bench;
+mod codec;
+mod ioctl;
+mod journal;
+mod metrics;
+mod nbt;
 
-fn main() {
+fn main() -> ExitCode {
     env_logger::Builder::from_env(
         env_logger::Env::default()
         .default_filter_or("info")
@@ -37,13 +48,45 @@ fn main() {
 
     let args: cli::Cli = Parser::parse();
 
-    match args.command {
+    let result = match args.command {
         cli::Command::Mount(args) => run_mount(args),
+        cli::Command::List(args) => run_list(args),
+        cli::Command::CopyChunk(args) => run_copy_chunk(args),
+        cli::Command::Format(args) => run_format(args),
+        cli::Command::Info(args) => run_info(args),
+        cli::Command::Explode(args) => run_explode(args),
+        cli::Command::Implode(args) => run_implode(args),
+        cli::Command::Merge(args) => run_merge(args),
+        cli::Command::Delta(args) => run_delta(args),
+        cli::Command::Extract(args) => run_extract(args),
+        cli::Command::DumpHeader(args) => run_dump_header(args),
+        cli::Command::DedupReport(args) => run_dedup_report(args),
+        cli::Command::Verify(args) => run_verify(args),
+        cli::Command::Recover(args) => run_recover(args),
+        cli::Command::Bench(args) => run_bench(args),
         cli::Command::Completion(args) => run_completion(args),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        // output piped into something like `head`, which has seen all it wants
+        Err(SmithyError::Output(e)) if e.kind() == ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::from(e.exit_status() as u8)
+        }
     }
 }
 
-fn run_mount(args: cli::MountCmd) {
+/// Setting this to anything but `0` has the same effect as `--i-have-a-backup`, for scripts
+const BACKUP_ENV: &str = "SMITHY_I_HAVE_A_BACKUP";
+
+/// Whether the user has confirmed that the region being mounted writable is backed up
+fn backup_acknowledged(flag: bool) -> bool {
+    flag || std::env::var_os(BACKUP_ENV).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+fn run_mount(mut args: cli::MountCmd) -> Result<(), SmithyError> {
     let mut options = vec![
         MountOption::NoAtime,
         MountOption::NoSuid,
@@ -53,69 +96,1009 @@ fn run_mount(args: cli::MountCmd) {
         MountOption::FSName("smithy".to_string())
     ];
 
-    if args.writable {
-        options.push(MountOption::RW);
-    } else {
+    // A kernel-level read-only mount would also refuse writes to `.smithy-writable`, so only
+    // archives and windows into larger files (which can never become writable) are mounted that
+    // way. Smithy itself refuses writes while read-only.
+    let fname = &args.region_file.fname;
+    let archive = Archive::from_fname(fname).is_some();
+    let window = args.offset.is_some() || args.length.is_some();
+    if archive || window {
         options.push(MountOption::RO);
+    } else {
+        options.push(MountOption::RW);
     }
 
     if args.auto_unmount {
         options.push(MountOption::AutoUnmount);
     }
 
-    let file = GuardedFile::new(&args.region_file.fname, args.writable).expect("Failed to find source file");
-    let data = {
-        let mut data = vec![];
-        let read = file.get().read_to_end(&mut data).expect("Failed to read source file");
-        debug!("Read {} bytes", read);
-        data
+    for option in args.options {
+        match option {
+            cli::FuseOption::Set(option) => {
+                options.retain(|existing| !replaces_option(&option, existing));
+                options.push(option);
+            }
+            cli::FuseOption::Unset(option) => options.retain(|existing| *existing != option)
+        }
+    }
+    debug!("Mount options: {:?}", options);
+
+    if args.writable && archive {
+        return Err(SmithyError::ArchiveReadOnly(fname.clone()));
+    }
+    if args.writable && window {
+        return Err(SmithyError::WindowReadOnly(fname.clone()));
+    }
+    if args.writable && !args.dry_run && !backup_acknowledged(args.i_have_a_backup) {
+        warn!(
+            "Mounting `{}` read-only: changes are written to it in place, so pass --i-have-a-backup (or set {}=1) along with -w once it is backed up",
+            fname, BACKUP_ENV
+        );
+        args.writable = false;
+    }
+
+    let retry_delay = Duration::from_millis(args.open_retry_delay);
+    // a dry run never writes, so it needn't keep others from writing either
+    let mut file = GuardedFile::new_retrying(fname, args.writable && !args.dry_run, args.open_retries, retry_delay)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    file.set_network(args.network_fs);
+    check_mount_point(&args.mount_point, fname, args.force_mount)?;
+    let data = if window {
+        read_window(fname, &file, args.offset.unwrap_or(0), args.length)?
+    } else {
+        read_region(fname, &file)?
+    };
+    let mut region = parse_region(fname, data, args.lenient, args.tolerant, args.strict)?;
+    region.set_profile_alloc(args.profile_alloc);
+    let future_margin = Duration::from_secs(args.future_margin);
+    let future = region.future_chunks(future_margin);
+    if !future.is_empty() {
+        if args.fix_future_mtime {
+            region.clamp_future_mtimes(future_margin);
+            warn!("Reset the timestamps of {} chunks that were in the future:{}", future.len(), chunk_list(future));
+        } else {
+            warn!(
+                "{} chunks have timestamps in the future, which may confuse backups (use --fix-future-mtime to reset them):{}",
+                future.len(), chunk_list(future)
+            );
+        }
+    }
+    let chunk_template = match &args.chunk_template {
+        Some(path) => read_chunk_template(path)?,
+        None => nbt::empty_chunk(nbt::DEFAULT_DATA_VERSION),
     };
-    let region = RegionFile::new(data);
 
     let uid = unsafe { geteuid() };
     let gid = unsafe { getegid() };
 
-    info!("Exposing {} via FUSE at {}", args.region_file.fname, args.mount_point);
+    info!("Exposing {} via FUSE at {}", fname, args.mount_point);
 
-    let fs = SmithyFS::new(region, uid, gid, args.writable, file);
-    let notif_mutex = Arc::clone(&fs.notifier);
+    let mut symlinks = vec![];
+    if args.latest_link {
+        symlinks.push(("latest.nbt".to_owned(), LinkTarget::Latest));
+    }
+    for alias in args.alias {
+        if symlinks.iter().any(|(name, _)| *name == alias.name) {
+            warn!("Ignoring duplicate symlink `{}`", alias.name);
+            continue;
+        }
+        symlinks.push((alias.name, LinkTarget::Chunk { x: alias.x, z: alias.z }));
+    }
 
-    let mut session = match fuser::Session::new(fs, args.mount_point, &options) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to create FUSE session: {}", e);
-            return;
+    if args.expert {
+        warn!("Expert mode enabled: writing to .smithy-header rewrites the region's chunk table directly");
+    }
+
+    let view = if args.raw {
+        ChunkView::Stored
+    } else if args.standalone_nbt {
+        ChunkView::Standalone
+    } else {
+        ChunkView::Nbt
+    };
+
+    let journal = match &args.journal {
+        Some(path) => {
+            let (journal, pending) = RegionState::read(Path::new(fname), file.get())
+                .and_then(|state| Journal::open(Path::new(path), view, state, args.force_journal))
+                .map_err(|e| SmithyError::Journal(path.clone(), e))?;
+            if !pending.is_empty() {
+                info!("Journal `{}` holds {} edits that were never saved", path, pending.len());
+            }
+            Some((journal, pending))
         }
+        None => None,
+    };
+
+    let fs_options = FsOptions {
+        writable: args.writable,
+        region_pos: (args.region_file.x, args.region_file.z),
+        max_read: args.max_read,
+        symlinks,
+        expert: args.expert,
+        progress: args.progress,
+        hidden_cmp: args.hidden_cmp,
+        dir_order: args.sort,
+        trim: args.trim,
+        verify_writeback: args.verify_writeback,
+        stable_inos: args.stable_inos,
+        discard_on_exit: args.discard_on_exit,
+        dry_run: args.dry_run,
+        permissive_read: args.permissive_read,
+        view,
+        compression_level: args.compression_level,
+        warn_full: args.warn_full,
+        full_write_threshold: args.full_write_threshold,
+        chunk_template,
+        metrics: args.metrics_file.map(|path| Metrics::new(path.into())),
+        on_save: args.on_save,
+        io_timeout: args.io_timeout.map(Duration::from_secs),
+        journal,
+        read_only_source: archive || window,
+        backup_acknowledged: backup_acknowledged(args.i_have_a_backup),
     };
 
+    let fs = SmithyFS::new(region, uid, gid, fs_options, file);
+    let notif_mutex = Arc::clone(&fs.notifier);
+
+    let mut session = fuser::Session::new(fs, &args.mount_point, &options)
+        .map_err(|e| SmithyError::Mount(args.mount_point.clone(), e))?;
+
     let notifier = session.notifier();
 
     {
-        notif_mutex.lock().unwrap().replace(notifier);
+        notif_mutex.lock().unwrap_or_else(PoisonError::into_inner).replace(Box::new(notifier));
     }
 
-    session.run().unwrap();
+    session.run().map_err(SmithyError::Session)?;
 
     drop(session);
 
     info!("Unmounted cleanly");
+
+    Ok(())
+}
+
+/// Whether `new` should take the place of `old`, because it repeats or contradicts it
+fn replaces_option(new: &MountOption, old: &MountOption) -> bool {
+    use MountOption::*;
+
+    if let (CUSTOM(new), CUSTOM(old)) = (new, old) {
+        return new == old;
+    }
+
+    std::mem::discriminant(new) == std::mem::discriminant(old) || matches!(
+        (new, old),
+        (Dev, NoDev) | (NoDev, Dev)
+        | (Suid, NoSuid) | (NoSuid, Suid)
+        | (Exec, NoExec) | (NoExec, Exec)
+        | (Atime, NoAtime) | (NoAtime, Atime)
+        | (Sync, Async) | (Async, Sync)
+    )
+}
+
+fn run_list(args: cli::ListCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let region = load_region(fname, &file, false)?;
+
+    let (offset_x, offset_z) = if args.global {
+        (args.region_file.x * 32, args.region_file.z * 32)
+    } else {
+        (0, 0)
+    };
+
+    let chunks: Box<dyn Iterator<Item = (u8, u8)>> = match args.since {
+        Some(cli::Timestamp(since)) => Box::new(region.modified_since(since)),
+        None => Box::new(region.present_chunks()),
+    };
+
+    let mut out = std::io::stdout().lock();
+    for (x, z) in chunks {
+        writeln!(out, "{} {}", offset_x + x as isize, offset_z + z as isize)
+            .map_err(SmithyError::Output)?;
+    }
+
+    Ok(())
+}
+
+fn run_format(args: cli::FormatCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    if Archive::from_fname(fname).is_some() {
+        return Err(SmithyError::ArchiveReadOnly(fname.clone()));
+    }
+
+    let mut file = match GuardedFile::create(fname) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            if !args.force {
+                return Err(SmithyError::RegionExists(fname.clone()));
+            }
+
+            warn!("Emptying existing region file {}", fname);
+            GuardedFile::new(fname, true)
+                .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?
+        }
+        Err(e) => return Err(SmithyError::OpenRegion(fname.clone(), e))
+    };
+
+    // a full write of an empty region also truncates away any old sectors
+    let (_, file) = file.get_mut();
+    RegionFile::empty().write_out(true, file)
+        .map_err(|e| SmithyError::WriteRegion(fname.clone(), e))?;
+
+    info!("Created empty region file {}", fname);
+
+    Ok(())
+}
+
+fn run_info(args: cli::InfoCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let region = load_region(fname, &file, false)?;
+
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "chunks: {}", region.present_chunks().count())
+        .and_then(|()| writeln!(out, "pending_deletions:{}", chunk_list(region.pending_deletions())))
+        .and_then(|()| writeln!(out, "load_warnings:{}", warning_list(region.load_warnings())))
+        .map_err(SmithyError::Output)?;
+
+    if let Some(cli::Timestamp(since)) = args.since {
+        writeln!(out, "modified_since:{}", chunk_list(region.modified_since(since)))
+            .map_err(SmithyError::Output)?;
+    }
+
+    Ok(())
+}
+
+fn run_copy_chunk(args: cli::CopyChunkCmd) -> Result<(), SmithyError> {
+    let src = &args.src_region.fname;
+    let src_file = GuardedFile::new(src, false)
+        .map_err(|e| SmithyError::OpenRegion(src.clone(), e))?;
+
+    // only archives have to be loaded whole to get at a single chunk
+    let cli::ChunkName { x: src_x, z: src_z } = args.src_chunk;
+    let chunk = if Archive::from_fname(src).is_some() {
+        let src_region = load_region(src, &src_file, false)?;
+        src_region.lookup_chunk(src_x, src_z).map(|chunk| anvil::OwnedChunk {
+            mtime: chunk.mtime,
+            compression_type: chunk.compression_type,
+            data: chunk.data.to_vec()
+        })
+    } else {
+        anvil::read_chunk(src_file.get(), src_x, src_z)
+            .map_err(|e| SmithyError::ReadRegion(src.clone(), e))?
+    };
+    let chunk = chunk.ok_or_else(|| SmithyError::MissingChunk { path: src.clone(), x: src_x, z: src_z })?;
+
+    let dst = &args.dst_region.fname;
+    if Archive::from_fname(dst).is_some() {
+        return Err(SmithyError::ArchiveReadOnly(dst.clone()));
+    }
+
+    let (mut dst_file, mut dst_region, created) = match GuardedFile::new(dst, true) {
+        Ok(file) => {
+            let region = load_region(dst, &file, false)?;
+            (file, region, false)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let file = GuardedFile::create(dst)
+                .map_err(|e| SmithyError::OpenRegion(dst.clone(), e))?;
+            info!("Creating new region file {}", dst);
+            (file, RegionFile::empty(), true)
+        }
+        Err(e) => return Err(SmithyError::OpenRegion(dst.clone(), e))
+    };
+
+    let cli::ChunkName { x: dst_x, z: dst_z } = args.dst_chunk;
+    if !args.force && dst_region.lookup_chunk(dst_x, dst_z).is_some() {
+        return Err(SmithyError::ChunkExists { path: dst.clone(), x: dst_x, z: dst_z });
+    }
+
+    dst_region.write_chunk(dst_x, dst_z, &chunk.data, chunk.compression_type, chunk.mtime);
+
+    // write_chunk only warns when it has to drop a chunk
+    if dst_region.lookup_chunk(dst_x, dst_z).is_none() {
+        return Err(SmithyError::ChunkNotWritten { path: dst.clone(), x: dst_x, z: dst_z });
+    }
+
+    let (changed, file) = dst_file.get_mut();
+    dst_region.write_out(changed || created, file)
+        .map_err(|e| SmithyError::WriteRegion(dst.clone(), e))?;
+
+    info!("Copied chunk [{} {}] of {} to chunk [{} {}] of {}", src_x, src_z, src, dst_x, dst_z, dst);
+
+    Ok(())
+}
+
+fn run_explode(args: cli::ExplodeCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let region = load_region(fname, &file, false)?;
+
+    let out_dir = Path::new(&args.out_dir);
+    let dir_error = |e| SmithyError::ChunkDir(args.out_dir.clone(), e);
+    prepare_out_dir(out_dir, args.force).map_err(dir_error)?;
+
+    let mut count = 0;
+    for (x, z) in region.present_chunks() {
+        let chunk = region.lookup_chunk(x, z).expect("present chunks can be looked up");
+        write_chunk_files(out_dir, &chunk).map_err(dir_error)?;
+        count += 1;
+    }
+
+    info!("Wrote {} chunks of {} to {}", count, fname, args.out_dir);
+
+    Ok(())
+}
+
+/// Create a directory for explode-style output, refusing one with files in it unless `force`
+fn prepare_out_dir(out_dir: &Path, force: bool) -> io::Result<()> {
+    // leftovers from an older explode would come back to life on implode
+    if !force && out_dir.read_dir().is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(io::Error::from(ErrorKind::DirectoryNotEmpty));
+    }
+    fs::create_dir_all(out_dir)
+}
+
+/// Write a chunk's `.nbt` and `.cmp` files to `out_dir`, as explode does
+fn write_chunk_files(out_dir: &Path, chunk: &anvil::Chunk) -> io::Result<()> {
+    let (chunk_name, cmp_name) = chunk_file_names(chunk.x, chunk.z);
+
+    // the cmp first, so that a chunk file never exists without one
+    fs::write(out_dir.join(cmp_name), chunk.compression_type.make_selector_string())?;
+    let mut file = File::create(out_dir.join(chunk_name))?;
+    file.write_all(chunk.data)?;
+    file.set_modified(chunk.mtime)
+}
+
+/// Region-local coordinates of the chunks listed in a coords file, one `x z` pair per line.
+/// Blank lines and lines starting with `#` are skipped.
+fn read_coords_file(path: &str, region_file: &cli::ExtendedFilename, global: bool) -> Result<Vec<(u8, u8)>, SmithyError> {
+    let text = fs::read_to_string(path).map_err(|e| SmithyError::CoordsFile(path.to_owned(), e))?;
+    let (offset_x, offset_z) = if global {
+        (region_file.x * 32, region_file.z * 32)
+    } else {
+        (0, 0)
+    };
+
+    let mut coords = vec![];
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || SmithyError::InvalidCoords { path: path.to_owned(), line: line_idx + 1 };
+        let mut fields = line.split_whitespace().map(|field| field.parse::<isize>());
+        let (Some(Ok(x)), Some(Ok(z)), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid());
+        };
+
+        let local = |pos: isize, offset: isize| u8::try_from(pos - offset).ok().filter(|&pos| pos < 32);
+        let (Some(x), Some(z)) = (local(x, offset_x), local(z, offset_z)) else {
+            return Err(invalid());
+        };
+        coords.push((x, z));
+    }
+
+    Ok(coords)
+}
+
+fn run_extract(args: cli::ExtractCmd) -> Result<(), SmithyError> {
+    let mut wanted: Vec<_> = args.chunks.iter().map(|chunk| (chunk.x, chunk.z)).collect();
+    if let Some(path) = &args.coords_file {
+        wanted.extend(read_coords_file(path, &args.region_file, args.global)?);
+    }
+
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let region = load_region(fname, &file, false)?;
+
+    // `-` pipes the decompressed NBT into other tools instead
+    let to_stdout = args.out_dir == "-";
+    let out_dir = Path::new(&args.out_dir);
+    let dir_error = |e| SmithyError::ChunkDir(args.out_dir.clone(), e);
+    if !to_stdout {
+        prepare_out_dir(out_dir, args.force).map_err(dir_error)?;
+    }
+    let mut stdout = std::io::stdout().lock();
+
+    let mut extracted = bitvec::bitarr![0; 32 * 32];
+    let mut missing = vec![];
+    for (x, z) in wanted {
+        let idx = anvil::coords_to_idx(x, z);
+        if extracted[idx] || missing.contains(&(x, z)) {
+            continue;
+        }
+
+        let Some(chunk) = region.lookup_chunk(x, z) else {
+            missing.push((x, z));
+            continue;
+        };
+
+        if to_stdout {
+            let nbt = codec::decompress(chunk.compression_type, chunk.data)
+                .map_err(|e| SmithyError::ChunkUnreadable { path: fname.clone(), x, z, error: e })?;
+            match stdout.write_all(&nbt) {
+                // whoever reads it has seen enough
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+                result => result.map_err(SmithyError::Output)?,
+            }
+        } else {
+            write_chunk_files(out_dir, &chunk).map_err(dir_error)?;
+        }
+        extracted.set(idx, true);
+    }
+
+    match stdout.flush() {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+        result => result.map_err(SmithyError::Output)?,
+    }
+
+    if !missing.is_empty() {
+        warn!("{} requested chunks are not present in {}:{}", missing.len(), fname, chunk_list(missing));
+    }
+
+    let destination = if to_stdout { "standard output" } else { &args.out_dir };
+    info!("Wrote {} chunks of {} to {}", extracted.count_ones(), fname, destination);
+
+    Ok(())
+}
+
+fn run_delta(args: cli::DeltaCmd) -> Result<(), SmithyError> {
+    let base_fname = &args.base_file.fname;
+    let base_file = GuardedFile::new(base_fname, false)
+        .map_err(|e| SmithyError::OpenRegion(base_fname.clone(), e))?;
+    let base = load_region(base_fname, &base_file, false)?;
+
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let region = load_region(fname, &file, false)?;
+
+    let out_dir = Path::new(&args.out_dir);
+    let dir_error = |e| SmithyError::ChunkDir(args.out_dir.clone(), e);
+    prepare_out_dir(out_dir, args.force).map_err(dir_error)?;
+
+    let mut count = 0;
+    for (x, z) in region.present_chunks() {
+        let chunk = region.lookup_chunk(x, z).expect("present chunks can be looked up");
+        let unchanged = base.lookup_chunk(x, z).is_some_and(|old| {
+            old.compression_type == chunk.compression_type && old.mtime == chunk.mtime && old.data == chunk.data
+        });
+        if unchanged {
+            continue;
+        }
+
+        write_chunk_files(out_dir, &chunk).map_err(dir_error)?;
+        count += 1;
+    }
+
+    // a directory of chunk files has no way to say that a chunk is gone
+    let removed: Vec<_> = base.present_chunks().filter(|&(x, z)| region.lookup_chunk(x, z).is_none()).collect();
+    if !removed.is_empty() {
+        warn!("{} chunks of {} were deleted since {}, which the delta can't record:{}", removed.len(), fname, base_fname, chunk_list(removed));
+    }
+
+    info!("Wrote {} chunks of {} that differ from {} to {}", count, fname, base_fname, args.out_dir);
+
+    Ok(())
+}
+
+fn run_implode(args: cli::ImplodeCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    if Archive::from_fname(fname).is_some() {
+        return Err(SmithyError::ArchiveReadOnly(fname.clone()));
+    }
+
+    let in_dir = Path::new(&args.in_dir);
+    let dir_error = |e| SmithyError::ChunkDir(args.in_dir.clone(), e);
+    if !in_dir.is_dir() {
+        return Err(dir_error(io::Error::from(ErrorKind::NotADirectory)));
+    }
+
+    // build the whole region before touching the output, so a bad chunk leaves it alone
+    let mut region = RegionFile::empty();
+    let mut count = 0;
+    for z in 0..32 {
+        for x in 0..32 {
+            let (chunk_name, cmp_name) = chunk_file_names(x, z);
+            let chunk_path = in_dir.join(&chunk_name);
+
+            let (data, mtime) = match fs::read(&chunk_path) {
+                Ok(data) => {
+                    let mtime = fs::metadata(&chunk_path).and_then(|meta| meta.modified()).map_err(dir_error)?;
+                    (data, mtime)
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(dir_error(e))
+            };
+
+            let cmp_path = in_dir.join(&cmp_name);
+            let selector = fs::read_to_string(&cmp_path).map_err(dir_error)?;
+            let compression_type = CompressionType::parse_selector_string(&selector)
+                .ok_or_else(|| SmithyError::InvalidCompression(cmp_path.display().to_string()))?;
+
+            region.write_chunk(x, z, &data, compression_type, mtime);
+            if region.lookup_chunk(x, z).is_none() {
+                return Err(SmithyError::ChunkNotWritten { path: fname.clone(), x, z });
+            }
+            count += 1;
+        }
+    }
+
+    write_new_region(fname, &mut region, args.force)?;
+
+    info!("Built {} from {} chunks in {}", fname, count, args.in_dir);
+
+    Ok(())
+}
+
+/// Write `region` to a new file, replacing an existing one only if `force` is set
+fn write_new_region(fname: &str, region: &mut RegionFile, force: bool) -> Result<(), SmithyError> {
+    let mut file = match GuardedFile::create(fname) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists && force => {
+            warn!("Replacing existing region file {}", fname);
+            GuardedFile::new(fname, true)
+                .map_err(|e| SmithyError::OpenRegion(fname.to_owned(), e))?
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(SmithyError::RegionExists(fname.to_owned())),
+        Err(e) => return Err(SmithyError::OpenRegion(fname.to_owned(), e))
+    };
+
+    let (_, file) = file.get_mut();
+    region.write_out(true, file)
+        .map_err(|e| SmithyError::WriteRegion(fname.to_owned(), e))
+}
+
+fn run_merge(args: cli::MergeCmd) -> Result<(), SmithyError> {
+    // regions are grouped by their coordinates, in the order they were given
+    let mut groups: Vec<((isize, isize), Vec<&str>)> = vec![];
+    for region_file in &args.regions {
+        let coords = (region_file.x, region_file.z);
+        match groups.iter_mut().find(|(group_coords, _)| *group_coords == coords) {
+            Some((_, fnames)) => fnames.push(&region_file.fname),
+            None => groups.push((coords, vec![&region_file.fname])),
+        }
+    }
+
+    // refuse before writing anything
+    if !args.newest && let Some(((x, z), fnames)) = groups.iter().find(|(_, fnames)| fnames.len() > 1) {
+        return Err(SmithyError::RegionConflict { x: *x, z: *z, paths: fnames.iter().map(|&fname| fname.to_owned()).collect() });
+    }
+
+    let out_dir = Path::new(&args.out_dir);
+    fs::create_dir_all(out_dir).map_err(|e| SmithyError::ChunkDir(args.out_dir.clone(), e))?;
+
+    for ((x, z), fnames) in groups {
+        let mut sources = Vec::with_capacity(fnames.len());
+        for fname in &fnames {
+            let file = GuardedFile::new(fname, false)
+                .map_err(|e| SmithyError::OpenRegion((*fname).to_owned(), e))?;
+            sources.push(load_region(fname, &file, false)?);
+        }
+
+        let out = out_dir.join(format!("r.{}.{}.mca", x, z)).display().to_string();
+        let mut region = RegionFile::empty();
+        let mut count = 0;
+
+        for idx in 0..(32 * 32) {
+            let (chunk_x, chunk_z) = anvil::idx_to_coords(idx);
+
+            // the first region given wins ties
+            let newest = sources.iter()
+                .filter_map(|source| source.lookup_chunk(chunk_x, chunk_z))
+                .reduce(|newest, chunk| if chunk.mtime > newest.mtime { chunk } else { newest });
+            let Some(chunk) = newest else { continue };
+
+            region.write_chunk(chunk_x, chunk_z, chunk.data, chunk.compression_type, chunk.mtime);
+            if region.lookup_chunk(chunk_x, chunk_z).is_none() {
+                return Err(SmithyError::ChunkNotWritten { path: out, x: chunk_x, z: chunk_z });
+            }
+            count += 1;
+        }
+
+        write_new_region(&out, &mut region, args.force)?;
+        info!("Wrote {} with {} chunks from {}", out, count, fnames.join(", "));
+    }
+
+    Ok(())
+}
+
+fn run_dump_header(args: cli::DumpHeaderCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+
+    // archives have to be decompressed whole anyway, plain files only need their header read
+    let (header, file_len) = if Archive::from_fname(fname).is_some() {
+        let data = read_region(fname, &file)?;
+        let len = data.len() as u64;
+        (data, len)
+    } else {
+        let mut header = vec![];
+        file.get().take(HEADER_LEN as u64).read_to_end(&mut header)
+            .and_then(|_| Ok((header, file.get().metadata()?.len())))
+            .map_err(|e| SmithyError::ReadRegion(fname.clone(), e))?
+    };
+
+    if header.len() < HEADER_LEN {
+        return Err(SmithyError::TruncatedRegion(fname.clone(), header.len()));
+    }
+
+    let mut out = std::io::stdout().lock();
+    if !args.json {
+        writeln!(out, "{:>2} {:>2} {:>8} {:>7} {:>10} status", "x", "z", "offset", "sectors", "timestamp")
+            .map_err(SmithyError::Output)?;
+    }
+
+    for entry in anvil::raw_header(&header) {
+        let status = entry.status(file_len);
+        let result = if args.json {
+            writeln!(
+                out,
+                r#"{{"x":{},"z":{},"offset":{},"sectors":{},"timestamp":{},"status":"{}"}}"#,
+                entry.x, entry.z, entry.offset, entry.sectors, entry.mtime, status
+            )
+        } else {
+            writeln!(
+                out,
+                "{:>2} {:>2} {:>8} {:>7} {:>10} {}",
+                entry.x, entry.z, entry.offset, entry.sectors, entry.mtime, status
+            )
+        };
+        result.map_err(SmithyError::Output)?;
+    }
+
+    Ok(())
+}
+
+fn run_dedup_report(args: cli::DedupReportCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let region = load_region(fname, &file, false)?;
+
+    // chunks are grouped by a hash of their decompressed contents, so identical NBT stored with
+    // different codecs still counts as a duplicate
+    let mut groups: HashMap<u64, Vec<(u8, u8, usize)>> = HashMap::new();
+
+    for (x, z) in region.present_chunks() {
+        let Some(chunk) = region.lookup_chunk(x, z) else { continue };
+
+        let mut hasher = DefaultHasher::new();
+        match codec::decompress(chunk.compression_type, chunk.data) {
+            Ok(data) => data.hash(&mut hasher),
+            Err(e) => {
+                warn!("Comparing chunk [{} {}] by its stored bytes: {}", x, z, e);
+                chunk.compression_type.name().hash(&mut hasher);
+                chunk.data.hash(&mut hasher);
+            }
+        }
+
+        // sectors the chunk occupies, including its 5-byte length and compression prefix
+        let stored_len = (chunk.data.len() + 5).div_ceil(SECTOR_LEN) * SECTOR_LEN;
+        groups.entry(hasher.finish()).or_default().push((x, z, stored_len));
+    }
+
+    let mut duplicates: Vec<_> = groups.into_values().filter(|group| group.len() > 1).collect();
+    duplicates.sort_unstable_by_key(|group| (group[0].1, group[0].0));
+
+    let mut out = std::io::stdout().lock();
+    let (mut total_chunks, mut total_savings) = (0, 0);
+
+    for group in &duplicates {
+        let names: Vec<String> = group.iter().map(|(x, z, _)| format!("x{}z{}", x, z)).collect();
+        // keeping one copy, the rest could be shared
+        let savings: usize = group.iter().skip(1).map(|(_, _, len)| len).sum();
+
+        total_chunks += group.len() - 1;
+        total_savings += savings;
+
+        writeln!(out, "{} ({} identical, {} bytes reclaimable)", names.join(" "), group.len(), savings)
+            .map_err(SmithyError::Output)?;
+    }
+
+    writeln!(
+        out,
+        "total: {} duplicate chunks in {} groups, {} bytes reclaimable",
+        total_chunks, duplicates.len(), total_savings
+    ).map_err(SmithyError::Output)?;
+
+    Ok(())
+}
+
+fn run_verify(args: cli::VerifyCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let region = load_region(fname, &file, false)?;
+
+    let mut out = std::io::stdout().lock();
+    let mut problems = 0;
+    let mut report = |x: u8, z: u8, problem: String| {
+        problems += 1;
+        writeln!(out, "x{}z{}: {}", x, z, problem).map_err(SmithyError::Output)
+    };
+
+    for (x, z) in region.future_chunks(Duration::from_secs(args.future_margin)) {
+        let chunk = region.lookup_chunk(x, z).expect("present chunks can be looked up");
+        let ahead = chunk.mtime.duration_since(SystemTime::now()).unwrap_or_default();
+        report(x, z, format!("was modified {} seconds in the future", ahead.as_secs()))?;
+    }
+
+    // (x, z, block entities, entities)
+    let mut stats = vec![];
+
+    for (x, z) in region.present_chunks() {
+        let chunk = region.lookup_chunk(x, z).expect("present chunks can be looked up");
+
+        let nbt = match codec::decompress(chunk.compression_type, chunk.data) {
+            Ok(nbt) => nbt,
+            Err(e) => {
+                report(x, z, format!("can't be decompressed: {}", e))?;
+                continue;
+            }
+        };
+
+        if args.stats {
+            stats.push((x, z, nbt::block_entity_count(&nbt), nbt::entity_count(&nbt)));
+        }
+
+        if args.coords {
+            let expected = (args.region_file.x * 32 + x as isize, args.region_file.z * 32 + z as isize);
+            match nbt::chunk_pos(&nbt) {
+                Some((chunk_x, chunk_z)) if (chunk_x as isize, chunk_z as isize) == expected => {}
+                Some((chunk_x, chunk_z)) => report(x, z, format!(
+                    "stores position {} {}, but its slot is chunk {} {}", chunk_x, chunk_z, expected.0, expected.1
+                ))?,
+                None => report(x, z, "has no position".to_owned())?,
+            }
+        }
+    }
+
+    if args.stats {
+        writeln!(out, "{:>2} {:>2} {:>14} {:>8}", "x", "z", "block_entities", "entities").map_err(SmithyError::Output)?;
+        for &(x, z, block_entities, entities) in &stats {
+            writeln!(out, "{:>2} {:>2} {:>14} {:>8}", x, z, block_entities, entities).map_err(SmithyError::Output)?;
+        }
+
+        let crowded: Vec<_> = stats.iter()
+            .filter(|&&(_, _, block_entities, entities)| block_entities.max(entities) > args.entity_limit)
+            .map(|&(x, z, _, _)| (x, z))
+            .collect();
+        if !crowded.is_empty() {
+            warn!("{} chunks hold more than {} block entities or entities, which may cause lag:{}", crowded.len(), args.entity_limit, chunk_list(crowded));
+        }
+    }
+
+    if problems > 0 {
+        return Err(SmithyError::VerifyFailed(fname.clone(), problems));
+    }
+
+    info!("Verified {} chunks of {}", region.present_chunks().count(), fname);
+
+    Ok(())
+}
+
+fn run_recover(args: cli::RecoverCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let data = read_region(fname, &file)?;
+
+    let out = &args.out_file.fname;
+    if Archive::from_fname(out).is_some() {
+        return Err(SmithyError::ArchiveReadOnly(out.clone()));
+    }
+    let mut out_file = GuardedFile::create(out)
+        .map_err(|e| SmithyError::OpenRegion(out.clone(), e))?;
+
+    let mut region = RegionFile::empty();
+    let mut recovered = 0;
+    // sectors before this belong to a chunk that was already recovered
+    let mut next_sector = 0;
+    let now = SystemTime::now();
+
+    for chunk in anvil::scan_chunks(&data) {
+        if chunk.sector < next_sector {
+            continue;
+        }
+
+        // data that doesn't decompress was a false positive, unless we just can't decode it
+        let nbt = match codec::decompress(chunk.compression_type, chunk.data) {
+            Ok(nbt) => nbt,
+            Err(e) if e.kind() == ErrorKind::Unsupported => {
+                warn!("Skipping chunk at sector {}, its position can't be read: {}", chunk.sector, e);
+                continue;
+            }
+            Err(_) => continue
+        };
+        next_sector = chunk.sector + chunk.sectors;
+
+        let Some((chunk_x, chunk_z)) = nbt::chunk_pos(&nbt) else {
+            warn!("Skipping chunk at sector {}, it has no position", chunk.sector);
+            continue;
+        };
+
+        let (x, z) = (chunk_x.rem_euclid(32) as u8, chunk_z.rem_euclid(32) as u8);
+
+        if (chunk_x.div_euclid(32) as isize, chunk_z.div_euclid(32) as isize) != (args.region_file.x, args.region_file.z) {
+            warn!("Chunk at sector {} belongs to another region ({} {}), recovering it as [{} {}]", chunk.sector, chunk_x, chunk_z, x, z);
+        }
+
+        if region.lookup_chunk(x, z).is_some() {
+            warn!("Chunk [{} {}] found again at sector {}, keeping the first copy", x, z, chunk.sector);
+            continue;
+        }
+
+        region.write_chunk(x, z, chunk.data, chunk.compression_type, now);
+        info!("Recovered chunk [{} {}] from sector {}", x, z, chunk.sector);
+        recovered += 1;
+    }
+
+    let (_, file) = out_file.get_mut();
+    region.write_out(true, file)
+        .map_err(|e| SmithyError::WriteRegion(out.clone(), e))?;
+
+    info!("Recovered {} chunks into {}", recovered, out);
+
+    Ok(())
+}
+
+fn run_bench(args: cli::BenchCmd) -> Result<(), SmithyError> {
+    let fname = &args.region_file.fname;
+    let file = GuardedFile::new(fname, false)
+        .map_err(|e| SmithyError::OpenRegion(fname.clone(), e))?;
+    let region = load_region(fname, &file, false)?;
+
+    let workloads = if args.workload.is_empty() {
+        vec![cli::Workload::Sequential, cli::Workload::Random, cli::Workload::Rewrite]
+    } else {
+        args.workload
+    };
+
+    bench::run(&region, &workloads, args.iterations, args.seed, &mut std::io::stdout().lock())
+        .map_err(SmithyError::Output)
+}
+
+/// Read the raw contents of a region, decompressing archives
+fn read_region(fname: &str, file: &GuardedFile) -> Result<Vec<u8>, SmithyError> {
+    let data = {
+        let mut data = vec![];
+        let read = file.get().read_to_end(&mut data)
+            .map_err(|e| SmithyError::ReadRegion(fname.to_owned(), e))?;
+        debug!("Read {} bytes", read);
+        data
+    };
+
+    decompress_archive(fname, data)
 }
 
-fn run_completion(args: cli::CompletionCmd) {
+/// Read `length` bytes (or the rest of the file) from `offset` onwards, decompressing archives
+fn read_window(fname: &str, file: &GuardedFile, offset: u64, length: Option<u64>) -> Result<Vec<u8>, SmithyError> {
+    let mut source = file.get();
+    source.seek(SeekFrom::Start(offset))
+        .map_err(|e| SmithyError::ReadRegion(fname.to_owned(), e))?;
+
+    let mut data = vec![];
+    match length {
+        Some(length) => {
+            let read = source.take(length).read_to_end(&mut data)
+                .map_err(|e| SmithyError::ReadRegion(fname.to_owned(), e))?;
+
+            if (read as u64) < length {
+                let e = io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("the window of {} bytes at offset {} runs past the end of the file", length, offset)
+                );
+                return Err(SmithyError::ReadRegion(fname.to_owned(), e));
+            }
+        }
+        None => {
+            source.read_to_end(&mut data)
+                .map_err(|e| SmithyError::ReadRegion(fname.to_owned(), e))?;
+        }
+    }
+    debug!("Read {} bytes at offset {}", data.len(), offset);
+
+    decompress_archive(fname, data)
+}
+
+/// Decompress `data` if `fname` names an archive
+fn decompress_archive(fname: &str, data: Vec<u8>) -> Result<Vec<u8>, SmithyError> {
+    match Archive::from_fname(fname) {
+        Some(archive) => archive.decompress(&data)
+            .map_err(|e| SmithyError::ReadRegion(fname.to_owned(), e)),
+        None => Ok(data)
+    }
+}
+
+fn read_chunk_template(fname: &str) -> Result<Vec<u8>, SmithyError> {
+    let data = std::fs::read(fname).map_err(|e| SmithyError::ChunkTemplate(fname.to_owned(), e))?;
+
+    if data.len() >= MAX_CHUNK_LEN {
+        let e = io::Error::new(ErrorKind::InvalidData, format!("longer than the {} byte limit for chunks", MAX_CHUNK_LEN));
+        return Err(SmithyError::ChunkTemplate(fname.to_owned(), e));
+    }
+
+    Ok(data)
+}
+
+/// Refuse mount points that contain the region file, including its own directory, and unless
+/// `force`, mount points with files of their own, which the mount would hide
+fn check_mount_point(mount_point: &str, fname: &str, force: bool) -> Result<(), SmithyError> {
+    // a missing mount point fails later with a better error
+    let (Ok(mount_dir), Ok(region)) = (fs::canonicalize(mount_point), fs::canonicalize(fname)) else {
+        return Ok(());
+    };
+
+    if region.starts_with(&mount_dir) {
+        return Err(SmithyError::MountOverRegion { mount_point: mount_point.to_owned(), path: fname.to_owned() });
+    }
+
+    if fs::read_dir(&mount_dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        if !force {
+            return Err(SmithyError::MountPointNotEmpty(mount_point.to_owned()));
+        }
+        warn!("`{}` is not empty, its files are hidden until the region is unmounted", mount_point);
+    }
+
+    Ok(())
+}
+
+/// Load a region, see [`RegionFile::new_checked`] for `lenient`
+fn load_region(fname: &str, file: &GuardedFile, lenient: bool) -> Result<RegionFile, SmithyError> {
+    let data = read_region(fname, file)?;
+    parse_region(fname, data, lenient, false, false)
+}
+
+/// Parse the raw contents of a region read from `fname`. Minecraft only writes whole sectors,
+/// so a partial last sector is a sign of truncation: it is padded with zeroes, or with `strict`,
+/// rejected. See [`RegionFile::new_checked`] for `lenient` and `tolerant`.
+fn parse_region(fname: &str, data: Vec<u8>, lenient: bool, tolerant: bool, strict: bool) -> Result<RegionFile, SmithyError> {
+    if data.len() < HEADER_LEN {
+        return Err(SmithyError::TruncatedRegion(fname.to_owned(), data.len()));
+    }
+
+    let expected = data.len().next_multiple_of(SECTOR_LEN);
+    if expected != data.len() {
+        if strict {
+            return Err(SmithyError::MisalignedRegion { path: fname.to_owned(), expected, actual: data.len() });
+        }
+        warn!(
+            "`{}` is {} bytes long, not a whole number of sectors ({} bytes expected): it may have been truncated, the rest of its last sector is read as zeroes",
+            fname, data.len(), expected
+        );
+    }
+
+    RegionFile::new_checked(data, lenient, tolerant)
+        .map_err(|e| SmithyError::InvalidRegion(fname.to_owned(), e))
+}
+
+fn run_completion(args: cli::CompletionCmd) -> Result<(), SmithyError> {
     let bin_name = option_env!("CARGO_BIN_NAME").unwrap_or("smithy");
     let mut cmd = <cli::Cli as CommandFactory>::command();
 
     match args.out_dir {
         Some(out_dir) => {
-            match generate_to(args.shell, &mut cmd, bin_name, out_dir) {
-                Ok(path) => {
-                    info!("Wrote completions file to: {}", path.display());
-                }
-                Err(err) => {
-                    error!("Failed to write completions file: {}", err);
-                }
-            }
+            let path = generate_to(args.shell, &mut cmd, bin_name, out_dir)
+                .map_err(SmithyError::Output)?;
+            info!("Wrote completions file to: {}", path.display());
+        }
+        None => {
+            // clap_complete panics on write errors, so only hand it a buffer
+            let mut script = vec![];
+            generate(args.shell, &mut cmd, bin_name, &mut script);
+            std::io::stdout().write_all(&script).map_err(SmithyError::Output)?;
         }
-        None => generate(args.shell, &mut cmd, bin_name, &mut std::io::stdout()),
     };
+
+    Ok(())
 }
@@ -0,0 +1,142 @@
+/*
+* Smithy
+* Copyright (C) 2025  Sam Wagenaar
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+
+//! Counters for `--metrics-file`, written in the Prometheus text format so that node_exporter's
+//! textfile collector (or anything else) can pick them up
+
+use std::{fmt::Write, fs, path::{Path, PathBuf}, sync::{Arc, Mutex, PoisonError, Weak}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use log::warn;
+
+/// Time between two writes of the metrics file
+const INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Counters {
+    reads: u64,
+    writes: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    write_outs: u64,
+    write_out_errors: u64,
+    last_error: Option<SystemTime>,
+    dirty_chunks: usize,
+}
+impl Counters {
+    fn render(&self) -> String {
+        let last_error = self.last_error
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |dur| dur.as_secs());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |dur| dur.as_secs());
+
+        let metrics: [(&str, &str, &str, u64); 9] = [
+            ("smithy_reads_total", "counter", "Reads from chunk files", self.reads),
+            ("smithy_writes_total", "counter", "Writes to chunk files", self.writes),
+            ("smithy_read_bytes_total", "counter", "Bytes read from chunk files", self.bytes_read),
+            ("smithy_written_bytes_total", "counter", "Bytes written to chunk files", self.bytes_written),
+            ("smithy_write_outs_total", "counter", "Successful saves of the region file", self.write_outs),
+            ("smithy_write_out_errors_total", "counter", "Failed saves of the region file", self.write_out_errors),
+            ("smithy_dirty_chunks", "gauge", "Chunks with unsaved changes", self.dirty_chunks as u64),
+            ("smithy_last_write_out_error_timestamp_seconds", "gauge", "Time of the last failed save, 0 if none", last_error),
+            ("smithy_last_update_timestamp_seconds", "gauge", "Time this file was written", now),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+        }
+        out
+    }
+
+    /// Write the metrics file. It is replaced in one go, so readers never see half of it.
+    fn write(&self, path: &Path) {
+        let mut tmp_path = path.to_owned().into_os_string();
+        tmp_path.push(".tmp");
+
+        let result = fs::write(&tmp_path, self.render())
+            .and_then(|()| fs::rename(&tmp_path, path));
+
+        if let Err(e) = result {
+            warn!("Failed to write metrics to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The mount's counters. A background thread writes them out every [`INTERVAL`], whether or not
+/// the mount is in use, until this is dropped.
+pub(crate) struct Metrics {
+    path: PathBuf,
+    counters: Arc<Mutex<Counters>>,
+}
+impl Metrics {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        let counters = Arc::new(Mutex::new(Counters::default()));
+
+        let weak = Arc::downgrade(&counters);
+        let thread_path = path.clone();
+        thread::spawn(move || Self::write_periodically(&thread_path, weak));
+
+        Self { path, counters }
+    }
+
+    fn write_periodically(path: &Path, counters: Weak<Mutex<Counters>>) {
+        while let Some(counters) = counters.upgrade() {
+            counters.lock().unwrap_or_else(PoisonError::into_inner).write(path);
+            drop(counters);
+            thread::sleep(INTERVAL);
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Counters)) {
+        f(&mut self.counters.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    pub(crate) fn read(&self, bytes: usize) {
+        self.update(|c| {
+            c.reads += 1;
+            c.bytes_read += bytes as u64;
+        });
+    }
+
+    pub(crate) fn written(&self, bytes: usize) {
+        self.update(|c| {
+            c.writes += 1;
+            c.bytes_written += bytes as u64;
+        });
+    }
+
+    pub(crate) fn write_out(&self, ok: bool) {
+        self.update(|c| if ok {
+            c.write_outs += 1;
+        } else {
+            c.write_out_errors += 1;
+            c.last_error = Some(SystemTime::now());
+        });
+    }
+
+    pub(crate) fn set_dirty_chunks(&self, dirty_chunks: usize) {
+        self.update(|c| c.dirty_chunks = dirty_chunks);
+    }
+
+    /// Write the metrics file now, rather than waiting for the next interval
+    pub(crate) fn write(&self, dirty_chunks: usize) {
+        self.update(|c| {
+            c.dirty_chunks = dirty_chunks;
+            c.write(&self.path);
+        });
+    }
+}
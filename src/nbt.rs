@@ -0,0 +1,219 @@
+/*
+* Smithy
+* Copyright (C) 2025  Sam Wagenaar
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Just enough NBT to peek at a few root tags without parsing whole chunks
+
+const TAG_END: u8 = 0;
+const TAG_BYTE: u8 = 1;
+const TAG_SHORT: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_LONG: u8 = 4;
+const TAG_FLOAT: u8 = 5;
+const TAG_DOUBLE: u8 = 6;
+const TAG_BYTE_ARRAY: u8 = 7;
+const TAG_STRING: u8 = 8;
+const TAG_LIST: u8 = 9;
+const TAG_COMPOUND: u8 = 10;
+const TAG_INT_ARRAY: u8 = 11;
+const TAG_LONG_ARRAY: u8 = 12;
+
+/// Nesting limit, so that malicious data can't overflow the stack
+const MAX_DEPTH: usize = 512;
+
+struct Reader<'a> {
+    data: &'a [u8]
+}
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn name(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    /// Length prefix of an array or list, with each element taking `elem_len` bytes
+    fn array_len(&mut self, elem_len: usize) -> Option<usize> {
+        let len = usize::try_from(self.i32()?).ok()?;
+        len.checked_mul(elem_len)
+    }
+
+    /// Skip through the current compound until the tag called `name`, leaving its payload next.
+    /// Fails if the compound ends first or the tag has a different type.
+    fn find(&mut self, name: &[u8], tag: u8, depth: usize) -> Option<()> {
+        loop {
+            let found = self.u8()?;
+            if found == TAG_END {
+                return None;
+            }
+
+            if self.name()? == name {
+                return (found == tag).then_some(());
+            }
+
+            self.skip_payload(found, depth)?;
+        }
+    }
+
+    fn skip_payload(&mut self, tag: u8, depth: usize) -> Option<()> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        match tag {
+            TAG_BYTE => { self.take(1)?; }
+            TAG_SHORT => { self.take(2)?; }
+            TAG_INT | TAG_FLOAT => { self.take(4)?; }
+            TAG_LONG | TAG_DOUBLE => { self.take(8)?; }
+            TAG_BYTE_ARRAY => {
+                let len = self.array_len(1)?;
+                self.take(len)?;
+            }
+            TAG_STRING => { self.name()?; }
+            TAG_LIST => {
+                let elem_tag = self.u8()?;
+                let count = self.array_len(1)?;
+                for _ in 0..count {
+                    self.skip_payload(elem_tag, depth + 1)?;
+                }
+            }
+            TAG_COMPOUND => {
+                loop {
+                    let tag = self.u8()?;
+                    if tag == TAG_END {
+                        break;
+                    }
+                    self.name()?;
+                    self.skip_payload(tag, depth + 1)?;
+                }
+            }
+            TAG_INT_ARRAY => {
+                let len = self.array_len(4)?;
+                self.take(len)?;
+            }
+            TAG_LONG_ARRAY => {
+                let len = self.array_len(8)?;
+                self.take(len)?;
+            }
+            _ => return None
+        }
+
+        Some(())
+    }
+}
+
+/// DataVersion given to new chunks that don't come from a template (Minecraft 1.21.1)
+pub(crate) const DEFAULT_DATA_VERSION: i32 = 3955;
+
+/// Uncompressed NBT for a chunk holding nothing but a `DataVersion`
+pub(crate) fn empty_chunk(data_version: i32) -> Vec<u8> {
+    const NAME: &[u8] = b"DataVersion";
+
+    let mut data = vec![TAG_COMPOUND, 0, 0, TAG_INT];
+    data.extend_from_slice(&(NAME.len() as u16).to_be_bytes());
+    data.extend_from_slice(NAME);
+    data.extend_from_slice(&data_version.to_be_bytes());
+    data.push(TAG_END);
+    data
+}
+
+/// Whether `data` is exactly one well-formed NBT compound, as every chunk is
+pub(crate) fn is_compound(data: &[u8]) -> bool {
+    let mut reader = Reader { data };
+    let parsed = (|| {
+        if reader.u8()? != TAG_COMPOUND {
+            return None;
+        }
+        reader.name()?;
+        reader.skip_payload(TAG_COMPOUND, 0)
+    })();
+
+    parsed.is_some() && reader.data.is_empty()
+}
+
+/// Global chunk coordinates stored in a chunk's uncompressed NBT
+pub(crate) fn chunk_pos(data: &[u8]) -> Option<(i32, i32)> {
+    // chunks from before 1.18 keep their position in the Level compound
+    int_at(data, &["xPos"]).zip(int_at(data, &["zPos"]))
+        .or_else(|| int_at(data, &["Level", "xPos"]).zip(int_at(data, &["Level", "zPos"])))
+}
+
+/// Block entities in a chunk's uncompressed NBT, 0 if it has none
+pub(crate) fn block_entity_count(data: &[u8]) -> usize {
+    // renamed (and moved out of Level) in 1.18
+    list_len_at(data, &["block_entities"])
+        .or_else(|| list_len_at(data, &["Level", "TileEntities"]))
+        .unwrap_or(0)
+}
+
+/// Entities in a chunk's uncompressed NBT, 0 if it has none. Since 1.17 they are kept in
+/// separate entity regions, whose chunks have a root `Entities` list.
+pub(crate) fn entity_count(data: &[u8]) -> usize {
+    list_len_at(data, &["Entities"])
+        .or_else(|| list_len_at(data, &["Level", "Entities"]))
+        .unwrap_or(0)
+}
+
+/// Find an int tag in uncompressed NBT by the names of the compounds leading to it from the root,
+/// e.g. `["Level", "xPos"]`
+pub(crate) fn int_at(data: &[u8], path: &[&str]) -> Option<i32> {
+    seek(data, path, TAG_INT)?.i32()
+}
+
+/// Number of elements in a list tag, found as in [`int_at`]
+pub(crate) fn list_len_at(data: &[u8], path: &[&str]) -> Option<usize> {
+    let mut reader = seek(data, path, TAG_LIST)?;
+    reader.u8()?;
+    reader.array_len(1)
+}
+
+/// A reader positioned at the payload of the tag at `path`, if it has type `tag`
+fn seek<'a>(data: &'a [u8], path: &[&str], tag: u8) -> Option<Reader<'a>> {
+    let (name, parents) = path.split_last()?;
+    let mut reader = Reader { data };
+
+    if reader.u8()? != TAG_COMPOUND {
+        return None;
+    }
+    reader.name()?;
+
+    // descend into each parent compound in turn
+    for (depth, parent) in parents.iter().enumerate() {
+        reader.find(parent.as_bytes(), TAG_COMPOUND, depth + 1)?;
+    }
+
+    reader.find(name.as_bytes(), tag, path.len())?;
+    Some(reader)
+}
@@ -13,17 +13,26 @@
 * along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::{borrow::Cow, cmp::Reverse, collections::HashMap, ffi::OsStr, fs::File, io::{self, ErrorKind, Read, Seek, SeekFrom}, process::Command, sync::{mpsc::{self, Receiver, RecvTimeoutError, TryRecvError}, Arc, Mutex, PoisonError}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use bitvec::{bitarr, order::Lsb0, BitArr};
-use fuser::{FileAttr, FileType, Filesystem, Notifier, FUSE_ROOT_ID};
+use fuser::{FileAttr, FileType, Filesystem, KernelConfig, Notifier, PollHandle, FUSE_ROOT_ID};
 use int_enum::IntEnum;
-use libc::{EACCES, EBADF, EEXIST, EFBIG, EINVAL, ENOENT, ENOSYS, ENOTDIR, EPERM, EROFS};
+use fuser::consts::{FOPEN_DIRECT_IO, FUSE_ATOMIC_O_TRUNC, FUSE_FLOCK_LOCKS, FUSE_POLL_SCHEDULE_NOTIFY, FUSE_POSIX_LOCKS};
+use libc::{c_int, EACCES, EAGAIN, EBADF, EBUSY, EEXIST, EFBIG, EINVAL, EIO, ENODATA, ENOENT, ENOLCK, ENOSYS, ENOTDIR, ENOTTY, EPERM, ERANGE, EROFS, ETIMEDOUT};
 use log::{debug, error, info, warn};
 
-use crate::{anvil::{coords_to_idx, idx_to_coords, Chunk, CompressionType, RegionFile, MAX_CHUNK_LEN, SECTOR_LEN}, GuardedFile};
+use crate::{anvil::{coords_to_idx, header_time, idx_to_coords, Chunk, CompressionType, LoadWarning, RegionFile, MAX_CHUNK_LEN, SECTOR_LEN}, cli::DirOrder, codec::{self, MAX_NBT_LEN}, ioctl, journal::{Entry, Journal}, metrics::Metrics, nbt, GuardedFile};
 
 
 const TTL: Duration = Duration::from_secs(1);
+/// Inodes between the root and this are reserved for control files
+const FIRST_DYNAMIC_INO: u64 = FUSE_ROOT_ID + 16;
+/// With stable inodes, inodes between [`FIRST_DYNAMIC_INO`] and this belong to fixed chunk files
+const FIRST_UNRESERVED_INO: u64 = FIRST_DYNAMIC_INO + 2 * 32 * 32;
+const DATA_VERSION_XATTR: &str = "user.smithy.data_version";
+const SECTOR_OFFSET_XATTR: &str = "user.smithy.sector_offset";
+const SECTOR_COUNT_XATTR: &str = "user.smithy.sector_count";
+const XATTRS: [&str; 3] = [DATA_VERSION_XATTR, SECTOR_OFFSET_XATTR, SECTOR_COUNT_XATTR];
 const ROOT_DIR_ATTR: FileAttr = fattr(FUSE_ROOT_ID, 0, UNIX_EPOCH, FileType::Directory, 0o555, 2, 0, 0);
 
 
@@ -56,70 +65,92 @@ struct FileKey {
     x: u8,
     /// Must be < 32
     z: u8,
-    kind: FileKind
+    kind: FileKind,
+    /// Named as a dotfile, which only `.cmp` files can be
+    hidden: bool
 }
 
 impl FileKey {
     fn parse(name: &str) -> Option<Self> {
-        enum FSM {
-            Uninit,
-            X{x: u8, n: u8},
-            Z{x: u8, z: u8, n: u8},
-        }
-        use FSM::*;
+        let (hidden, name) = match name.strip_prefix('.') {
+            Some(name) => (true, name),
+            None => (false, name)
+        };
 
         let (kind, name) = FileKind::parse_extension(name)?;
+        if hidden && kind.is_chunk() {
+            return None;
+        }
 
-        let mut chars = name.chars();
-        let mut state = Uninit;
+        let (x, z) = parse_chunk_name(name)?;
 
-        while let Some(c) = chars.next() {
-            state = match state {
-                Uninit => {
-                    match c {
-                        'x' => X { x: 0, n: 2 },
-                        _ => break
-                    }
-                }
-                X{x, n} => {
-                    if let Some(d) = c.to_digit(10) {
-                        if n == 0 {
-                            break
-                        }
+        Some(Self { x, z, kind, hidden })
+    }
+}
 
-                        if n < 2 && x == 0 {
-                            break
-                        }
+/// Whether `name` would collide with a file smithy generates in the root directory (in any mode)
+pub(crate) fn is_reserved_name(name: &str) -> bool {
+    FileKey::parse(name).is_some() || ControlFile::from_name(name).is_some()
+}
 
-                        X { x: x * 10 + (d as u8), n: n - 1 }
-                    } else if c == 'z' {
-                        Z { x, z: 0, n: 2 }
-                    } else {
-                        break
-                    }
+/// Parse a chunk name without extension (`x#z#`) into local chunk coordinates
+pub(crate) fn parse_chunk_name(name: &str) -> Option<(u8, u8)> {
+    enum FSM {
+        Uninit,
+        X{x: u8, n: u8},
+        Z{x: u8, z: u8, n: u8},
+    }
+    use FSM::*;
+
+    let mut chars = name.chars();
+    let mut state = Uninit;
+
+    while let Some(c) = chars.next() {
+        state = match state {
+            Uninit => {
+                match c {
+                    'x' => X { x: 0, n: 2 },
+                    _ => break
                 }
-                Z{x, z, n} => {
+            }
+            X{x, n} => {
+                if let Some(d) = c.to_digit(10) {
                     if n == 0 {
-                        return None
+                        break
                     }
 
-                    if n < 2 && z == 0 {
-                        return None
+                    if n < 2 && x == 0 {
+                        break
                     }
 
-                    if let Some(d) = c.to_digit(10) {
-                        Z { x, z: z * 10 + (d as u8), n: n - 1 }
-                    } else {
-                        return None
-                    }
+                    X { x: x * 10 + (d as u8), n: n - 1 }
+                } else if c == 'z' {
+                    Z { x, z: 0, n: 2 }
+                } else {
+                    break
+                }
+            }
+            Z{x, z, n} => {
+                if n == 0 {
+                    return None
                 }
-            };
-        }
 
-        match state {
-            Z{x, z, n} if n < 2 && x < 32 && z < 32 => Some(Self { x, z, kind }),
-            _ => None
-        }
+                if n < 2 && z == 0 {
+                    return None
+                }
+
+                if let Some(d) = c.to_digit(10) {
+                    Z { x, z: z * 10 + (d as u8), n: n - 1 }
+                } else {
+                    return None
+                }
+            }
+        };
+    }
+
+    match state {
+        Z{x, z, n} if n < 2 && x < 32 && z < 32 => Some((x, z)),
+        _ => None
     }
 }
 
@@ -169,13 +200,68 @@ impl FileKind {
     }
 }
 
+/// Names of a chunk's data and compression info files, as they appear in a (non `--hidden-cmp`)
+/// mount
+pub(crate) fn chunk_file_names(x: u8, z: u8) -> (String, String) {
+    (FileKind::Chunk.make_fname(x, z), FileKind::CompressionInfo.make_fname(x, z))
+}
+
+/// Space-prefixed chunk names, e.g. ` x0z0 x5z3`
+pub(crate) fn chunk_list(coords: impl IntoIterator<Item = (u8, u8)>) -> String {
+    coords.into_iter().map(|(x, z)| format!(" x{}z{}", x, z)).collect()
+}
+
+/// Space-prefixed chunk names with the problem found in each, e.g. ` x0z0:invalid_header`
+pub(crate) fn warning_list(warnings: &[LoadWarning]) -> String {
+    warnings.iter().map(|w| format!(" x{}z{}:{}", w.x, w.z, w.problem.name())).collect()
+}
+
+/// Files in the root directory that expose smithy's own state rather than chunk data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ControlFile {
+    /// The raw location and timestamp tables (expert mode only)
+    Header,
+    /// Summary of unsaved and doomed chunks, read-only
+    Status,
+    /// `1` or `0`, toggles whether the mount accepts writes
+    Writable,
+    /// The region's own coordinates, as `x z`, read-only
+    Region,
+}
+impl ControlFile {
+    const ALL: [Self; 4] = [Self::Header, Self::Status, Self::Writable, Self::Region];
+
+    fn ino(self) -> u64 {
+        FUSE_ROOT_ID + 1 + self as u64
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Header => ".smithy-header",
+            Self::Status => ".smithy-status",
+            Self::Writable => ".smithy-writable",
+            Self::Region => ".smithy-region",
+        }
+    }
+
+    fn from_ino(ino: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|file| file.ino() == ino)
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|file| file.name() == name)
+    }
+}
+
 struct FileHandle {
-    perms: u8
+    perms: u8,
+    /// Text of a `.cmp` file as edited through this handle, parsed once it is flushed
+    written: Option<Vec<u8>>
 }
 impl FileHandle {
     fn new(read: bool, write: bool) -> Self {
         let perms = (read as u8) | ((write as u8) << 1);
-        Self { perms }
+        Self { perms, written: None }
     }
 
     #[inline(always)]
@@ -201,17 +287,54 @@ impl FileHandleAlloc {
     }
 }
 
-fn read_into(data: &[u8], offset: usize, size: usize, reply: fuser::ReplyData) {
+/// A FUSE file offset as an index into file contents, or `None` if it's negative. Offsets too
+/// large for `usize` become `usize::MAX`, which is past the end of any file.
+fn file_offset(offset: i64) -> Option<usize> {
+    u64::try_from(offset).ok().map(|offset| usize::try_from(offset).unwrap_or(usize::MAX))
+}
+
+/// Up to `size` bytes of `data` from `offset`, as `pread` would return them
+fn slice_at(data: &[u8], offset: usize, size: usize) -> &[u8] {
     if offset >= data.len() {
-        reply.data(&[]);
+        &[]
+    } else {
+        let end = offset.saturating_add(size).min(data.len());
+        &data[offset..end]
+    }
+}
+
+/// Reply with an xattr value or list, or just its size if the caller is probing with `size == 0`
+fn reply_xattr(data: &[u8], size: u32, reply: fuser::ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
     } else {
-        let end = (offset + size).min(data.len());
-        reply.data(&data[offset..end]);
+        reply.data(data);
     }
 }
 
+/// Longest text accepted in a `.cmp` file, far more than any selector string needs
+const MAX_INFO_LEN: usize = 256;
+
+/// How `.nbt` files present chunk data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChunkView {
+    /// The bytes stored in the region (`--raw`)
+    Stored,
+    /// Decompressed NBT
+    Nbt,
+    /// Gzipped NBT, like standalone `.nbt` files (`--standalone-nbt`)
+    Standalone,
+}
+
 enum InodeData {
+    /// Chunk data as stored in the region, compressed with the `.cmp` type
     Chunk(Vec<u8>),
+    /// Decompressed chunk data, compressed with the `.cmp` type when saved
+    Nbt(Vec<u8>),
+    /// Gzipped chunk data, recompressed with the `.cmp` type when saved
+    Standalone(Vec<u8>),
     Info(CompressionType),
 }
 impl InodeData {
@@ -222,56 +345,55 @@ impl InodeData {
         }
     }
 
-    fn blank(kind: FileKind) -> Self {
+    /// A chunk file showing `data`, stored with `compression_type`, as `view` presents it
+    fn chunk(view: ChunkView, compression_type: CompressionType, data: &[u8]) -> std::io::Result<Self> {
+        Ok(match view {
+            ChunkView::Stored => Self::Chunk(data.to_vec()),
+            ChunkView::Nbt => Self::Nbt(codec::decompress(compression_type, data)?),
+            ChunkView::Standalone => {
+                let nbt = codec::decompress(compression_type, data)?;
+                Self::Standalone(codec::compress(CompressionType::GZip, &nbt)?)
+            }
+        })
+    }
+
+    /// Contents of a new file, with `template` as uncompressed chunk data
+    fn blank(kind: FileKind, template: &[u8], view: ChunkView) -> Self {
         match kind {
-            FileKind::Chunk => InodeData::Chunk(vec![]),
-            FileKind::CompressionInfo => InodeData::Info(CompressionType::Unknown(42)),
+            FileKind::Chunk => Self::chunk(view, CompressionType::None, template)
+                .expect("uncompressed data can be shown in every view"),
+            FileKind::CompressionInfo => InodeData::Info(CompressionType::None),
         }
     }
 
     fn len(&self) -> usize {
         match self {
-            InodeData::Chunk(data) => data.len(),
+            InodeData::Chunk(data) | InodeData::Nbt(data) | InodeData::Standalone(data) => data.len(),
             InodeData::Info(ct) => ct.make_selector_string().len(),
         }
     }
 
-    fn read(&self, offset: i64, size: u32, reply: fuser::ReplyData) {
-        if offset < 0 {
-            reply.error(EINVAL);
-            return;
-        }
-
-        let offset = offset as usize;
-        let size = size as usize;
-
+    /// Up to `size` bytes of the file's contents from `offset`
+    fn read_at(&self, offset: usize, size: usize) -> Cow<'_, [u8]> {
         match self {
-            Self::Chunk(chunk) => {
-                read_into(chunk, offset, size, reply)
-            }
+            Self::Chunk(chunk) | Self::Nbt(chunk) | Self::Standalone(chunk) => Cow::Borrowed(slice_at(chunk, offset, size)),
             Self::Info(info) => {
                 let info = info.make_selector_string();
-                let info = info.as_bytes();
-                read_into(info, offset, size, reply)
+                Cow::Owned(slice_at(info.as_bytes(), offset, size).to_vec())
             }
         }
     }
 
-    fn write(&mut self, offset: i64, data: &[u8], reply: fuser::ReplyWrite) {
-        if offset < 0 {
-            reply.error(EINVAL);
-            return;
-        }
-
-        let offset = offset as usize;
+    /// Write `data` at `offset`, returning how many bytes were written or an errno
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<usize, c_int> {
+        let max_len = self.max_len();
 
         match self {
-            Self::Chunk(chunk) => {
-                let end = offset + data.len();
+            Self::Chunk(chunk) | Self::Nbt(chunk) | Self::Standalone(chunk) => {
+                let end = offset.checked_add(data.len()).ok_or(EFBIG)?;
 
-                if end >= MAX_CHUNK_LEN {
-                    reply.error(EFBIG);
-                    return;
+                if end >= max_len {
+                    return Err(EFBIG);
                 }
 
                 if end > chunk.len() {
@@ -279,46 +401,80 @@ impl InodeData {
                 }
 
                 chunk[offset..end].copy_from_slice(data);
-
-                reply.written(data.len() as u32);
             }
             Self::Info(ct) => {
                 if offset != 0 {
-                    reply.error(EINVAL);
-                    return;
+                    return Err(EINVAL);
                 }
 
-                let data_str = match std::str::from_utf8(data) {
-                    Ok(data_str) => data_str,
-                    Err(_) => {
-                        reply.error(EINVAL);
-                        return;
-                    }
-                };
+                *ct = std::str::from_utf8(data).ok()
+                    .and_then(CompressionType::parse_selector_string)
+                    .ok_or(EINVAL)?;
+            }
+        }
 
-                let ct_new = match CompressionType::parse_selector_string(data_str) {
-                    Some(ct_new) => ct_new,
-                    None => {
-                        reply.error(EINVAL);
-                        return;
-                    }
-                };
+        Ok(data.len())
+    }
 
-                *ct = ct_new;
-                reply.written(data.len() as u32);
-            }
+    /// A chunk's data as stored in the region, compressing decompressed data with `compression_type`
+    /// at `level` (see [`codec::compress_with`])
+    fn stored(&self, compression_type: CompressionType, level: Option<u32>) -> std::io::Result<Cow<'_, [u8]>> {
+        match self {
+            Self::Chunk(data) => Ok(Cow::Borrowed(data)),
+            Self::Nbt(nbt) => codec::compress_with(compression_type, nbt, level).map(Cow::Owned),
+            Self::Standalone(gzipped) => codec::decompress(CompressionType::GZip, gzipped)
+                .and_then(|nbt| codec::compress_with(compression_type, &nbt, level))
+                .map(Cow::Owned),
+            Self::Info(_) => Err(std::io::Error::new(ErrorKind::InvalidInput, "not a chunk file")),
+        }
+    }
+
+    /// Length chunk files must stay below
+    fn max_len(&self) -> usize {
+        match self {
+            Self::Nbt(_) | Self::Standalone(_) => MAX_NBT_LEN,
+            Self::Chunk(_) | Self::Info(_) => MAX_CHUNK_LEN,
         }
     }
 
     #[inline(always)]
     fn kind(&self) -> FileKind {
         match self {
-            Self::Chunk(_) => FileKind::Chunk,
+            Self::Chunk(_) | Self::Nbt(_) | Self::Standalone(_) => FileKind::Chunk,
             Self::Info(_) => FileKind::CompressionInfo
         }
     }
 }
 
+/// Permission bits of chunks loaded from the region
+const DEFAULT_PERM: u16 = 0o644;
+
+/// Restrict a requested mode to the bits chunk files support, i.e. no execute or special bits
+fn clamp_perm(mode: u32) -> u16 {
+    (mode & 0o666) as u16
+}
+
+/// An advisory byte-range lock, both ends inclusive
+#[derive(Clone, Copy, Debug)]
+struct FileLock {
+    owner: u64,
+    start: u64,
+    end: u64,
+    typ: i32,
+    pid: u32
+}
+impl FileLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.typ == libc::F_WRLCK || other.typ == libc::F_WRLCK)
+    }
+}
+
 struct Inode {
     ino: u64,
     x: u8,
@@ -326,6 +482,12 @@ struct Inode {
     data: InodeData,
     mtime: SystemTime,
     open_handles: HashMap<u64, FileHandle>,
+    /// Permission bits, before read-only mounts strip write access
+    perm: u16,
+    /// Belongs to a chunk that was only partially loaded or can't be decompressed, so it must not
+    /// be modified
+    read_only: bool,
+    locks: Vec<FileLock>,
     linked: bool,
     nlookup: u64
 }
@@ -338,19 +500,25 @@ impl Inode {
             data: InodeData::new(kind, chunk),
             mtime: chunk.mtime,
             open_handles: HashMap::new(),
+            perm: DEFAULT_PERM,
+            read_only: chunk.truncated,
+            locks: vec![],
             linked: true,
             nlookup: 0
         }
     }
 
-    fn blank(x: u8, z: u8, inos: &InoSet, kind: FileKind) -> Self {
+    fn blank(x: u8, z: u8, inos: &InoSet, kind: FileKind, perm: u16, template: &[u8], view: ChunkView) -> Self {
         Self {
             ino: inos.get(kind),
             x,
             z,
-            data: InodeData::blank(kind),
-            mtime: SystemTime::now(),
+            data: InodeData::blank(kind, template, view),
+            mtime: header_time(SystemTime::now()),
             open_handles: HashMap::new(),
+            perm,
+            read_only: false,
+            locks: vec![],
             linked: true,
             nlookup: 0
         }
@@ -358,7 +526,7 @@ impl Inode {
 
     fn attr(&self, writable: bool, uid: u32, gid: u32) -> FileAttr {
         let len = self.data.len();
-        let perm = if writable { 0o644 } else { 0o444 };
+        let perm = if writable && !self.read_only { self.perm } else { self.perm & 0o444 };
 
         fattr(self.ino, len as u64, self.mtime, FileType::RegularFile, perm, self.linked as u32, uid, gid)
     }
@@ -372,10 +540,31 @@ impl Inode {
             error!("Lookup count mismatch detected in {}. It may be wise to remount the smithy filesystem.", self.make_fname());
         }
 
-        self.nlookup -= count;
+        self.nlookup = self.nlookup.saturating_sub(count);
         self.nlookup
     }
 
+    /// Release `owner`'s locks within `start..=end`, splitting any that extend past it
+    fn unlock(&mut self, owner: u64, start: u64, end: u64) {
+        let mut kept = Vec::with_capacity(self.locks.len());
+
+        for lock in self.locks.drain(..) {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+
+            if lock.start < start {
+                kept.push(FileLock { end: start - 1, ..lock });
+            }
+            if lock.end > end {
+                kept.push(FileLock { start: end + 1, ..lock });
+            }
+        }
+
+        self.locks = kept;
+    }
+
     fn can_discard(&self) -> bool {
         !self.linked && self.nlookup == 0 && self.open_handles.is_empty()
     }
@@ -407,31 +596,64 @@ impl IntoIterator for InoSet {
     }
 }
 
-struct InoAlloc(u64);
+struct InoAlloc {
+    next: u64,
+    /// Derive chunk inodes from chunk coordinates, so they stay the same across remounts
+    stable: bool
+}
 impl InoAlloc {
-    fn new() -> Self {
-        Self(FUSE_ROOT_ID + 1)
+    fn new(stable: bool) -> Self {
+        let next = if stable { FIRST_UNRESERVED_INO } else { FIRST_DYNAMIC_INO };
+        Self { next, stable }
+    }
+
+    fn allocate(&mut self) -> u64 {
+        let ino = self.next;
+        self.next += 1;
+        ino
     }
 
     fn allocate_inos(&mut self) -> InoSet {
         // round up to next even
-        self.0 = (self.0 + 1) & (!1);
+        self.next = (self.next + 1) & (!1);
 
         let entry = InoSet {
-            chunk_ino: self.0,
-            info_ino: self.0 + 1
+            chunk_ino: self.next,
+            info_ino: self.next + 1
         };
 
-        self.0 += 2;
+        self.next += 2;
 
         entry
     }
+
+    /// Inodes for a chunk's files. `in_use` tells whether an inode still belongs to an earlier,
+    /// deleted chunk at the same position, in which case stable inodes can't be used.
+    fn chunk_inos(&mut self, x: u8, z: u8, in_use: impl Fn(u64) -> bool) -> InoSet {
+        if self.stable {
+            let chunk_ino = FIRST_DYNAMIC_INO + 2 * coords_to_idx(x, z) as u64;
+            if !in_use(chunk_ino) && !in_use(chunk_ino + 1) {
+                return InoSet { chunk_ino, info_ino: chunk_ino + 1 };
+            }
+            debug!("Stable inodes of chunk [{} {}] are still in use, allocating new ones", x, z);
+        }
+
+        self.allocate_inos()
+    }
 }
 
 struct DirHandle {
     entries: Vec<(u64, FileType, String)>
 }
 
+struct ControlHandle {
+    file: ControlFile,
+    handle: FileHandle,
+    /// Contents as of open, then edited by writes until they are committed
+    data: Vec<u8>,
+    modified: bool
+}
+
 #[derive(Clone, Copy, Debug)]
 struct DeletionInfo {
     ino: u64,
@@ -450,393 +672,2011 @@ impl From<&mut Inode> for DeletionInfo {
     }
 }
 
+/// The kernel cache invalidations smithy sends, implemented by [`Notifier`] once the session
+/// has started
+pub(crate) trait Notify: Send {
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()>;
+    fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()>;
+}
+impl Notify for Notifier {
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        Notifier::inval_entry(self, parent, name)
+    }
+
+    fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        Notifier::inval_inode(self, ino, offset, len)
+    }
+}
+
+/// What a computed symlink in the root directory points at
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum LinkTarget {
+    /// Whichever chunk was modified most recently
+    Latest,
+    /// A fixed chunk, which need not exist
+    Chunk { x: u8, z: u8 }
+}
+
+struct Symlink {
+    ino: u64,
+    name: String,
+    target: LinkTarget
+}
+
+/// Mount-wide settings for [`SmithyFS`]
+pub(crate) struct FsOptions {
+    pub(crate) writable: bool,
+    /// Coordinates of the region, from its file name
+    pub(crate) region_pos: (isize, isize),
+    /// Upper bound on the size of a single read reply, if any
+    pub(crate) max_read: Option<u32>,
+    pub(crate) symlinks: Vec<(String, LinkTarget)>,
+    /// Expose control files that allow corrupting the region if misused
+    pub(crate) expert: bool,
+    /// Log progress while loading chunks
+    pub(crate) progress: bool,
+    /// Name `.cmp` files as dotfiles
+    pub(crate) hidden_cmp: bool,
+    /// Order of chunk files in directory listings
+    pub(crate) dir_order: DirOrder,
+    /// Compact the region file whenever a write-out leaves holes in it
+    pub(crate) trim: bool,
+    /// Check every write-out by reading the region back
+    pub(crate) verify_writeback: bool,
+    /// Number chunk files by their coordinates instead of in load order
+    pub(crate) stable_inos: bool,
+    /// Drop unsaved changes at unmount rather than saving them
+    pub(crate) discard_on_exit: bool,
+    /// Log what write-outs would save instead of saving it
+    pub(crate) dry_run: bool,
+    /// Let write-only handles read too
+    pub(crate) permissive_read: bool,
+    /// How chunk files present their data
+    pub(crate) view: ChunkView,
+    /// Level chunks are compressed at when saved, if not each codec's default
+    pub(crate) compression_level: Option<u32>,
+    /// Percentage of addressable sectors in use past which write-outs warn
+    pub(crate) warn_full: Option<u8>,
+    /// Percentage of changed sectors past which write-outs write every sector
+    pub(crate) full_write_threshold: u8,
+    /// Uncompressed NBT that new chunks start out with
+    pub(crate) chunk_template: Vec<u8>,
+    pub(crate) metrics: Option<Metrics>,
+    /// Program run with the region's path after every successful write-out
+    pub(crate) on_save: Option<String>,
+    /// How long a write-out may take before it is given up on
+    pub(crate) io_timeout: Option<Duration>,
+    /// Where edits are recorded until they are saved, with the edits a previous mount left there
+    pub(crate) journal: Option<(Journal, Vec<Entry>)>,
+    /// The region was decompressed from an archive or cut out of a larger file, so it can never
+    /// be made writable
+    pub(crate) read_only_source: bool,
+    /// The user vouched for having a backup of the region, without which it is only made writable
+    /// for dry runs
+    pub(crate) backup_acknowledged: bool,
+}
+
+/// Everything a mount has saved, for the summary logged at unmount
+#[derive(Default)]
+struct SaveTotals {
+    /// Chunks written or deleted
+    chunks: usize,
+    /// Compressed bytes of the chunks written
+    bytes: usize,
+    write_outs: usize
+}
 
 pub(crate) struct SmithyFS {
     region: RegionFile,
+    region_pos: (isize, isize),
     uid: u32,
     gid: u32,
     writable: bool,
-    root_dir_attr: FileAttr,
+    read_only_source: bool,
+    backup_acknowledged: bool,
+    /// Upper bound on the size of a single read reply, if any
+    max_read: Option<u32>,
+    progress: bool,
+    hidden_cmp: bool,
+    dir_order: DirOrder,
+    trim: bool,
+    verify_writeback: bool,
+    discard_on_exit: bool,
+    dry_run: bool,
+    permissive_read: bool,
+    view: ChunkView,
+    compression_level: Option<u32>,
+    warn_full: Option<u8>,
+    /// Whether the last write-out was past `warn_full`, so that crossing it is only logged once
+    over_full: bool,
+    full_write_threshold: u8,
+    chunk_template: Vec<u8>,
+    metrics: Option<Metrics>,
+    on_save: Option<String>,
+    io_timeout: Option<Duration>,
+    /// Result of a write-out that timed out, which arrives once the write finally finishes
+    stalled_write: Option<Receiver<std::io::Result<()>>>,
+    journal: Option<Journal>,
+    /// Edits found in the journal, waiting for the mount to be writable to be replayed
+    journal_pending: Vec<Entry>,
 
     links: HashMap<(u8, u8), InoSet>,
     inodes: HashMap<u64, Inode>,
+    symlinks: Vec<Symlink>,
+    control_files: Vec<ControlFile>,
     dirty_chunks: BitArr!(for 32 * 32, in usize, Lsb0),
 
     dir_handles: HashMap<u64, DirHandle>,
+    control_handles: HashMap<u64, ControlHandle>,
+    /// `.smithy-status` handles waiting in `poll` for the status to change from what they last read
+    status_polls: Vec<(u64, PollHandle)>,
 
     ino_alloc: InoAlloc,
     fh_alloc: FileHandleAlloc,
 
     backing_file: GuardedFile,
+    mounted_at: Instant,
+    saved: SaveTotals,
 
-    pub(crate) notifier: Arc<Mutex<Option<Notifier>>>
+    pub(crate) notifier: Arc<Mutex<Option<Box<dyn Notify>>>>
 }
 
 impl SmithyFS {
-    pub(crate) fn new(region: RegionFile, uid: u32, gid: u32, writable: bool, backing_file: GuardedFile) -> Self {
+    pub(crate) fn new(region: RegionFile, uid: u32, gid: u32, options: FsOptions, backing_file: GuardedFile) -> Self {
+        let FsOptions { writable, region_pos, max_read, symlinks, expert, progress, hidden_cmp, dir_order, trim, verify_writeback, stable_inos, discard_on_exit, dry_run, permissive_read, view, compression_level, warn_full, full_write_threshold, chunk_template, metrics, on_save, io_timeout, journal, read_only_source, backup_acknowledged } = options;
+        let (journal, journal_pending) = journal.map_or((None, vec![]), |(journal, pending)| (Some(journal), pending));
+
+        let mut control_files = vec![ControlFile::Status, ControlFile::Writable, ControlFile::Region];
+        if expert {
+            control_files.push(ControlFile::Header);
+        }
+
         let mut fs = Self {
             region,
+            region_pos,
             uid,
             gid,
             writable,
-            root_dir_attr: FileAttr {
-                uid,
-                gid,
-                perm: if writable { 0o755 } else { 0o555 },
-                ..ROOT_DIR_ATTR
-            },
+            read_only_source,
+            backup_acknowledged,
+            max_read,
+            progress,
+            hidden_cmp,
+            dir_order,
+            trim,
+            verify_writeback,
+            discard_on_exit,
+            dry_run,
+            permissive_read,
+            view,
+            compression_level,
+            warn_full,
+            over_full: false,
+            full_write_threshold,
+            chunk_template,
+            metrics,
+            on_save,
+            io_timeout,
+            stalled_write: None,
+            journal,
+            journal_pending,
 
             links: HashMap::new(),
             inodes: HashMap::new(),
+            symlinks: Vec::with_capacity(symlinks.len()),
+            control_files,
             dirty_chunks: bitarr!(usize, Lsb0; 0; 32 * 32),
 
             dir_handles: HashMap::new(),
+            control_handles: HashMap::new(),
+            status_polls: vec![],
 
-            ino_alloc: InoAlloc::new(),
+            ino_alloc: InoAlloc::new(stable_inos),
             fh_alloc: FileHandleAlloc::new(),
 
             backing_file,
+            mounted_at: Instant::now(),
+            saved: SaveTotals::default(),
 
             notifier: Arc::default()
         };
 
+        for (name, target) in symlinks {
+            let ino = fs.ino_alloc.allocate();
+            fs.symlinks.push(Symlink { ino, name, target });
+        }
+
+        fs.load_chunks();
+        fs.replay_journal();
+
+        fs
+    }
+
+    /// Create inodes for every chunk in the region that isn't linked yet
+    fn load_chunks(&mut self) {
+        let mut loaded = 0;
+
         for z in 0..32 {
             for x in 0..32 {
-                let chunk = match fs.region.lookup_chunk(x, z) {
+                if self.links.contains_key(&(x, z)) {
+                    continue;
+                }
+
+                let chunk = match self.region.lookup_chunk(x, z) {
                     Some(c) => c,
                     None => continue,
                 };
 
-                let inos = fs.ino_alloc.allocate_inos();
+                let inodes = &self.inodes;
+                let inos = self.ino_alloc.chunk_inos(x, z, |ino| inodes.contains_key(&ino));
 
-                let chunk_ino = Inode::new(&chunk, &inos, FileKind::Chunk);
-                let info_ino = Inode::new(&chunk, &inos, FileKind::CompressionInfo);
+                let (chunk_ino, info_ino) = Self::chunk_inodes(self.view, &chunk, &inos);
+
+                self.links.insert((x, z), inos);
+                self.inodes.insert(inos.chunk_ino, chunk_ino);
+                self.inodes.insert(inos.info_ino, info_ino);
+                loaded += 1;
+            }
 
-                fs.links.insert((x, z), inos);
-                fs.inodes.insert(inos.chunk_ino, chunk_ino);
-                fs.inodes.insert(inos.info_ino, info_ino);
+            if self.progress && (z + 1) % 8 == 0 {
+                info!("Loading chunks: {}/32 rows done, {} chunks so far", z + 1, loaded);
             }
         }
 
-        fs
+        if self.progress {
+            info!("Loaded {} chunks", loaded);
+        }
     }
 
-    #[inline(always)]
-    fn get_ino(&self, key: FileKey) -> Option<u64> {
-        let inos = self.links.get(&(key.x, key.z))?;
-        Some(inos.get(key.kind))
-    }
+    /// A chunk's `.nbt` and `.cmp` inodes, as `view` shows it
+    fn chunk_inodes(view: ChunkView, chunk: &Chunk<'_>, inos: &InoSet) -> (Inode, Inode) {
+        let mut chunk_ino = Inode::new(chunk, inos, FileKind::Chunk);
+        let mut info_ino = Inode::new(chunk, inos, FileKind::CompressionInfo);
+
+        if view != ChunkView::Stored {
+            match InodeData::chunk(view, chunk.compression_type, chunk.data) {
+                Ok(data) => chunk_ino.data = data,
+                Err(e) => {
+                    warn!("Chunk [{} {}] can't be decompressed, so its stored data is shown read-only: {}", chunk.x, chunk.z, e);
+                    chunk_ino.read_only = true;
+                    info_ino.read_only = true;
+                }
+            }
+        }
 
-    #[allow(unused)]
-    #[inline(always)]
-    fn get_inode(&self, key: FileKey) -> Option<&Inode> {
-        let ino = self.get_ino(key)?;
-        self.inodes.get(&ino)
+        (chunk_ino, info_ino)
     }
 
-    #[inline(always)]
-    fn get_inode_mut(&mut self, key: FileKey) -> Option<&mut Inode> {
-        let ino = self.get_ino(key)?;
-        self.inodes.get_mut(&ino)
-    }
+    /// Throw away unsaved changes to the chunk `ino` belongs to, going back to the region's copy.
+    /// Open handles stay open, and see the old contents from then on.
+    fn reset_chunk(&mut self, ino: u64) -> Result<(), c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
 
-    fn stat_ino(&self, ino: u64) -> Option<FileAttr> {
-        let inode = self.inodes.get(&ino)?;
-        Some(self.stat_inode(inode))
-    }
+        let Some((x, z)) = self.inodes.get(&ino).filter(|inode| inode.linked).map(|inode| (inode.x, inode.z)) else {
+            return Err(EINVAL);
+        };
+        let Some(inos) = self.links.get(&(x, z)).copied() else {
+            return Err(EINVAL);
+        };
+        let Some(chunk) = self.region.lookup_chunk(x, z) else {
+            // created since the last save, so there is nothing to go back to
+            return Err(ENODATA);
+        };
 
-    fn stat_inode(&self, inode: &Inode) -> FileAttr {
-        inode.attr(self.writable, self.uid, self.gid)
+        let (chunk_ino, info_ino) = Self::chunk_inodes(self.view, &chunk, &inos);
+        for fresh in [chunk_ino, info_ino] {
+            if let Some(inode) = self.inodes.get_mut(&fresh.ino) {
+                inode.data = fresh.data;
+                inode.mtime = fresh.mtime;
+                inode.read_only = fresh.read_only;
+            }
+        }
+
+        self.dirty_chunks.set(coords_to_idx(x, z), false);
+        self.record(Entry::Reset { x, z });
+        info!("Reset chunk [{} {}] to its last saved state", x, z);
+        self.invalidate_attrs(inos.chunk_ino);
+        self.invalidate_attrs(inos.info_ino);
+        self.notify_status_polls();
+        Ok(())
     }
 
-    fn create_dir_handle(&mut self) -> u64 {
-        let fh = self.fh_alloc.alloc();
+    /// Unlink both files of a chunk, deleting it on the next write-out. Returns the files for
+    /// [`SmithyFS::delete`] to notify the kernel of.
+    fn unlink_chunk(&mut self, x: u8, z: u8) -> Result<Vec<DeletionInfo>, c_int> {
+        if self.get_inode(FileKey { x, z, kind: FileKind::Chunk, hidden: false }).is_some_and(|inode| inode.read_only) {
+            return Err(EPERM);
+        }
 
-        let mut entries = vec![
-            (FUSE_ROOT_ID, FileType::Directory, ".".to_owned()),
-            (FUSE_ROOT_ID, FileType::Directory, "..".to_owned()),
-        ];
+        let inos = self.links.remove(&(x, z)).ok_or(ENOENT)?;
 
-        entries.reserve_exact(self.inodes.len());
+        let mut to_delete = vec![];
 
-        let kinds = vec![
-            FileKind::Chunk,
-            FileKind::CompressionInfo
-        ];
+        for ino in inos {
+            let inode = match self.inodes.get_mut(&ino) {
+                Some(inode) => inode,
+                None => continue
+            };
 
-        for z in 0..32 {
-            for x in 0..32 {
-                for &kind in &kinds {
-                    if let Some(inos) = self.links.get(&(x, z)) {
-                        let ino = inos.get(kind);
-                        entries.push((ino, FileType::RegularFile, kind.make_fname(x, z)));
-                    }
-                }
-            }
+            inode.linked = false;
+            to_delete.push(DeletionInfo::from(inode));
+
+            self.gc(ino);
+            self.mark_dirty(x, z);
         }
 
-        self.dir_handles.insert(fh, DirHandle { entries });
+        if to_delete.is_empty() {
+            return Err(ENOENT);
+        }
 
-        fh
+        self.record(Entry::Delete { x, z });
+        Ok(to_delete)
     }
 
-    fn gc(&mut self, ino: u64) -> Option<Inode> {
-        let inode = self.inodes.get(&ino)?;
-
-        if !inode.can_discard() {
-            return None;
+    /// Append an edit to the journal, if there is one
+    fn record(&mut self, entry: Entry) {
+        if let Some(journal) = &mut self.journal && let Err(e) = journal.append(&entry) {
+            warn!("Failed to record an edit in journal `{}`, a crash would lose it: {}", journal.path().display(), e);
         }
-
-        info!("Discarding inode {}", ino);
-        self.inodes.remove(&ino)
     }
 
-    fn delete(&mut self, info: DeletionInfo) {
-        let ino = info.ino;
-        let name = info.kind.make_fname(info.x, info.z);
+    /// Re-apply the edits a previous mount left in the journal, then save them. Only writable
+    /// mounts can, so read-only ones keep them for when they become writable.
+    fn replay_journal(&mut self) {
+        if self.journal_pending.is_empty() || !self.writable {
+            return;
+        }
+
+        let entries = std::mem::take(&mut self.journal_pending);
+        info!("Replaying {} unsaved edits from the journal", entries.len());
 
-        if let Ok(guard) = self.notifier.try_lock() {
-            guard.as_ref().inspect(|&notifier| {
-                let name: std::ffi::OsString = name.into();
+        // they are already in it
+        let journal = self.journal.take();
+        for entry in entries {
+            if let Err(e) = self.replay(&entry) {
+                warn!("Failed to replay {:?} from the journal: {}", entry, std::io::Error::from_raw_os_error(e));
+            }
+        }
+        self.journal = journal;
 
-                info!("Notifying deletion of inode {}", ino);
+        self.write_back_dirty();
+    }
 
-                match notifier.inval_entry(FUSE_ROOT_ID, &name) {
-                    Ok(_) => info!("Notified deletion of inode {}", ino),
-                    Err(e) => warn!("Failed to notify deletion of inode {}: {}", ino, e)
+    /// Apply an edit from the journal, as the operation that recorded it did
+    fn replay(&mut self, entry: &Entry) -> Result<(), c_int> {
+        match *entry {
+            Entry::Create { x, z } => {
+                let name = self.fname(FileKind::Chunk, x, z);
+                let ino = self.create_chunk(FUSE_ROOT_ID, name.as_ref(), libc::S_IFREG | DEFAULT_PERM as u32, 0)?;
+                // no kernel was told about it, so there is no lookup to forget later
+                self.forget_lookups(ino, 1);
+            }
+            Entry::Write { x, z, offset, ref data } => {
+                let inode = self.get_inode_mut(FileKey { x, z, kind: FileKind::Chunk, hidden: false }).ok_or(ENOENT)?;
+                inode.data.write_at(usize::try_from(offset).map_err(|_| EFBIG)?, data)?;
+                inode.mtime = header_time(SystemTime::now());
+                self.mark_dirty(x, z);
+            }
+            Entry::Truncate { x, z, len } => {
+                let inode = self.get_inode_mut(FileKey { x, z, kind: FileKind::Chunk, hidden: false }).ok_or(ENOENT)?;
+                let max_len = inode.data.max_len();
+                match &mut inode.data {
+                    InodeData::Chunk(data) | InodeData::Nbt(data) | InodeData::Standalone(data) => match usize::try_from(len) {
+                        Ok(len) if len < max_len => data.resize(len, 0),
+                        _ => return Err(EFBIG),
+                    },
+                    InodeData::Info(_) => return Err(EINVAL),
+                }
+                inode.mtime = header_time(SystemTime::now());
+                self.mark_dirty(x, z);
+            }
+            Entry::Delete { x, z } => {
+                // nothing is mounted yet (or the kernel was never told), so there is nobody to notify
+                self.unlink_chunk(x, z)?;
+            }
+            Entry::Compression { x, z, compression_type } => {
+                let inode = self.get_inode_mut(FileKey { x, z, kind: FileKind::CompressionInfo, hidden: false }).ok_or(ENOENT)?;
+                let InodeData::Info(previous) = inode.data else {
+                    return Err(EINVAL);
                 };
-            });
-        } else {
-            warn!("Failed to acquire notifier lock. Deletion of inode {} will be silent.", ino);
+                inode.data = InodeData::Info(compression_type);
+                inode.mtime = header_time(SystemTime::now());
+                self.compression_changed(x, z, previous)?;
+                self.mark_dirty(x, z);
+            }
+            Entry::Reset { x, z } => {
+                let ino = self.get_ino(FileKey { x, z, kind: FileKind::Chunk, hidden: false }).ok_or(ENOENT)?;
+                self.reset_chunk(ino)?;
+            }
         }
+
+        Ok(())
     }
 
-    /// Mark a chunk as needing to be saved
-    fn mark_dirty(&mut self, x: u8, z: u8) {
-        if !self.writable { // there's really no point
-            return;
+    /// Unlink every chunk inode and recreate them from the region, for when its layout changed
+    fn reload_chunks(&mut self) {
+        let mut to_delete = vec![];
+
+        for (_, inos) in std::mem::take(&mut self.links) {
+            for ino in inos {
+                if let Some(inode) = self.inodes.get_mut(&ino) {
+                    inode.linked = false;
+                    to_delete.push(DeletionInfo::from(inode));
+                }
+
+                self.gc(ino);
+            }
         }
 
-        self.dirty_chunks.set(coords_to_idx(x, z), true);
-        debug!("Marked chunk [{} {}] as dirty", x, z);
-    }
+        self.delete(&to_delete);
 
-    /// Actually save data to disk
-    fn write_back(&mut self) {
-        if !self.writable {
-            warn!("Read-only but asked to write???");
-            return;
+        self.dirty_chunks.fill(false);
+        // the journal's edits were made to chunks that may no longer be where they were
+        if let Some(journal) = &mut self.journal && let Err(e) = journal.reset(self.backing_file.get()) {
+            warn!("Failed to clear journal `{}`, its edits may be replayed onto the wrong chunks: {}", journal.path().display(), e);
         }
+        self.load_chunks();
+        self.notify_status_polls();
+    }
 
-        info!("Writing all changes to mounted file");
+    /// Name of a chunk's file as shown in this mount
+    fn fname(&self, kind: FileKind, x: u8, z: u8) -> String {
+        let name = kind.make_fname(x, z);
 
-        let mut deleted_chunks = vec![];
-        let mut modified_chunks = vec![];
+        if self.hidden_cmp && !kind.is_chunk() {
+            format!(".{}", name)
+        } else {
+            name
+        }
+    }
 
-        for dirty_idx in self.dirty_chunks.iter_ones() {
-            let (x, z) = idx_to_coords(dirty_idx);
+    /// Parse a chunk file name, as long as it's in the form this mount uses
+    fn parse_key(&self, name: &std::ffi::OsStr) -> Option<FileKey> {
+        name.to_str()
+            .and_then(FileKey::parse)
+            .filter(|key| key.hidden == (self.hidden_cmp && !key.kind.is_chunk()))
+    }
 
-            let inodes = self.links.get(&(x, z))
-                .map_or(
-                    (None, None),
-                    |inos| (self.inodes.get(&inos.chunk_ino), self.inodes.get(&inos.info_ino)),
-                );
+    #[inline(always)]
+    fn get_ino(&self, key: FileKey) -> Option<u64> {
+        let inos = self.links.get(&(key.x, key.z))?;
+        Some(inos.get(key.kind))
+    }
 
-            match inodes {
-                (
-                    Some(Inode {
-                        data: InodeData::Chunk(chunk_data),
-                        mtime,
-                        ..
-                    }),
-                    Some(Inode {
-                        data: InodeData::Info(compression_type),
-                        ..
-                    })
-                ) => {
-                    info!("> Writing chunk [{} {}]", x, z);
-                    modified_chunks.push((x, z, chunk_data, compression_type, mtime));
-                }
-                (Some(_), Some(_)) => warn!("> Chunk [{} {}] is broken and cannot be written", x, z),
-                _ => {
-                    info!("> Writing deletion of chunk [{} {}]", x, z);
-                    deleted_chunks.push((x, z));
-                }
+    #[inline(always)]
+    fn get_inode(&self, key: FileKey) -> Option<&Inode> {
+        let ino = self.get_ino(key)?;
+        self.inodes.get(&ino)
+    }
+
+    #[inline(always)]
+    fn get_inode_mut(&mut self, key: FileKey) -> Option<&mut Inode> {
+        let ino = self.get_ino(key)?;
+        self.inodes.get_mut(&ino)
+    }
+
+    fn stat_root(&self) -> FileAttr {
+        FileAttr {
+            uid: self.uid,
+            gid: self.gid,
+            perm: if self.writable { 0o755 } else { 0o555 },
+            ..ROOT_DIR_ATTR
+        }
+    }
+
+    fn stat_ino(&self, ino: u64) -> Option<FileAttr> {
+        let inode = self.inodes.get(&ino)?;
+        Some(self.stat_inode(inode))
+    }
+
+    fn stat_inode(&self, inode: &Inode) -> FileAttr {
+        inode.attr(self.writable, self.uid, self.gid)
+    }
+
+    /// `DataVersion` of a chunk inode, if its data can be decompressed and has one
+    fn data_version(&self, inode: &Inode) -> Option<i32> {
+        let data = match &inode.data {
+            InodeData::Chunk(data) => data,
+            InodeData::Nbt(nbt) => return nbt::int_at(nbt, &["DataVersion"]),
+            InodeData::Standalone(gzipped) => {
+                let nbt = codec::decompress(CompressionType::GZip, gzipped).ok()?;
+                return nbt::int_at(&nbt, &["DataVersion"]);
             }
+            InodeData::Info(_) => return None
+        };
+
+        let inos = self.links.get(&(inode.x, inode.z))?;
+        let InodeData::Info(compression_type) = self.inodes.get(&inos.info_ino)?.data else {
+            return None;
+        };
+
+        let nbt = codec::decompress(compression_type, data).ok()?;
+        nbt::int_at(&nbt, &["DataVersion"])
+    }
+
+    /// First sector and number of sectors of a chunk file's data within the region file, if it
+    /// has been saved
+    fn sectors(&self, inode: &Inode) -> Option<(u32, u32)> {
+        if inode.data.kind() != FileKind::Chunk {
+            return None;
         }
 
-        // Delete chunks first to free up sectors
-        for (x, z) in deleted_chunks {
-            self.region.delete_chunk(x, z);
+        self.region.chunk_sectors(inode.x, inode.z)
+    }
+
+    fn xattr(&self, inode: &Inode, name: &str) -> Option<String> {
+        match name {
+            DATA_VERSION_XATTR => self.data_version(inode).map(|version| version.to_string()),
+            SECTOR_OFFSET_XATTR => self.sectors(inode).map(|(offset, _)| offset.to_string()),
+            SECTOR_COUNT_XATTR => self.sectors(inode).map(|(_, len)| len.to_string()),
+            _ => None
+        }
+    }
+
+    fn get_symlink(&self, ino: u64) -> Option<&Symlink> {
+        self.symlinks.iter().find(|link| link.ino == ino)
+    }
+
+    /// Find the chunk a symlink currently points at
+    fn resolve_link(&self, target: LinkTarget) -> Option<(u8, u8)> {
+        match target {
+            LinkTarget::Latest => {
+                self.links.iter()
+                    .filter_map(|(&(x, z), inos)| Some((self.inodes.get(&inos.chunk_ino)?.mtime, x, z)))
+                    .max_by_key(|&(mtime, x, z)| (mtime, coords_to_idx(x, z)))
+                    .map(|(_, x, z)| (x, z))
+            }
+            LinkTarget::Chunk { x, z } => Some((x, z))
         }
+    }
 
-        // Then free sectors from modified chunks
-        for &(x, z, _, _, _) in &modified_chunks {
-            self.region.free_chunk(x, z);
+    fn stat_symlink(&self, link: &Symlink) -> FileAttr {
+        let target = self.resolve_link(link.target);
+
+        let len = target.map_or(0, |(x, z)| FileKind::Chunk.make_fname(x, z).len());
+        let mtime = target
+            .and_then(|(x, z)| self.get_inode(FileKey { x, z, kind: FileKind::Chunk, hidden: false }))
+            .map_or(UNIX_EPOCH, |inode| inode.mtime);
+
+        fattr(link.ino, len as u64, mtime, FileType::Symlink, 0o777, 1, self.uid, self.gid)
+    }
+
+    fn control_file(&self, ino: u64) -> Option<ControlFile> {
+        ControlFile::from_ino(ino).filter(|file| self.control_files.contains(file))
+    }
+
+    fn control_file_named(&self, name: &str) -> Option<ControlFile> {
+        ControlFile::from_name(name).filter(|file| self.control_files.contains(file))
+    }
+
+    /// Current contents of a control file
+    fn read_control(&self, file: ControlFile) -> Vec<u8> {
+        match file {
+            ControlFile::Header => self.region.encode_header(),
+            ControlFile::Status => self.status().into_bytes(),
+            ControlFile::Writable => format!("{}\n", self.writable as u8).into_bytes(),
+            ControlFile::Region => format!("{} {}\n", self.region_pos.0, self.region_pos.1).into_bytes(),
         }
+    }
 
-        // write biggest chunks first, to reduce fragmentation
-        modified_chunks.sort_unstable_by_key(|(_, _, data, _, _)| usize::MAX - data.len());
+    fn control_writable(&self, file: ControlFile) -> bool {
+        match file {
+            ControlFile::Header => self.writable,
+            ControlFile::Status | ControlFile::Region => false,
+            ControlFile::Writable => !self.read_only_source,
+        }
+    }
 
-        // Then write modified chunks
-        for &(x, z, data, compression_type, mtime) in &modified_chunks {
-            self.region.write_chunk(x, z, data, *compression_type, *mtime);
+    /// Whether `ino` can be modified right now. `.smithy-writable` stays writable on a read-only
+    /// mount, since it is how the mount is made writable.
+    fn accepts_writes(&self, ino: u64) -> bool {
+        self.writable || self.control_file(ino) == Some(ControlFile::Writable)
+    }
+
+    fn stat_control(&self, file: ControlFile) -> FileAttr {
+        let len = self.read_control(file).len();
+        let perm = if self.control_writable(file) { 0o644 } else { 0o444 };
+
+        fattr(file.ino(), len as u64, UNIX_EPOCH, FileType::RegularFile, perm, 1, self.uid, self.gid)
+    }
+
+    /// Apply data written to a control file, if `fh` is a modified control file handle
+    fn commit_control(&mut self, fh: u64) -> Option<Result<(), c_int>> {
+        let handle = self.control_handles.get_mut(&fh)?;
+
+        if !handle.modified {
+            return Some(Ok(()));
         }
+        handle.modified = false;
 
-        // write out to disk
-        let (full_write, file) = self.backing_file.get_mut();
-        if full_write {
-            info!("> Writing all sectors");
-        } else {
-            info!("> Writing changed sectors");
+        let (file, data) = (handle.file, handle.data.clone());
+
+        Some(match file {
+            ControlFile::Header => self.replace_header(&data),
+            ControlFile::Status | ControlFile::Region => Err(EACCES),
+            ControlFile::Writable => match data.trim_ascii() {
+                b"1" => self.set_writable(true),
+                b"0" => self.set_writable(false),
+                _ => Err(EINVAL),
+            },
+        })
+    }
+
+    /// Switch between read-only and writable, re-opening the region file to match. Unsaved
+    /// changes are written before giving up write access.
+    fn set_writable(&mut self, writable: bool) -> Result<(), c_int> {
+        if writable == self.writable {
+            return Ok(());
         }
-        match self.region.write_out(full_write, file) {
-            Ok(()) => {
-                self.dirty_chunks.fill(false);
+        if self.read_only_source {
+            return Err(EROFS);
+        }
+        if writable && !self.dry_run && !self.backup_acknowledged {
+            warn!("Staying read-only, since the mount wasn't started with --i-have-a-backup");
+            return Err(EACCES);
+        }
+
+        if !writable {
+            self.write_back_dirty();
+            if self.dirty_chunks.any() {
+                error!("Staying writable, since unsaved changes could not be written");
+                return Err(EIO);
             }
-            Err(err) => {
-                error!("Failed to write out region: {}", err);
+        }
+
+        // re-opening would hang just like the write did
+        if writable && let Some(stalled) = &self.stalled_write {
+            match stalled.try_recv() {
+                Err(TryRecvError::Empty) => {
+                    warn!("Staying read-only, since the last write to the region file still hasn't finished");
+                    return Err(EBUSY);
+                }
+                Ok(Ok(())) => info!("The stalled write finished after all"),
+                Ok(Err(e)) => warn!("The stalled write finally failed: {}", e),
+                Err(TryRecvError::Disconnected) => warn!("The thread of the stalled write panicked"),
             }
+            self.stalled_write = None;
+        }
+
+        if let Err(e) = self.backing_file.reopen(writable && !self.dry_run) {
+            error!("Failed to re-open region file: {}", e);
+            return Err(match e.kind() {
+                ErrorKind::PermissionDenied => EACCES,
+                ErrorKind::WouldBlock => EBUSY,
+                _ => EIO,
+            });
         }
+
+        info!("Mount is now {}", if writable { "writable" } else { "read-only" });
+        self.writable = writable;
+        self.replay_journal();
+        self.notify_status_polls();
+        Ok(())
     }
-}
 
-impl Filesystem for SmithyFS {
-    fn lookup(&mut self, _req: &fuser::Request<'_>, parent: u64, name: &std::ffi::OsStr, reply: fuser::ReplyEntry) {
-        if parent != FUSE_ROOT_ID {
-            reply.error(ENOENT);
+    /// Contents of `.smithy-status`, one `key: value` per line
+    fn status(&self) -> String {
+        format!(
+            "writable: {}\nunsaved:{}\npending_deletions:{}\nload_warnings:{}\n",
+            self.writable,
+            chunk_list(self.dirty_chunks.iter_ones().map(idx_to_coords)),
+            chunk_list(self.region.pending_deletions()),
+            warning_list(self.region.load_warnings()),
+        )
+    }
+
+    /// Wake every `poll` on `.smithy-status` whose handle last read something other than the current status
+    fn notify_status_polls(&mut self) {
+        if self.status_polls.is_empty() {
             return;
         }
 
-        if let Some(key) = name.to_str().and_then(FileKey::parse) {
-            //debug!("Parsed file name as chunk [{} {}] {:?}", key.x, key.z, key.kind);
-            let (writable, uid, gid) = (self.writable, self.uid, self.gid);
+        let status = self.status().into_bytes();
+        let (changed, waiting) = std::mem::take(&mut self.status_polls)
+            .into_iter()
+            .partition(|(fh, _)| self.control_handles.get(fh).is_none_or(|handle| handle.data != status));
+        self.status_polls = waiting;
 
-            if let Some(inode) = self.get_inode_mut(key) {
-                inode.inc_lookup();
-                let attr = inode.attr(writable, uid, gid);
-                reply.entry(&TTL, &attr, 0);
-                return;
+        for (_, ph) in changed {
+            if let Err(e) = ph.notify() {
+                debug!("Failed to wake a poll on {}: {}", ControlFile::Status.name(), e);
+            }
+        }
+    }
+
+    fn replace_header(&mut self, data: &[u8]) -> Result<(), c_int> {
+        warn!("Replacing region header with the contents of {}", ControlFile::Header.name());
+
+        // outstanding edits have to land before chunk locations change underneath them
+        if self.dirty_chunks.any() {
+            self.write_back();
+            // reloading drops whatever is still unsaved, along with its journal record
+            if self.dirty_chunks.any() {
+                error!("Not replacing the region header, unsaved chunks would be lost");
+                return Err(EIO);
             }
-            //debug!("Chunk [{} {}] is missing", key.x, key.z);
         }
 
-        //debug!("Failed to look up file {:?} in {}", name, parent);
-        reply.error(ENOENT);
+        if let Err(e) = self.region.replace_header(data) {
+            error!("Rejected new region header: {}", e);
+            return Err(EINVAL);
+        }
+
+        self.reload_chunks();
+        self.write_back();
+
+        Ok(())
     }
 
-    fn forget(&mut self, _req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
-        let inode = match self.inodes.get_mut(&ino) {
-            Some(inode) => inode,
-            None => return
+    fn create_dir_handle(&mut self, dir: u64) -> u64 {
+        let fh = self.fh_alloc.alloc();
+
+        // the mount is a single flat directory, and like any root it is its own parent
+        let mut entries = vec![
+            (dir, FileType::Directory, ".".to_owned()),
+            (FUSE_ROOT_ID, FileType::Directory, "..".to_owned()),
+        ];
+
+        entries.reserve_exact(self.inodes.len() + self.symlinks.len() + self.control_files.len());
+
+        for &file in &self.control_files {
+            entries.push((file.ino(), FileType::RegularFile, file.name().to_owned()));
+        }
+
+        for link in &self.symlinks {
+            entries.push((link.ino, FileType::Symlink, link.name.clone()));
+        }
+
+        let kinds = vec![
+            FileKind::Chunk,
+            FileKind::CompressionInfo
+        ];
+
+        let mut chunks: Vec<(u8, u8)> = (0..32)
+            .flat_map(|z| (0..32).map(move |x| (x, z)))
+            .filter(|pos| self.links.contains_key(pos))
+            .collect();
+        let chunk_inode = |pos: &(u8, u8)| self.inodes.get(&self.links[pos].get(FileKind::Chunk));
+        // stable, so ties stay in coordinate order
+        match self.dir_order {
+            DirOrder::Coord => {}
+            DirOrder::Size => chunks.sort_by_key(|pos| Reverse(chunk_inode(pos).map_or(0, |inode| inode.data.len()))),
+            DirOrder::Mtime => chunks.sort_by_key(|pos| Reverse(chunk_inode(pos).map(|inode| inode.mtime))),
+        }
+
+        for (x, z) in chunks {
+            for &kind in &kinds {
+                let ino = self.links[&(x, z)].get(kind);
+                entries.push((ino, FileType::RegularFile, self.fname(kind, x, z)));
+            }
+        }
+
+        self.dir_handles.insert(fh, DirHandle { entries });
+
+        fh
+    }
+
+    /// Discard `ino` if nothing refers to it any more. Linked inodes are never discarded, whatever
+    /// their lookup counts, so a chunk's `.nbt` and `.cmp` stay together for as long as it is in
+    /// `links`; only once both are unlinked can each go its own way.
+    fn gc(&mut self, ino: u64) -> Option<Inode> {
+        let inode = self.inodes.get(&ino)?;
+
+        if !inode.can_discard() {
+            return None;
+        }
+
+        info!("Discarding inode {}", ino);
+        self.inodes.remove(&ino)
+    }
+
+    /// Tell the kernel that the files in `deleted` are gone, taking the notifier lock only once
+    fn delete(&self, deleted: &[DeletionInfo]) {
+        if deleted.is_empty() {
+            return;
+        }
+
+        // the lock is only ever held to install the notifier before the session starts, so
+        // waiting for it can't stall, unlike skipping the notifications
+        let guard = self.notifier.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(notifier) = guard.as_ref() else {
+            warn!("No notifier yet. Deletion of {} inodes will be silent.", deleted.len());
+            return;
         };
 
-        if inode.dec_lookup(nlookup) == 0 {
-            self.gc(ino);
+        for info in deleted {
+            let name: std::ffi::OsString = self.fname(info.kind, info.x, info.z).into();
+
+            match notifier.inval_entry(FUSE_ROOT_ID, &name) {
+                Ok(_) => info!("Notified deletion of inode {}", info.ino),
+                Err(e) => warn!("Failed to notify deletion of inode {}: {}", info.ino, e)
+            };
         }
     }
 
-    fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, _fh: Option<u64>, reply: fuser::ReplyAttr) {
-        if ino == FUSE_ROOT_ID {
-            reply.attr(&TTL, &self.root_dir_attr);
-        } else if let Some(attr) = self.stat_ino(ino) {
-            reply.attr(&TTL, &attr);
-        } else {
-            reply.error(ENOENT);
+    /// Drop the kernel's cached attributes of `ino`, so that its size is fetched again
+    fn invalidate_attrs(&self, ino: u64) {
+        // blocking, for the same reason as in `delete`
+        let guard = self.notifier.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(notifier) = guard.as_ref() {
+            // a negative offset leaves the page cache alone, which could deadlock against
+            // the operation that changed the file
+            if let Err(e) = notifier.inval_inode(ino, -1, 0) {
+                warn!("Failed to invalidate cached attributes of inode {}: {}", ino, e);
+            }
         }
     }
 
-    fn mknod(
-            &mut self,
-            _req: &fuser::Request<'_>,
-            parent: u64,
-            name: &std::ffi::OsStr,
-            mode: u32,
-            _umask: u32,
-            _rdev: u32,
-            reply: fuser::ReplyEntry,
-        ) {
+    /// Create a new chunk from the name of either of its files, returning that file's inode with
+    /// one lookup counted, for the entry it is replied with
+    fn create_chunk(&mut self, parent: u64, name: &std::ffi::OsStr, mode: u32, umask: u32) -> Result<u64, c_int> {
         if !self.writable {
-            reply.error(EROFS);
-            return;
+            return Err(EROFS);
         }
 
         if parent != FUSE_ROOT_ID {
-            reply.error(ENOENT);
-            return;
+            return Err(ENOENT);
         }
 
         let file_type = mode & libc::S_IFMT;
 
         if file_type != libc::S_IFREG {
-            reply.error(EPERM);
-            return;
+            return Err(EPERM);
         }
 
-        let Some(key) = name.to_str().and_then(FileKey::parse) else {
-            reply.error(EINVAL);
-            return;
+        let Some(key) = self.parse_key(name) else {
+            return Err(EINVAL);
         };
 
         if self.links.contains_key(&(key.x, key.z)) {
-            reply.error(EEXIST);
-            return;
+            return Err(EEXIST);
+        }
+
+        // its sectors are released by the write-out either way, this just keeps it from vanishing silently
+        if self.region.is_pending_deletion(key.x, key.z) {
+            warn!("Replacing chunk [{} {}], which had an invalid header and couldn't be loaded", key.x, key.z);
         }
 
-        let inos = self.ino_alloc.allocate_inos();
-        let chunk_inode = Inode::blank(key.x, key.z, &inos, FileKind::Chunk);
-        let info_inode = Inode::blank(key.x, key.z, &inos, FileKind::CompressionInfo);
+        let perm = clamp_perm(mode & !umask);
+
+        let inodes = &self.inodes;
+        let inos = self.ino_alloc.chunk_inos(key.x, key.z, |ino| inodes.contains_key(&ino));
+        let chunk_inode = Inode::blank(key.x, key.z, &inos, FileKind::Chunk, perm, &self.chunk_template, self.view);
+        let info_inode = Inode::blank(key.x, key.z, &inos, FileKind::CompressionInfo, perm, &self.chunk_template, self.view);
 
-        warn!("Make sure to set correct compression type in {}", info_inode.make_fname());
+        if self.view == ChunkView::Stored {
+            warn!(
+                "New chunk [{} {}] starts out uncompressed, set the compression type in {} before writing compressed data to it",
+                key.x, key.z, self.fname(FileKind::CompressionInfo, key.x, key.z)
+            );
+        }
 
         self.links.insert((key.x, key.z), inos);
         self.inodes.insert(inos.chunk_ino, chunk_inode);
         self.inodes.insert(inos.info_ino, info_inode);
+        // returned through an entry, which the kernel counts as a lookup
+        if let Some(inode) = self.inodes.get_mut(&inos.get(key.kind)) {
+            inode.inc_lookup();
+        }
 
         self.mark_dirty(key.x, key.z);
+        self.record(Entry::Create { x: key.x, z: key.z });
 
-        reply.entry(&TTL, &self.stat_ino(inos.get(key.kind)).expect("just-created inode should exist"), 0);
+        Ok(inos.get(key.kind))
     }
 
-    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-        let (read, write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => {
-                if flags & libc::O_TRUNC != 0{
-                    reply.error(EACCES);
-                    return;
-                }
-                (true, false)
-            }
-            libc::O_WRONLY => {
-                (false, true)
+    /// Apply a change of a chunk's `.cmp` file from `previous`. Decompressed data is simply saved
+    /// with the new type, which fails with `EINVAL` (keeping `previous`) if smithy can't write it.
+    /// Stored data is converted to the new type, or keeps its bytes under the new label if it can't be.
+    fn compression_changed(&mut self, x: u8, z: u8, previous: CompressionType) -> Result<(), c_int> {
+        let Some(inos) = self.links.get(&(x, z)).copied() else {
+            return Ok(());
+        };
+        let Some(&Inode { data: InodeData::Info(current), .. }) = self.inodes.get(&inos.info_ino) else {
+            return Ok(());
+        };
+
+        if current.encode() == previous.encode() {
+            return Ok(());
+        }
+
+        if let Some(Inode { data: InodeData::Nbt(_) | InodeData::Standalone(_), .. }) = self.inodes.get(&inos.chunk_ino) {
+            if codec::can_compress(current) {
+                return Ok(());
             }
-            libc::O_RDWR => {
-                (true, true)
+
+            if let Some(Inode { data: InodeData::Info(compression_type), .. }) = self.inodes.get_mut(&inos.info_ino) {
+                *compression_type = previous;
             }
+            return Err(EINVAL);
+        }
+
+        let level = self.compression_level;
+        let Some(Inode { data: InodeData::Chunk(data), mtime, .. }) = self.inodes.get_mut(&inos.chunk_ino) else {
+            return Ok(());
+        };
+
+        // only data that really was `previous` is converted: anything decodes as `none`, so bytes
+        // written before fixing a wrong label would otherwise be compressed a second time
+        let nbt = match codec::decompress(previous, data) {
+            Ok(nbt) if nbt::is_compound(&nbt) => nbt,
             _ => {
-                reply.error(EINVAL);
-                return;
+                info!(
+                    "Chunk [{} {}] is now labelled {}; its data isn't NBT stored as {}, so its bytes were kept as they are",
+                    x, z, current.name(), previous.name()
+                );
+                return Ok(());
             }
         };
 
-        if write && !self.writable {
-            reply.error(EROFS);
-            return;
+        match codec::compress_with(current, &nbt, level) {
+            Ok(encoded) => {
+                *data = encoded;
+                *mtime = header_time(SystemTime::now());
+                info!("Re-encoded chunk [{} {}] from {} to {}", x, z, previous.name(), current.name());
+            }
+            Err(e) => warn!(
+                "Chunk [{} {}] is now labelled {}, but its data couldn't be re-encoded from {} and was kept as-is: {}",
+                x, z, current.name(), previous.name(), e
+            )
         }
 
-        let inode = match self.inodes.get_mut(&ino) {
-            Some(i) => i,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+        Ok(())
+    }
+
+    /// Apply the text written to a `.cmp` file through `fh`, if there is any
+    fn commit_info(&mut self, ino: u64, fh: u64) -> Result<(), c_int> {
+        let Some(inode) = self.inodes.get_mut(&ino) else {
+            return Ok(());
+        };
+        let InodeData::Info(previous) = inode.data else {
+            return Ok(());
+        };
+        // emptied by O_TRUNC but not written yet, e.g. when a shell closes the fd it just dup'd.
+        // Left empty, the type simply stays as it was.
+        let Some(text) = inode.open_handles.get_mut(&fh)
+            .and_then(|handle| handle.written.take_if(|text| !text.is_empty())) else {
+            return Ok(());
         };
 
-        let fh = self.fh_alloc.alloc();
-        inode.open_handles.insert(fh, FileHandle::new(read, write));
+        let parsed = inode.data.write_at(0, &text);
+        let (x, z, linked) = (inode.x, inode.z, inode.linked);
+
+        // the size seen while editing no longer applies either way
+        self.invalidate_attrs(ino);
+        parsed?;
+
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.mtime = header_time(SystemTime::now());
+        }
+        // a deleted chunk's `.cmp` has no chunk left to relabel, and must not touch one created
+        // at the same position since
+        if !linked {
+            return Ok(());
+        }
+        self.compression_changed(x, z, previous)?;
+        self.mark_dirty(x, z);
+        if let Some(&Inode { data: InodeData::Info(compression_type), linked: true, .. }) = self.inodes.get(&ino) {
+            self.record(Entry::Compression { x, z, compression_type });
+        }
+
+        Ok(())
+    }
+
+    /// Mark a chunk as needing to be saved
+    fn mark_dirty(&mut self, x: u8, z: u8) {
+        if !self.writable { // there's really no point
+            return;
+        }
+
+        self.dirty_chunks.set(coords_to_idx(x, z), true);
+        debug!("Marked chunk [{} {}] as dirty", x, z);
+        self.notify_status_polls();
+    }
+
+    /// Warn when the region crosses the `--warn-full` threshold, before sector allocation starts failing
+    fn check_fullness(&mut self) {
+        let Some(threshold) = self.warn_full else {
+            return;
+        };
+
+        let fullness = self.region.fullness();
+        let over_full = fullness >= threshold as f64;
+        if over_full && !self.over_full {
+            warn!(
+                "Region is {:.1}% full (warning at {}%), chunks that no longer fit will be dropped once it fills up",
+                fullness, threshold
+            );
+        } else if !over_full && self.over_full {
+            info!("Region is back below {}% full", threshold);
+        }
+        self.over_full = over_full;
+    }
+
+    /// Write back at a flush, fsync or release boundary, if anything changed since the last one.
+    /// Writes only ever edit the in-memory chunk data, so however many of them come in between,
+    /// each chunk is written to the region once.
+    fn write_back_dirty(&mut self) {
+        if self.dirty_chunks.any() {
+            self.write_back();
+            if self.trim {
+                self.trim();
+            }
+        } else {
+            debug!("Nothing changed since the last write-back");
+        }
+    }
+
+    /// Save, then compact the region into a new file that replaces the old one
+    fn defrag(&mut self) -> Result<u32, c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+
+        if self.dry_run {
+            info!("Dry run: not defragmenting the region");
+            return Err(EROFS);
+        }
+
+        if self.dirty_chunks.any() {
+            self.write_back();
+            if self.dirty_chunks.any() {
+                return Err(EIO);
+            }
+        }
+
+        // the moved layout is only kept once it is safely on disk: written over the old file in
+        // place, an interrupted write would leave the old header pointing at moved sectors
+        let mut region = self.region.clone();
+        let freed = region.defrag();
+
+        if let Err(e) = self.backing_file.replace(|file| region.write_out(true, file)) {
+            error!("Failed to write defragmented region, keeping the old layout: {}", e);
+            return Err(EIO);
+        }
+        self.region = region;
+
+        info!("Defragmented region, freeing {} sectors", freed);
+        self.verify_written();
+        Ok(freed)
+    }
+
+    /// Defragment if the region has free sectors between its chunks
+    fn trim(&mut self) {
+        let stats = self.region.stats();
+        if stats.used_sectors == stats.file_sectors {
+            return;
+        }
+
+        if let Ok(freed) = self.defrag() {
+            info!("Trimmed region, reclaiming {} bytes", freed as usize * SECTOR_LEN);
+        }
+    }
+
+    /// With `verify_writeback`, read the region file back and check that it holds exactly the
+    /// chunks in memory. A mismatch means something in the write path is broken, so no more
+    /// writes are risked after one.
+    fn verify_written(&mut self) {
+        if !self.verify_writeback {
+            return;
+        }
+
+        let mut data = vec![];
+        let mut file = self.backing_file.get();
+        let read = file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_end(&mut data));
+
+        let problem = match read {
+            // leniently, since truncated chunks kept in memory are written back as they are
+            Ok(_) => match RegionFile::new_checked(data, true, false) {
+                Ok(written) => self.region.first_difference(&written)
+                    .map(|(x, z)| format!("chunk [{} {}] differs from what was written", x, z)),
+                Err(e) => Some(e.to_string()),
+            },
+            Err(e) => Some(format!("failed to read it back: {}", e)),
+        };
+
+        match problem {
+            Some(problem) => {
+                error!("!!! Write-back verification FAILED: {} !!!", problem);
+                error!("Making the mount read-only to avoid further damage");
+                // not through `set_writable`, which would try to save whatever is still dirty to
+                // the file that was just read back wrong
+                self.writable = false;
+                if let Err(e) = self.backing_file.reopen(false) {
+                    error!("Failed to re-open region file read-only: {}", e);
+                }
+                self.notify_status_polls();
+            }
+            None => debug!("Write-back verified"),
+        }
+    }
+
+    /// Start the `--on-save` program, without waiting for it to finish
+    fn run_on_save(&self) {
+        let Some(program) = &self.on_save else {
+            return;
+        };
+
+        let path = self.backing_file.path();
+        match Command::new(program).arg(path).spawn() {
+            Ok(mut child) => {
+                let program = program.clone();
+                // reap it in the background, so that slow hooks never hold up the filesystem
+                thread::spawn(move || match child.wait() {
+                    Ok(status) if status.success() => debug!("On-save program `{}` finished", program),
+                    Ok(status) => warn!("On-save program `{}` failed: {}", program, status),
+                    Err(e) => warn!("Failed to wait for on-save program `{}`: {}", program, e),
+                });
+            }
+            Err(e) => warn!("Failed to run on-save program `{}`: {}", program, e),
+        }
+    }
+
+    /// Update the dirty chunk gauge of the metrics file
+    fn update_metrics(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_dirty_chunks(self.dirty_chunks.count_ones());
+        }
+    }
+
+    /// Write the region out from another thread, giving up after `timeout`. A write that times
+    /// out can't be cancelled, so it is left to finish in the background, in `stalled_write`.
+    fn write_out_within(&mut self, full_write: bool, mut file: File, timeout: Duration) -> std::io::Result<()> {
+        let plan = self.region.write_plan(full_write);
+        let (done, result) = mpsc::channel();
+        thread::spawn(move || {
+            // nobody is listening any more if the write took too long
+            let _ = done.send(plan.apply(&mut file));
+        });
+
+        match result.recv_timeout(timeout) {
+            Ok(Ok(())) => {
+                self.region.mark_written();
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e),
+            Err(RecvTimeoutError::Timeout) => {
+                self.stalled_write = Some(result);
+                Err(std::io::Error::new(ErrorKind::TimedOut, format!("no reply from the region file's storage within {:?}", timeout)))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(std::io::Error::other("the writing thread panicked")),
+        }
+    }
+
+    /// Actually save data to disk
+    fn write_back(&mut self) {
+        if !self.writable {
+            warn!("Read-only but asked to write???");
+            return;
+        }
+
+        if self.dry_run {
+            info!("Dry run: logging changes instead of writing them");
+        } else {
+            info!("Writing all changes to mounted file");
+        }
+        let verb = if self.dry_run { "Would write" } else { "Writing" };
+
+        let mut deleted_chunks = vec![];
+        let mut modified_chunks = vec![];
+        let mut unsaved_chunks = vec![];
+
+        for dirty_idx in self.dirty_chunks.iter_ones() {
+            let (x, z) = idx_to_coords(dirty_idx);
+
+            // a chunk is always written with the compression type in its own .cmp sibling
+            let inodes = self.links.get(&(x, z))
+                .map_or(
+                    (None, None),
+                    |inos| (self.inodes.get(&inos.chunk_ino), self.inodes.get(&inos.info_ino)),
+                );
+
+            match inodes {
+                (
+                    Some(Inode {
+                        data: chunk_data @ (InodeData::Chunk(_) | InodeData::Nbt(_) | InodeData::Standalone(_)),
+                        mtime,
+                        ..
+                    }),
+                    Some(Inode {
+                        data: InodeData::Info(compression_type),
+                        ..
+                    })
+                ) => {
+                    // decompressed chunks are kept unsaved if they can't be stored as their .cmp says
+                    let stored = match chunk_data.stored(*compression_type, self.compression_level) {
+                        Ok(stored) if stored.len() < MAX_CHUNK_LEN => stored,
+                        Ok(stored) => {
+                            error!("> Chunk [{} {}] is too long to be saved ({} bytes once compressed)", x, z, stored.len());
+                            unsaved_chunks.push(dirty_idx);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("> Chunk [{} {}] could not be compressed as {}: {}", x, z, compression_type.name(), e);
+                            unsaved_chunks.push(dirty_idx);
+                            continue;
+                        }
+                    };
+
+                    info!("> {} chunk [{} {}] ({} bytes as {})", verb, x, z, stored.len(), compression_type.name());
+                    modified_chunks.push((x, z, stored, *compression_type, *mtime));
+                }
+                (Some(_), Some(_)) => warn!("> Chunk [{} {}] is broken and cannot be written", x, z),
+                _ => {
+                    info!("> {} deletion of chunk [{} {}]", verb, x, z);
+                    deleted_chunks.push((x, z));
+                }
+            }
+        }
+
+        // the changes stay in memory, where reads keep seeing them
+        if self.dry_run {
+            self.dirty_chunks.fill(false);
+            for idx in unsaved_chunks {
+                self.dirty_chunks.set(idx, true);
+            }
+            self.notify_status_polls();
+            return;
+        }
+
+        let saved_chunks = deleted_chunks.len() + modified_chunks.len();
+        let saved_bytes = modified_chunks.iter().map(|(_, _, data, _, _)| data.len()).sum::<usize>();
+
+        // Delete chunks first. Their sectors only become reusable once the new header is written,
+        // so that an interrupted write-out never leaves the old header pointing at reused sectors.
+        for (x, z) in deleted_chunks {
+            self.region.delete_chunk(x, z);
+        }
+
+        // Then release sectors from modified chunks (likewise deferred)
+        for &(x, z, _, _, _) in &modified_chunks {
+            self.region.free_chunk(x, z);
+        }
+
+        // write biggest chunks first, to reduce fragmentation
+        modified_chunks.sort_unstable_by_key(|(_, _, data, _, _)| usize::MAX - data.len());
+
+        // Then write modified chunks
+        for (x, z, data, compression_type, mtime) in &modified_chunks {
+            self.region.write_chunk(*x, *z, data, *compression_type, *mtime);
+        }
+
+        // write out to disk
+        let dirty_percent = self.region.dirty_percent();
+        let (changed, file) = self.backing_file.get_mut();
+        // a file changed behind our back has to be rewritten whole, and when most of it changed
+        // anyway, one sequential write beats seeking to each sector
+        let full_write = changed || dirty_percent > self.full_write_threshold as f64;
+        if changed {
+            info!("> Writing all sectors");
+        } else if full_write {
+            info!("> Writing all sectors, since {:.0}% of them changed", dirty_percent);
+        } else {
+            info!("> Writing changed sectors");
+        }
+        let result = match self.io_timeout {
+            Some(timeout) => match file.try_clone() {
+                Ok(file) => self.write_out_within(full_write, file, timeout),
+                Err(e) => Err(e),
+            },
+            None => self.region.write_out(full_write, file),
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.write_out(result.is_ok());
+        }
+
+        match result {
+            Ok(()) => {
+                self.dirty_chunks.fill(false);
+                for idx in unsaved_chunks {
+                    self.dirty_chunks.set(idx, true);
+                }
+                self.saved.chunks += saved_chunks;
+                self.saved.bytes += saved_bytes;
+                self.saved.write_outs += 1;
+                self.backing_file.written();
+                if !self.dirty_chunks.any() && let Some(journal) = &mut self.journal
+                    && let Err(e) = journal.reset(self.backing_file.get()) {
+                    warn!("Failed to clear journal `{}`, its edits will be replayed again: {}", journal.path().display(), e);
+                }
+                self.verify_written();
+                self.check_fullness();
+                // a failed verification makes the mount read-only, and the save doesn't count
+                if self.writable {
+                    self.run_on_save();
+                }
+            }
+            Err(err) => {
+                error!("Failed to write out region: {}", err);
+                if self.stalled_write.is_some() {
+                    // nothing is known about the file until the write finishes, so stop writing to it
+                    error!("Making the mount read-only until the stalled write finishes");
+                    self.writable = false;
+                }
+            }
+        }
+
+        self.update_metrics();
+        self.notify_status_polls();
+    }
+}
+
+/// FUSE operations, kept apart from [`Filesystem`] (which only replies with their results) so
+/// that they can run without a kernel, as the tests do
+impl SmithyFS {
+    fn lookup_entry(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<FileAttr, c_int> {
+        if parent != FUSE_ROOT_ID {
+            return Err(ENOENT);
+        }
+
+        if let Some(link) = name.to_str().and_then(|name| self.symlinks.iter().find(|link| link.name == name)) {
+            return Ok(self.stat_symlink(link));
+        }
+
+        if let Some(file) = name.to_str().and_then(|name| self.control_file_named(name)) {
+            return Ok(self.stat_control(file));
+        }
+
+        if let Some(key) = self.parse_key(name) {
+            //debug!("Parsed file name as chunk [{} {}] {:?}", key.x, key.z, key.kind);
+            let (writable, uid, gid) = (self.writable, self.uid, self.gid);
+
+            if let Some(inode) = self.get_inode_mut(key) {
+                inode.inc_lookup();
+                return Ok(inode.attr(writable, uid, gid));
+            }
+            //debug!("Chunk [{} {}] is missing", key.x, key.z);
+        }
+
+        //debug!("Failed to look up file {:?} in {}", name, parent);
+        Err(ENOENT)
+    }
+
+    fn forget_lookups(&mut self, ino: u64, nlookup: u64) {
+        let inode = match self.inodes.get_mut(&ino) {
+            Some(inode) => inode,
+            None => return
+        };
+
+        if inode.dec_lookup(nlookup) == 0 {
+            self.gc(ino);
+        }
+    }
+
+    fn attr_of(&self, ino: u64, fh: Option<u64>) -> Result<FileAttr, c_int> {
+        // prefer the open handle's view: it pins its inode even after an unlink, and control
+        // file handles hold edits that haven't been committed yet
+        if let Some(fh) = fh {
+            if let Some(handle) = self.control_handles.get(&fh).filter(|handle| handle.file.ino() == ino) {
+                let mut attr = self.stat_control(handle.file);
+                attr.size = handle.data.len() as u64;
+                return Ok(attr);
+            }
+
+            if let Some(inode) = self.inodes.get(&ino) && let Some(handle) = inode.open_handles.get(&fh) {
+                let mut attr = self.stat_inode(inode);
+                if let Some(text) = &handle.written {
+                    attr.size = text.len() as u64;
+                }
+                return Ok(attr);
+            }
+        }
+
+        if ino == FUSE_ROOT_ID {
+            Ok(self.stat_root())
+        } else if let Some(attr) = self.stat_ino(ino) {
+            Ok(attr)
+        } else if let Some(link) = self.get_symlink(ino) {
+            Ok(self.stat_symlink(link))
+        } else if let Some(file) = self.control_file(ino) {
+            Ok(self.stat_control(file))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    /// Create a chunk and open one of its files, returning that file's attributes and handle
+    fn create_file(&mut self, parent: u64, name: &std::ffi::OsStr, mode: u32, umask: u32, flags: i32) -> Result<(FileAttr, u64), c_int> {
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (true, false),
+            libc::O_WRONLY => (self.permissive_read, true),
+            libc::O_RDWR => (true, true),
+            _ => return Err(EINVAL)
+        };
+
+        let ino = self.create_chunk(parent, name, mode, umask)?;
+
+        let attr = self.stat_ino(ino).expect("just-created inode should exist");
+        let fh = self.fh_alloc.alloc();
+        self.inodes.get_mut(&ino)
+            .expect("just-created inode should exist")
+            .open_handles.insert(fh, FileHandle::new(read, write));
+
+        Ok((attr, fh))
+    }
+
+    /// Open a file, returning its handle and the `FOPEN_*` flags to open it with
+    fn open_file(&mut self, ino: u64, flags: i32) -> Result<(u64, u32), c_int> {
+        if !self.accepts_writes(ino) && (flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0) {
+            return Err(EROFS);
+        }
+
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => {
+                if flags & libc::O_TRUNC != 0{
+                    return Err(EACCES);
+                }
+                (true, false)
+            }
+            libc::O_WRONLY => {
+                (self.permissive_read, true)
+            }
+            libc::O_RDWR => {
+                (true, true)
+            }
+            _ => return Err(EINVAL)
+        };
+
+        if let Some(file) = self.control_file(ino) {
+            if write && !self.control_writable(file) {
+                return Err(EACCES);
+            }
+
+            let truncate = write && flags & libc::O_TRUNC != 0;
+            let data = if truncate { vec![] } else { self.read_control(file) };
+
+            let fh = self.fh_alloc.alloc();
+            self.control_handles.insert(fh, ControlHandle {
+                file,
+                handle: FileHandle::new(read, write),
+                data,
+                modified: truncate
+            });
+
+            // contents are generated on the fly, so the page cache would only serve stale data
+            return Ok((fh, FOPEN_DIRECT_IO));
+        }
+
+        let inode = self.inodes.get_mut(&ino).ok_or(ENOENT)?;
+
+        if write && inode.read_only {
+            return Err(EACCES);
+        }
+
+        // appended text would follow the whole selector, whose bracketed entry would still win
+        if let InodeData::Info(_) = inode.data && flags & libc::O_APPEND != 0 && flags & libc::O_TRUNC == 0 {
+            let (x, z) = (inode.x, inode.z);
+            warn!("{} can't be appended to, overwrite it instead", self.fname(FileKind::CompressionInfo, x, z));
+            return Err(EINVAL);
+        }
+
+        let mut handle = FileHandle::new(read, write);
+        let mut truncated = false;
+
+        // left to us rather than sent as a separate truncate, see `init`
+        if flags & libc::O_TRUNC != 0 {
+            match &mut inode.data {
+                InodeData::Chunk(data) | InodeData::Nbt(data) | InodeData::Standalone(data) => {
+                    data.clear();
+                    inode.mtime = header_time(SystemTime::now());
+                    truncated = true;
+                }
+                // the text is only applied on flush, so the .cmp can be empty until then
+                InodeData::Info(_) => handle.written = Some(vec![]),
+            }
+        }
+
+        let (x, z, linked) = (inode.x, inode.z, inode.linked);
+        let fh = self.fh_alloc.alloc();
+        inode.open_handles.insert(fh, handle);
+
+        if truncated {
+            self.mark_dirty(x, z);
+            if linked {
+                self.record(Entry::Truncate { x, z, len: 0 });
+            }
+        }
+
+        // O_NOATIME, O_NONBLOCK, O_SYNC and O_DSYNC need nothing here: there are no access times,
+        // nothing blocks, and chunks are saved as soon as they are closed (or fsynced) anyway.
+        // O_CREAT, O_EXCL and O_NOCTTY never get here, the kernel handles them before calling open
+        let open_flags = if flags & libc::O_DIRECT != 0 { FOPEN_DIRECT_IO } else { 0 };
+        Ok((fh, open_flags))
+    }
+
+    fn read_file(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Cow<'_, [u8]>, c_int> {
+        if let Some(file) = self.control_file(ino) {
+            // reading the status from the start again picks up any change, so that pollers can rewind
+            if file == ControlFile::Status && offset == 0 {
+                let status = self.status().into_bytes();
+                if let Some(handle) = self.control_handles.get_mut(&fh) {
+                    handle.data = status;
+                }
+            }
+
+            return match (self.control_handles.get(&fh), file_offset(offset)) {
+                (Some(handle), _) if !handle.handle.can_read() => Err(EACCES),
+                (Some(handle), Some(offset)) => Ok(Cow::Borrowed(slice_at(&handle.data, offset, size as usize))),
+                (Some(_), None) => Err(EINVAL),
+                (None, _) => Err(EBADF)
+            };
+        }
+
+        let inode = self.inodes.get(&ino).ok_or(ENOENT)?;
+        let handle = inode.open_handles.get(&fh).ok_or(EBADF)?;
+
+        if !handle.can_read() {
+            return Err(EACCES);
+        }
+        let offset = file_offset(offset).ok_or(EINVAL)?;
+        let size = match self.max_read {
+            Some(max_read) => size.min(max_read),
+            None => size
+        };
+
+        let data = match &handle.written {
+            Some(text) => Cow::Borrowed(slice_at(text, offset, size as usize)),
+            None => inode.data.read_at(offset, size as usize),
+        };
+
+        if let Some(metrics) = &self.metrics {
+            metrics.read(data.len());
+        }
+        Ok(data)
+    }
+
+    fn write_file(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8]) -> Result<u32, c_int> {
+        if !self.accepts_writes(ino) {
+            return Err(EROFS);
+        }
+
+        if self.control_file(ino).is_some() {
+            let handle = self.control_handles.get_mut(&fh).ok_or(EBADF)?;
+
+            if !handle.handle.can_write() {
+                return Err(EACCES);
+            }
+
+            let offset = file_offset(offset).ok_or(EINVAL)?;
+            let end = match offset.checked_add(data.len()) {
+                Some(end) if end < MAX_CHUNK_LEN => end,
+                _ => return Err(EFBIG)
+            };
+
+            if end > handle.data.len() {
+                handle.data.resize(end, 0);
+            }
+
+            handle.data[offset..end].copy_from_slice(data);
+            handle.modified = true;
+
+            return Ok(data.len() as u32);
+        }
+
+        let inode = self.inodes.get_mut(&ino).ok_or(ENOENT)?;
+        let handle = inode.open_handles.get_mut(&fh).ok_or(EBADF)?;
+
+        if !handle.can_write() {
+            return Err(EACCES);
+        }
+        let offset = file_offset(offset).ok_or(EINVAL)?;
+
+        // shells may split even `echo zstd >x0z0.cmp` into several writes, so the text is
+        // edited like a small file of its own and only parsed when the file is flushed
+        if let InodeData::Info(compression_type) = inode.data {
+            let end = match offset.checked_add(data.len()) {
+                Some(end) if end <= MAX_INFO_LEN => end,
+                _ => return Err(EFBIG)
+            };
+
+            let text = handle.written.get_or_insert_with(|| compression_type.make_selector_string().into_bytes());
+            if end > text.len() {
+                text.resize(end, 0);
+            }
+            text[offset..end].copy_from_slice(data);
+
+            if let Some(metrics) = &self.metrics {
+                metrics.written(data.len());
+            }
+            self.update_metrics();
+            return Ok(data.len() as u32);
+        }
+
+        let written = inode.data.write_at(offset, data)?;
+        // shown as it will be saved, so the listing doesn't change after a remount
+        inode.mtime = header_time(SystemTime::now());
+
+        // because the borrow checker (reasonably) doesn't trust us here. Perhaps separated
+        // fields would be good (but a pain). Rust could benefit from "field-restricted
+        // references" so that we can tell the compiler that SmithyFS::mark_dirty doesn't
+        // need access to the inodes field.
+        // TODO: ^ RFC this? ^
+        let (x, z, linked) = (inode.x, inode.z, inode.linked);
+
+        self.mark_dirty(x, z);
+        if linked {
+            self.record(Entry::Write { x, z, offset: offset as u64, data: data.to_vec() });
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.written(written);
+        }
+        self.update_metrics();
+        Ok(written as u32)
+    }
+
+    fn release_file(&mut self, ino: u64, fh: u64, lock_owner: Option<u64>, flush: bool) -> Result<(), c_int> {
+        if self.control_file(ino).is_some() {
+            let result = self.commit_control(fh);
+            self.control_handles.remove(&fh);
+            self.status_polls.retain(|(polled, _)| *polled != fh);
+
+            return result.unwrap_or(Err(EBADF));
+        }
+
+        // already applied by the flush before this, unless no flush came (or it failed)
+        let committed = self.commit_info(ino, fh);
+
+        let inode = self.inodes.get_mut(&ino).ok_or(ENOENT)?;
+        inode.open_handles.remove(&fh).ok_or(EBADF)?;
+
+        // flock locks belong to the open file, so they go away with it
+        if let Some(owner) = lock_owner {
+            inode.unlock(owner, 0, u64::MAX);
+        }
+
+        self.gc(ino);
+
+        if flush && self.writable {
+            self.write_back_dirty();
+        }
+
+        committed
+    }
+
+    /// Change a file's size or mode, returning its new attributes and whether a chunk's size
+    /// changed (which other views of it have to be told about)
+    fn set_attr(&mut self, ino: u64, mode: Option<u32>, owner: (Option<u32>, Option<u32>), size: Option<u64>, fh: Option<u64>) -> Result<(FileAttr, bool), c_int> {
+        // setattr is only sent to change something
+        if !self.accepts_writes(ino) {
+            return Err(EROFS);
+        }
+
+        if let Some(file) = self.control_file(ino) {
+            if let Some(target) = size {
+                // only an open handle has contents to truncate; they are regenerated otherwise
+                let handle = fh.and_then(|fh| self.control_handles.get_mut(&fh)).ok_or(EINVAL)?;
+                if !handle.handle.can_write() {
+                    return Err(EACCES);
+                }
+                // bounded like writes to it
+                let target = match usize::try_from(target) {
+                    Ok(target) if target < MAX_CHUNK_LEN => target,
+                    _ => return Err(EFBIG)
+                };
+
+                handle.data.resize(target, 0);
+                handle.modified = true;
+            }
+
+            return Ok((self.stat_control(file), false));
+        }
+
+        let inode = self.inodes.get_mut(&ino).ok_or(ENOENT)?;
+
+        if inode.read_only {
+            return Err(EPERM);
+        }
+
+        // truncate
+        if let Some(target) = size {
+            if let Some(handle) = fh.and_then(|fh| inode.open_handles.get(&fh)) {
+                if !handle.can_write() {
+                    return Err(EACCES);
+                }
+            }
+
+            let target = target as usize;
+            let max_len = inode.data.max_len();
+
+            // the edited text is cut instead, like writes to it
+            if let InodeData::Info(compression_type) = inode.data {
+                // without a handle there would be no flush to apply the shortened text
+                let handle = fh.and_then(|fh| inode.open_handles.get_mut(&fh)).ok_or(EINVAL)?;
+                if target > MAX_INFO_LEN {
+                    return Err(EFBIG);
+                }
+
+                handle.written.get_or_insert_with(|| compression_type.make_selector_string().into_bytes())
+                    .resize(target, 0);
+
+                let mut attr = inode.attr(self.writable, self.uid, self.gid);
+                attr.size = target as u64;
+                return Ok((attr, false));
+            }
+
+            match &mut inode.data {
+                InodeData::Chunk(chunk) | InodeData::Nbt(chunk) | InodeData::Standalone(chunk) => {
+                    if target >= max_len {
+                        return Err(EFBIG);
+                    }
+
+                    chunk.resize(target, 0);
+                    debug!("Resized ino {:#x?} to {} bytes", ino, target);
+                },
+                InodeData::Info(_) => {}
+            }
+
+            let attr = inode.attr(self.writable, self.uid, self.gid);
+            let (x, z, linked) = (inode.x, inode.z, inode.linked);
+
+            self.mark_dirty(x, z);
+            if linked {
+                self.record(Entry::Truncate { x, z, len: target as u64 });
+            }
+
+            return Ok((attr, true));
+        }
+
+        // chmod
+        if let Some(mode) = mode {
+            inode.perm = clamp_perm(mode);
+            debug!("Set mode of ino {:#x?} to {:#o}", ino, inode.perm);
+
+            return Ok((inode.attr(self.writable, self.uid, self.gid), false));
+        }
+
+        debug!(
+            "[Not Implemented] setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
+            gid: {:?}, size: {:?}, fh: {:?})",
+            ino, mode, owner.0, owner.1, size, fh
+        );
+        Err(ENOSYS)
+    }
+
+    /// What `setattr` does once it has replied: a resized file's cached size is stale, but
+    /// invalidating it while the kernel waits for the reply could deadlock
+    fn after_setattr(&self, ino: u64, resized: bool) {
+        if resized {
+            self.invalidate_attrs(ino);
+        }
+    }
+
+    /// What `unlink` does once it has replied: the kernel holds the directory until then, which
+    /// the notifications would wait for
+    fn after_unlink(&mut self, to_delete: &[DeletionInfo]) {
+        self.delete(to_delete);
+        self.write_back_dirty();
+    }
+
+    /// Unlink a chunk by either of its files' names, returning what to tell the kernel is gone
+    fn unlink_file(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<Vec<DeletionInfo>, c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+
+        if parent != FUSE_ROOT_ID {
+            return Err(ENOENT);
+        }
+
+        if name.to_str().and_then(|name| self.control_file_named(name)).is_some() {
+            return Err(EPERM);
+        }
+
+        let key = self.parse_key(name).ok_or(ENOENT)?;
+        if !key.kind.is_chunk() {
+            if self.get_inode(key).is_none() {
+                return Err(ENOENT);
+            }
+
+            warn!(
+                "{} can't be deleted on its own, delete {} to delete the chunk",
+                self.fname(key.kind, key.x, key.z), self.fname(FileKind::Chunk, key.x, key.z)
+            );
+            return Err(EPERM);
+        }
+
+        self.unlink_chunk(key.x, key.z)
+    }
+
+    /// Apply what was written through a handle and save it, for flush and fsync
+    fn save_file(&mut self, ino: u64, fh: u64) -> Result<(), c_int> {
+        if let Some(result) = self.commit_control(fh) {
+            return result;
+        }
+
+        if !self.writable {
+            return Err(ENOSYS);
+        }
+
+        self.commit_info(ino, fh)?;
+
+        let write_mode = self.inodes.get(&ino)
+            .and_then(|inode| inode.open_handles.get(&fh))
+            .map_or(false, FileHandle::can_write);
+
+        if write_mode {
+            self.write_back_dirty();
+            if self.stalled_write.is_some() {
+                return Err(ETIMEDOUT);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run one of the commands in [`ioctl`], returning its result and output
+    fn run_ioctl(&mut self, ino: u64, cmd: u32, out_size: u32) -> Result<(i32, Vec<u8>), c_int> {
+        match cmd {
+            ioctl::FLUSH => {
+                if !self.writable {
+                    return Err(EROFS);
+                }
+
+                self.write_back_dirty();
+                if self.dirty_chunks.any() {
+                    Err(EIO)
+                } else {
+                    Ok((0, vec![]))
+                }
+            }
+            ioctl::DEFRAG => self.defrag().map(|freed| (freed as i32, vec![])),
+            ioctl::GET_STATS => {
+                let region = self.region.stats();
+                let stats = ioctl::Stats {
+                    chunks: region.chunks,
+                    used_sectors: region.used_sectors,
+                    file_sectors: region.file_sectors,
+                    unsaved_chunks: self.dirty_chunks.count_ones() as u32,
+                };
+
+                let data = stats.to_bytes();
+                if (out_size as usize) < data.len() {
+                    Err(EINVAL)
+                } else {
+                    Ok((0, data))
+                }
+            }
+            ioctl::RESET => self.reset_chunk(ino).map(|()| (0, vec![])),
+            _ => Err(ENOTTY)
+        }
+    }
+}
+
+impl Filesystem for SmithyFS {
+    fn init(&mut self, _req: &fuser::Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        // advisory locks are tracked here, so that they work across clients
+        if let Err(unsupported) = config.add_capabilities(FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS) {
+            warn!("Kernel does not support remote file locking (capabilities {:#x})", unsupported);
+        }
+
+        // otherwise O_TRUNC arrives as a truncate without the handle being opened, which `.cmp`
+        // files need to know about
+        if config.add_capabilities(FUSE_ATOMIC_O_TRUNC).is_err() {
+            warn!("Kernel does not let the filesystem handle O_TRUNC, so `.cmp` files can only be replaced with O_TRUNC through ftruncate");
+        }
+
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        // changes are normally saved as files are closed, this catches failed saves and chunks
+        // that were created without ever being opened
+        let unsaved = self.dirty_chunks.count_ones();
+        if unsaved > 0 {
+            if self.discard_on_exit {
+                warn!("Discarding unsaved changes to {} chunks", unsaved);
+            } else {
+                info!("Saving {} chunks with unsaved changes before unmounting", unsaved);
+                self.write_back_dirty();
+            }
+        }
+
+        let dirty_chunks = self.dirty_chunks.count_ones();
+        if dirty_chunks > 0 && !self.discard_on_exit {
+            error!("Unmounting with unsaved changes to {} chunks, which could not be written", dirty_chunks);
+        }
+
+        info!(
+            "Unmounting after {:.1?}: saved {} chunks ({} bytes) in {} write-outs",
+            self.mounted_at.elapsed(), self.saved.chunks, self.saved.bytes, self.saved.write_outs
+        );
+
+        // a stalled write may still land, so others mustn't write until it has
+        let mut writing = false;
+        if let Some(stalled) = self.stalled_write.take() {
+            let timeout = self.io_timeout.unwrap_or_default();
+            info!("Waiting up to {:?} for the stalled write to the region file", timeout);
+            match stalled.recv_timeout(timeout) {
+                Ok(Ok(())) => info!("The stalled write finished after all"),
+                Ok(Err(e)) => warn!("The stalled write finally failed: {}", e),
+                Err(RecvTimeoutError::Disconnected) => warn!("The thread of the stalled write panicked"),
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!("The stalled write still hasn't finished, keeping the region file locked until Smithy exits");
+                    writing = true;
+                }
+            }
+        }
+
+        // nothing is written after this, so other writers needn't wait for the process to exit
+        if !writing && let Err(e) = self.backing_file.unlock() {
+            warn!("Failed to release the lock on the region file: {}", e);
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.write(dirty_chunks);
+        }
+    }
+
+    fn lookup(&mut self, _req: &fuser::Request<'_>, parent: u64, name: &std::ffi::OsStr, reply: fuser::ReplyEntry) {
+        match self.lookup_entry(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e)
+        }
+    }
+
+    fn forget(&mut self, _req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        self.forget_lookups(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, fh: Option<u64>, reply: fuser::ReplyAttr) {
+        match self.attr_of(ino, fh) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e)
+        }
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        let Some(link) = self.get_symlink(ino) else {
+            reply.error(if self.inodes.contains_key(&ino) { EINVAL } else { ENOENT });
+            return;
+        };
+
+        match self.resolve_link(link.target) {
+            Some((x, z)) => reply.data(FileKind::Chunk.make_fname(x, z).as_bytes()),
+            None => reply.error(ENOENT)
+        }
+    }
+
+    fn mknod(
+            &mut self,
+            _req: &fuser::Request<'_>,
+            parent: u64,
+            name: &std::ffi::OsStr,
+            mode: u32,
+            umask: u32,
+            _rdev: u32,
+            reply: fuser::ReplyEntry,
+        ) {
+        match self.create_chunk(parent, name, mode, umask) {
+            Ok(ino) => reply.entry(&TTL, &self.stat_ino(ino).expect("just-created inode should exist"), 0),
+            Err(e) => reply.error(e)
+        }
+    }
+
+    fn create(
+            &mut self,
+            _req: &fuser::Request<'_>,
+            parent: u64,
+            name: &std::ffi::OsStr,
+            mode: u32,
+            umask: u32,
+            flags: i32,
+            reply: fuser::ReplyCreate,
+        ) {
+        match self.create_file(parent, name, mode, umask, flags) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(e) => reply.error(e)
+        }
+    }
 
-        let open_flags = 0;
-        reply.opened(fh, open_flags);
+    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        match self.open_file(ino, flags) {
+            Ok((fh, open_flags)) => reply.opened(fh, open_flags),
+            Err(e) => reply.error(e)
+        }
     }
 
     fn opendir(&mut self, _req: &fuser::Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
@@ -845,7 +2685,7 @@ impl Filesystem for SmithyFS {
             return;
         }
 
-        let fh = self.create_dir_handle();
+        let fh = self.create_dir_handle(ino);
         let open_flags = 0;
         reply.opened(fh, open_flags);
     }
@@ -861,26 +2701,9 @@ impl Filesystem for SmithyFS {
             _lock_owner: Option<u64>,
             reply: fuser::ReplyData,
         ) {
-        let inode = match self.inodes.get(&ino) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let handle = match inode.open_handles.get(&fh) {
-            Some(handle) => handle,
-            None => {
-                reply.error(EBADF);
-                return;
-            }
-        };
-
-        if handle.can_read() {
-            inode.data.read(offset, size, reply);
-        } else {
-            reply.error(EACCES);
+        match self.read_file(ino, fh, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e)
         }
     }
 
@@ -896,40 +2719,9 @@ impl Filesystem for SmithyFS {
             _lock_owner: Option<u64>,
             reply: fuser::ReplyWrite,
         ) {
-        if !self.writable {
-            reply.error(EROFS);
-            return;
-        }
-
-        let inode = match self.inodes.get_mut(&ino) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let handle = match inode.open_handles.get(&fh) {
-            Some(handle) => handle,
-            None => {
-                reply.error(EBADF);
-                return;
-            }
-        };
-
-        if handle.can_write() {
-            inode.data.write(offset, data, reply);
-            inode.mtime = SystemTime::now();
-
-            // because the borrow checker (reasonably) doesn't trust us here. Perhaps separated
-            // fields would be good (but a pain). Rust could benefit from "field-restricted
-            // references" so that we can tell the compiler that SmithyFS::mark_dirty doesn't need
-            // access to the inodes field.
-            // TODO: ^ RFC this? ^
-            let (x, z) = (inode.x, inode.z);
-            self.mark_dirty(x, z);
-        } else {
-            reply.error(EACCES);
+        match self.write_file(ino, fh, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e)
         }
     }
 
@@ -990,33 +2782,13 @@ impl Filesystem for SmithyFS {
             ino: u64,
             fh: u64,
             _flags: i32,
-            _lock_owner: Option<u64>,
+            lock_owner: Option<u64>,
             flush: bool,
             reply: fuser::ReplyEmpty,
         ) {
-        let inode = match self.inodes.get_mut(&ino) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        match inode.open_handles.remove(&fh) {
-            Some(_) => {
-                self.gc(ino);
-
-                if flush && self.writable {
-                    self.write_back();
-                }
-
-                reply.ok();
-                return;
-            }
-            None => {
-                reply.error(EBADF);
-                return;
-            }
+        match self.release_file(ino, fh, lock_owner, flush) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e)
         }
     }
 
@@ -1035,147 +2807,771 @@ impl Filesystem for SmithyFS {
             _crtime: Option<SystemTime>,
             _chgtime: Option<SystemTime>,
             _bkuptime: Option<SystemTime>,
-            flags: Option<u32>,
+            _flags: Option<u32>,
             reply: fuser::ReplyAttr,
         ) {
-        let inode = match self.inodes.get_mut(&ino) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
-                return;
+        match self.set_attr(ino, mode, (uid, gid), size, fh) {
+            Ok((attr, resized)) => {
+                reply.attr(&TTL, &attr);
+                self.after_setattr(ino, resized);
             }
+            Err(e) => reply.error(e)
+        }
+    }
+
+    fn getlk(
+            &mut self,
+            _req: &fuser::Request<'_>,
+            ino: u64,
+            _fh: u64,
+            lock_owner: u64,
+            start: u64,
+            end: u64,
+            typ: i32,
+            pid: u32,
+            reply: fuser::ReplyLock,
+        ) {
+        let Some(inode) = self.inodes.get(&ino) else {
+            reply.error(ENOLCK);
+            return;
         };
 
-        // truncate
-        if let Some(target) = size {
-            if !self.writable {
-                reply.error(EROFS);
-                return;
+        let probe = FileLock { owner: lock_owner, start, end, typ, pid };
+
+        match inode.locks.iter().find(|lock| lock.conflicts(&probe)) {
+            Some(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, 0)
+        }
+    }
+
+    fn setlk(
+            &mut self,
+            _req: &fuser::Request<'_>,
+            ino: u64,
+            _fh: u64,
+            lock_owner: u64,
+            start: u64,
+            end: u64,
+            typ: i32,
+            pid: u32,
+            _sleep: bool,
+            reply: fuser::ReplyEmpty,
+        ) {
+        let Some(inode) = self.inodes.get_mut(&ino) else {
+            reply.error(ENOLCK);
+            return;
+        };
+
+        if typ == libc::F_UNLCK {
+            inode.unlock(lock_owner, start, end);
+            reply.ok();
+            return;
+        }
+
+        if typ != libc::F_RDLCK && typ != libc::F_WRLCK {
+            reply.error(EINVAL);
+            return;
+        }
+
+        let lock = FileLock { owner: lock_owner, start, end, typ, pid };
+
+        // requests are handled one at a time, so waiting for the lock would block its holder too
+        if inode.locks.iter().any(|held| held.conflicts(&lock)) {
+            reply.error(EAGAIN);
+            return;
+        }
+
+        // a new lock replaces whatever the owner held in its range
+        inode.unlock(lock_owner, start, end);
+        inode.locks.push(lock);
+
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &fuser::Request<'_>, ino: u64, name: &std::ffi::OsStr, size: u32, reply: fuser::ReplyXattr) {
+        let value = name.to_str()
+            .zip(self.inodes.get(&ino))
+            .and_then(|(name, inode)| self.xattr(inode, name));
+
+        match value {
+            Some(value) => reply_xattr(value.as_bytes(), size, reply),
+            None => reply.error(ENODATA)
+        }
+    }
+
+    fn listxattr(&mut self, _req: &fuser::Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        let mut names = vec![];
+
+        if let Some(inode) = self.inodes.get(&ino) {
+            for name in XATTRS.into_iter().filter(|name| self.xattr(inode, name).is_some()) {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
             }
+        }
 
-            if let Some(handle) = fh.and_then(|fh| inode.open_handles.get(&fh)) {
-                if !handle.can_write() {
-                    reply.error(EACCES);
-                    return;
+        reply_xattr(&names, size, reply);
+    }
+
+    fn poll(
+            &mut self,
+            _req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            ph: PollHandle,
+            _events: u32,
+            flags: u32,
+            reply: fuser::ReplyPoll,
+        ) {
+        // what the kernel assumes for filesystems without poll support
+        let always_ready = (libc::POLLIN | libc::POLLOUT | libc::POLLRDNORM | libc::POLLWRNORM) as u32;
+
+        if self.control_file(ino) != Some(ControlFile::Status) {
+            reply.poll(always_ready);
+            return;
+        }
+
+        let status = self.status().into_bytes();
+        match self.control_handles.get(&fh) {
+            Some(handle) if handle.data != status => reply.poll(always_ready),
+            Some(_) => {
+                if flags & FUSE_POLL_SCHEDULE_NOTIFY != 0 {
+                    // the kernel asks again on every poll, only the newest handle needs waking
+                    self.status_polls.retain(|(polled, _)| *polled != fh);
+                    self.status_polls.push((fh, ph));
                 }
+                reply.poll(0);
             }
+            None => reply.error(EBADF)
+        }
+    }
 
-            let target = target as usize;
+    fn ioctl(
+            &mut self,
+            _req: &fuser::Request<'_>,
+            ino: u64,
+            _fh: u64,
+            _flags: u32,
+            cmd: u32,
+            _in_data: &[u8],
+            out_size: u32,
+            reply: fuser::ReplyIoctl,
+        ) {
+        match self.run_ioctl(ino, cmd, out_size) {
+            Ok((result, data)) => reply.ioctl(result, &data),
+            Err(e) => reply.error(e)
+        }
+    }
 
-            match &mut inode.data {
-                InodeData::Chunk(chunk) => {
-                    if target >= MAX_CHUNK_LEN {
-                        reply.error(EFBIG);
-                        return;
-                    }
+    fn bmap(&mut self, _req: &fuser::Request<'_>, _ino: u64, _blocksize: u32, _idx: u64, reply: fuser::ReplyBmap) {
+        // chunks live inside the region file, not on blocks of a device; their position in it
+        // is available from the sector offset xattr instead
+        reply.error(EINVAL);
+    }
 
-                    chunk.resize(target, 0);
-                    debug!("Resized ino {:#x?} to {} bytes", ino, target);
-                },
-                InodeData::Info(_) => {}
+    fn unlink(&mut self, _req: &fuser::Request<'_>, parent: u64, name: &std::ffi::OsStr, reply: fuser::ReplyEmpty) {
+        match self.unlink_file(parent, name) {
+            Ok(to_delete) => {
+                // the kernel holds the directory until the unlink is answered, which the
+                // notifications would wait for
+                reply.ok();
+                self.after_unlink(&to_delete);
             }
+            Err(e) => reply.error(e)
+        }
+    }
 
-            let attr = inode.attr(self.writable, self.uid, self.gid);
-            let (x, z) = (inode.x, inode.z);
+    fn flush(&mut self, _req: &fuser::Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: fuser::ReplyEmpty) {
+        match self.save_file(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e)
+        }
+    }
 
-            self.mark_dirty(x, z);
+    fn fsync(&mut self, _req: &fuser::Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: fuser::ReplyEmpty) {
+        match self.save_file(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e)
+        }
+    }
+}
 
-            reply.attr(&TTL, &attr);
-            return;
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, fs::OpenOptions, os::unix::fs::FileExt};
+
+    use super::*;
+    use crate::{anvil::HEADER_LEN, codec::tests::COMPRESSIONS, util::TempPath};
+
+    /// A region file holding an empty chunk at each of `chunks`, compressed with zlib
+    fn region_file(chunks: &[(u8, u8)]) -> TempPath {
+        region_file_with(chunks, CompressionType::Zlib)
+    }
+
+    fn region_file_with(chunks: &[(u8, u8)], compression_type: CompressionType) -> TempPath {
+        let mut region = RegionFile::empty();
+        let data = codec::compress(compression_type, &nbt::empty_chunk(nbt::DEFAULT_DATA_VERSION)).unwrap();
+        for &(x, z) in chunks {
+            region.write_chunk(x, z, &data, compression_type, SystemTime::now());
         }
 
-        debug!(
-            "[Not Implemented] setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
-            gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
-            ino, mode, uid, gid, size, fh, flags
-        );
-        reply.error(ENOSYS);
+        let path = TempPath::new("r.0.0.mca");
+        let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path.0).unwrap();
+        region.write_out(true, &mut file).unwrap();
+        path
     }
 
-    fn unlink(&mut self, _req: &fuser::Request<'_>, parent: u64, name: &std::ffi::OsStr, reply: fuser::ReplyEmpty) {
-        if parent != FUSE_ROOT_ID {
-            reply.error(ENOENT);
-            return;
+    fn options(writable: bool) -> FsOptions {
+        FsOptions {
+            writable,
+            region_pos: (0, 0),
+            max_read: None,
+            symlinks: vec![],
+            expert: false,
+            progress: false,
+            hidden_cmp: false,
+            dir_order: DirOrder::Coord,
+            trim: false,
+            verify_writeback: false,
+            stable_inos: false,
+            discard_on_exit: false,
+            dry_run: false,
+            permissive_read: false,
+            view: ChunkView::Nbt,
+            compression_level: None,
+            warn_full: None,
+            full_write_threshold: 50,
+            chunk_template: nbt::empty_chunk(nbt::DEFAULT_DATA_VERSION),
+            metrics: None,
+            on_save: None,
+            io_timeout: None,
+            journal: None,
+            read_only_source: false,
+            backup_acknowledged: true,
         }
+    }
 
-        if let Some(key) = name.to_str().and_then(FileKey::parse) {
-            if !key.kind.is_chunk() {
-                reply.error(EACCES);
-                return;
-            }
+    /// Mount the region at `path` as `main` would
+    fn mount_with(path: &TempPath, options: FsOptions) -> SmithyFS {
+        let file = GuardedFile::new(&path.0, options.writable).unwrap();
+        let region = RegionFile::new_checked(std::fs::read(&path.0).unwrap(), false, false).unwrap();
+        SmithyFS::new(region, 0, 0, options, file)
+    }
 
-            let inos = match self.links.remove(&(key.x, key.z)) {
-                Some(inos) => inos,
-                None => {
-                    reply.error(ENOENT);
-                    return;
-                }
-            };
+    fn mount(path: &TempPath, writable: bool) -> SmithyFS {
+        mount_with(path, options(writable))
+    }
 
-            let mut to_delete = vec![];
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    enum Invalidation {
+        Entry(u64, String),
+        Inode(u64, i64, i64),
+    }
 
-            for ino in inos {
-                let inode = match self.inodes.get_mut(&ino) {
-                    Some(inode) => inode,
-                    None => continue
-                };
+    /// Records invalidations instead of sending them to a kernel
+    #[derive(Clone, Default)]
+    struct RecordingNotifier(Arc<Mutex<Vec<Invalidation>>>);
+    impl RecordingNotifier {
+        fn install(fs: &SmithyFS) -> Self {
+            let notifier = Self::default();
+            fs.notifier.lock().unwrap().replace(Box::new(notifier.clone()));
+            notifier
+        }
 
-                let (x, z) = (inode.x, inode.z);
+        fn take(&self) -> Vec<Invalidation> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+    impl Notify for RecordingNotifier {
+        fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+            self.0.lock().unwrap().push(Invalidation::Entry(parent, name.to_string_lossy().into_owned()));
+            Ok(())
+        }
 
-                inode.linked = false;
-                to_delete.push(DeletionInfo::from(inode));
+        fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+            self.0.lock().unwrap().push(Invalidation::Inode(ino, offset, len));
+            Ok(())
+        }
+    }
 
-                self.gc(ino);
-                self.mark_dirty(x, z);
-            }
+    impl SmithyFS {
+        fn lookup_ino(&mut self, name: &str) -> Result<u64, c_int> {
+            self.lookup_entry(FUSE_ROOT_ID, name.as_ref()).map(|attr| attr.ino)
+        }
 
-            if !to_delete.is_empty() {
-                reply.ok();
+        fn open_name(&mut self, name: &str, flags: i32) -> (u64, u64) {
+            let ino = self.lookup_ino(name).unwrap();
+            (ino, self.open_file(ino, flags).unwrap().0)
+        }
 
-                for del_info in to_delete {
-                    self.delete(del_info);
-                }
+        fn read_all(&mut self, ino: u64, fh: u64) -> Vec<u8> {
+            self.read_file(ino, fh, 0, u32::MAX).unwrap().into_owned()
+        }
+    }
 
-                self.write_back();
+    #[test]
+    fn root_is_its_own_parent() {
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, false);
 
-                return;
-            }
+        let fh = fs.create_dir_handle(FUSE_ROOT_ID);
+        let entries = &fs.dir_handles[&fh].entries;
+        assert_eq!(entries[0], (FUSE_ROOT_ID, FileType::Directory, ".".to_owned()));
+        assert_eq!(entries[1], (FUSE_ROOT_ID, FileType::Directory, "..".to_owned()));
+    }
+
+    #[test]
+    fn writes_are_compressed_once_per_save() {
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, true);
+
+        let (ino, fh) = fs.open_name("x0z0.nbt", libc::O_RDWR);
+        let nbt = fs.read_all(ino, fh);
+
+        COMPRESSIONS.with(|count| count.set(0));
+        // byte by byte, as the least efficient editor would
+        for (i, byte) in nbt.iter().enumerate() {
+            fs.write_file(ino, fh, i as i64, &[*byte]).unwrap();
         }
+        assert!(nbt.len() > 10);
+        assert_eq!(COMPRESSIONS.with(Cell::get), 0, "writes compressed the chunk");
 
-        reply.error(ENOENT);
+        fs.save_file(ino, fh).unwrap();
+        assert_eq!(COMPRESSIONS.with(Cell::get), 1);
     }
 
-    fn flush(&mut self, _req: &fuser::Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: fuser::ReplyEmpty) {
-        if !self.writable {
-            reply.error(ENOSYS);
-            return;
+    #[test]
+    fn read_only_mounts_refuse_every_change() {
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, false);
+        let ino = fs.lookup_ino("x0z0.nbt").unwrap();
+        let info = fs.lookup_ino("x0z0.cmp").unwrap();
+        let (_, fh) = fs.open_name("x0z0.nbt", libc::O_RDONLY);
+
+        for flags in [libc::O_WRONLY, libc::O_RDWR, libc::O_RDONLY | libc::O_TRUNC] {
+            assert_eq!(fs.open_file(ino, flags), Err(EROFS), "open with {:#o}", flags);
+            assert_eq!(fs.open_file(info, flags), Err(EROFS), "open .cmp with {:#o}", flags);
+        }
+        assert_eq!(fs.create_file(FUSE_ROOT_ID, "x1z0.nbt".as_ref(), libc::S_IFREG | 0o644, 0, libc::O_WRONLY).err(), Some(EROFS));
+        assert_eq!(fs.create_chunk(FUSE_ROOT_ID, "x1z0.nbt".as_ref(), libc::S_IFREG | 0o644, 0), Err(EROFS));
+        assert_eq!(fs.write_file(ino, fh, 0, b"x"), Err(EROFS));
+        assert_eq!(fs.set_attr(ino, None, (None, None), Some(0), None).err(), Some(EROFS));
+        assert_eq!(fs.set_attr(ino, Some(0o600), (None, None), None, None).err(), Some(EROFS));
+        assert_eq!(fs.unlink_file(FUSE_ROOT_ID, "x0z0.nbt".as_ref()).err(), Some(EROFS));
+        for cmd in [ioctl::FLUSH, ioctl::DEFRAG, ioctl::RESET] {
+            assert_eq!(fs.run_ioctl(ino, cmd, 0), Err(EROFS), "ioctl {:#x}", cmd);
         }
 
-        let write_mode = self.inodes.get(&ino)
-            .and_then(|inode| inode.open_handles.get(&fh))
-            .map_or(false, FileHandle::can_write);
+        // the mount being read-only comes before any other complaint
+        assert_eq!(fs.unlink_file(FUSE_ROOT_ID, "x9z9.nbt".as_ref()).err(), Some(EROFS));
+        assert_eq!(fs.unlink_file(ino, "x0z0.nbt".as_ref()).err(), Some(EROFS));
+        assert_eq!(fs.create_file(FUSE_ROOT_ID, "garbage".as_ref(), libc::S_IFREG | 0o644, 0, libc::O_WRONLY).err(), Some(EROFS));
+        assert_eq!(fs.create_chunk(FUSE_ROOT_ID, "garbage".as_ref(), libc::S_IFREG | 0o644, 0), Err(EROFS));
+        assert_eq!(fs.set_attr(99999, None, (None, None), Some(0), None).err(), Some(EROFS));
+        assert_eq!(fs.write_file(99999, fh, 0, b"x"), Err(EROFS));
+
+        // and nothing changed
+        assert_eq!(fs.read_all(ino, fh), nbt::empty_chunk(nbt::DEFAULT_DATA_VERSION));
+        assert!(!fs.dirty_chunks.any());
+    }
 
-        if write_mode {
-            self.write_back();
+    #[test]
+    fn relabelling_recompresses_the_chunk() {
+        let path = region_file_with(&[(0, 0)], CompressionType::GZip);
+        let mut fs = mount(&path, true);
+
+        let (ino, fh) = fs.open_name("x0z0.cmp", libc::O_WRONLY | libc::O_TRUNC);
+        fs.write_file(ino, fh, 0, b"zstd\n").unwrap();
+        fs.save_file(ino, fh).unwrap();
+        fs.release_file(ino, fh, None, false).unwrap();
+
+        let region = RegionFile::new_checked(std::fs::read(&path.0).unwrap(), false, false).unwrap();
+        assert!(region.load_warnings().is_empty());
+        let chunk = region.lookup_chunk(0, 0).unwrap();
+        assert_eq!(chunk.compression_type.encode(), 53);
+        assert_eq!(codec::decompress(CompressionType::Zstd, chunk.data).unwrap(), nbt::empty_chunk(nbt::DEFAULT_DATA_VERSION));
+    }
+
+    #[test]
+    fn unlinked_chunks_can_be_stated_through_their_handle() {
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, true);
+
+        let (ino, fh) = fs.open_name("x0z0.nbt", libc::O_RDONLY);
+        let size = fs.read_all(ino, fh).len() as u64;
+        fs.unlink_file(FUSE_ROOT_ID, "x0z0.nbt".as_ref()).unwrap();
+
+        assert_eq!(fs.lookup_ino("x0z0.nbt"), Err(ENOENT));
+        let attr = fs.attr_of(ino, Some(fh)).unwrap();
+        assert_eq!((attr.ino, attr.size), (ino, size));
+        assert_eq!(fs.read_all(ino, fh).len() as u64, size);
+    }
+
+    #[test]
+    fn chunks_keep_their_own_compression() {
+        let path = region_file(&[(0, 0), (1, 0), (2, 0)]);
+        let mut fs = mount(&path, true);
+
+        for (name, selector) in [("x0z0.cmp", "gzip"), ("x1z0.cmp", "zstd")] {
+            let (ino, fh) = fs.open_name(name, libc::O_WRONLY | libc::O_TRUNC);
+            fs.write_file(ino, fh, 0, selector.as_bytes()).unwrap();
+            fs.release_file(ino, fh, None, true).unwrap();
+        }
+        // rewrite every chunk's data, so each is compressed again with its own type
+        for name in ["x0z0.nbt", "x1z0.nbt", "x2z0.nbt"] {
+            let (ino, fh) = fs.open_name(name, libc::O_RDWR);
+            let nbt = fs.read_all(ino, fh);
+            fs.write_file(ino, fh, 0, &nbt).unwrap();
+            fs.release_file(ino, fh, None, true).unwrap();
         }
 
-        reply.ok();
+        let region = RegionFile::new_checked(std::fs::read(&path.0).unwrap(), false, false).unwrap();
+        let types: Vec<u8> = (0..3).map(|x| region.lookup_chunk(x, 0).unwrap().compression_type.encode()).collect();
+        assert_eq!(types, [1, 53, 2]);
     }
 
-    fn fsync(&mut self, _req: &fuser::Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: fuser::ReplyEmpty) {
-        if !self.writable {
-            reply.error(ENOSYS);
-            return;
+    #[test]
+    fn mtimes_match_the_header() {
+        let path = region_file(&[(0, 0)]);
+        let header_mtime = |path: &TempPath| {
+            let region = RegionFile::new_checked(std::fs::read(&path.0).unwrap(), false, false).unwrap();
+            region.lookup_chunk(0, 0).unwrap().mtime
+        };
+        let mut fs = mount(&path, true);
+
+        let (ino, fh) = fs.open_name("x0z0.nbt", libc::O_RDWR);
+        let loaded = fs.attr_of(ino, None).unwrap().mtime;
+        assert_eq!(loaded, header_mtime(&path));
+
+        // a write's mtime is what will be saved, not the sub-second time it happened at
+        let nbt = fs.read_all(ino, fh);
+        std::thread::sleep(Duration::from_millis(1100));
+        fs.write_file(ino, fh, 0, &nbt).unwrap();
+        let mtime = fs.attr_of(ino, None).unwrap().mtime;
+        assert!(mtime > loaded);
+        fs.save_file(ino, fh).unwrap();
+        assert_eq!(mtime, header_mtime(&path));
+    }
+
+    #[test]
+    fn chunks_can_be_created_over_invalid_headers() {
+        let path = region_file(&[(0, 0)]);
+        // point [1 0] past the end of the file
+        let mut data = std::fs::read(&path.0).unwrap();
+        data[4 * coords_to_idx(1, 0)..][..4].copy_from_slice(&(0x100 << 8 | 1u32).to_be_bytes());
+        std::fs::write(&path.0, data).unwrap();
+
+        let mut fs = mount(&path, true);
+        assert!(fs.region.is_pending_deletion(1, 0));
+        assert_eq!(fs.lookup_ino("x1z0.nbt"), Err(ENOENT));
+
+        let nbt = nbt::empty_chunk(nbt::DEFAULT_DATA_VERSION);
+        let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "x1z0.nbt".as_ref(), libc::S_IFREG | 0o644, 0, libc::O_WRONLY).unwrap();
+        fs.write_file(attr.ino, fh, 0, &nbt).unwrap();
+        fs.release_file(attr.ino, fh, None, true).unwrap();
+
+        let region = RegionFile::new_checked(std::fs::read(&path.0).unwrap(), false, false).unwrap();
+        assert!(region.load_warnings().is_empty());
+        assert_eq!(region.present_chunks().collect::<Vec<_>>(), [(0, 0), (1, 0)]);
+        let chunk = region.lookup_chunk(1, 0).unwrap();
+        assert_eq!(codec::decompress(chunk.compression_type, chunk.data).unwrap(), nbt);
+    }
+
+    #[test]
+    fn cmp_writes_are_applied_on_flush() {
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, true);
+
+        let (ino, fh) = fs.open_name("x0z0.cmp", libc::O_WRONLY | libc::O_TRUNC);
+        // as `echo zstd >` might split it
+        for (offset, part) in [(0, "zs"), (2, "t"), (3, "d\n")] {
+            fs.write_file(ino, fh, offset, part.as_bytes()).unwrap();
         }
+        // nothing is applied before the flush, a partial name could mean another codec
+        let (reader_ino, reader) = fs.open_name("x0z0.cmp", libc::O_RDONLY);
+        assert_eq!(fs.read_all(reader_ino, reader), CompressionType::Zlib.make_selector_string().as_bytes());
+        assert_eq!(fs.attr_of(ino, Some(fh)).unwrap().size, 5);
 
-        let write_mode = self.inodes.get(&ino)
-            .and_then(|inode| inode.open_handles.get(&fh))
-            .map_or(false, FileHandle::can_write);
+        fs.save_file(ino, fh).unwrap();
+        let (reader_ino, reader) = fs.open_name("x0z0.cmp", libc::O_RDONLY);
+        assert_eq!(fs.read_all(reader_ino, reader), CompressionType::Zstd.make_selector_string().as_bytes());
 
-        if write_mode {
-            self.write_back();
+        let region = RegionFile::new_checked(std::fs::read(&path.0).unwrap(), false, false).unwrap();
+        assert_eq!(region.lookup_chunk(0, 0).unwrap().compression_type, CompressionType::Zstd);
+    }
+
+    #[test]
+    fn cmp_files_cannot_be_removed() {
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, true);
+
+        assert_eq!(fs.unlink_file(FUSE_ROOT_ID, "x0z0.cmp".as_ref()).err(), Some(EPERM));
+        assert_eq!(fs.unlink_file(FUSE_ROOT_ID, "x1z0.cmp".as_ref()).err(), Some(ENOENT));
+        assert!(fs.lookup_ino("x0z0.cmp").is_ok());
+        assert!(!fs.dirty_chunks.any());
+    }
+
+    #[test]
+    fn open_flags() {
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, true);
+        let ino = fs.lookup_ino("x0z0.nbt").unwrap();
+        let info = fs.lookup_ino("x0z0.cmp").unwrap();
+
+        for flag in [libc::O_NOATIME, libc::O_NONBLOCK, libc::O_SYNC, libc::O_DSYNC, libc::O_APPEND] {
+            for file in [ino, info] {
+                let flags = libc::O_RDWR | flag;
+                match fs.open_file(file, flags) {
+                    Err(EINVAL) if file == info && flag == libc::O_APPEND => {}
+                    Ok((fh, 0)) => fs.release_file(file, fh, None, false).unwrap(),
+                    other => panic!("opening inode {} with {:#o}: {:?}", file, flags, other),
+                }
+            }
         }
 
-        reply.ok();
+        let (fh, open_flags) = fs.open_file(ino, libc::O_RDONLY | libc::O_DIRECT).unwrap();
+        assert_eq!(open_flags, FOPEN_DIRECT_IO);
+        fs.release_file(ino, fh, None, false).unwrap();
+
+        // replacing the text is fine, however it was opened
+        let (fh, _) = fs.open_file(info, libc::O_WRONLY | libc::O_APPEND | libc::O_TRUNC).unwrap();
+        fs.write_file(info, fh, 0, b"gzip\n").unwrap();
+        fs.release_file(info, fh, None, true).unwrap();
+        let (fh, _) = fs.open_file(info, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read_all(info, fh), CompressionType::GZip.make_selector_string().as_bytes());
+    }
+
+    #[test]
+    fn huge_offsets_dont_panic() {
+        let data = [1, 2, 3];
+        assert_eq!(slice_at(&data, usize::MAX, usize::MAX), []);
+        assert_eq!(slice_at(&data, 1, usize::MAX), [2, 3]);
+        assert_eq!(file_offset(-1), None);
+        assert_eq!(file_offset(i64::MAX), Some(i64::MAX as usize));
+
+        let mut chunk = InodeData::Chunk(data.to_vec());
+        assert_eq!(chunk.write_at(usize::MAX, &[1]), Err(EFBIG));
+        assert_eq!(chunk.write_at(usize::MAX - 1, &[1, 2, 3]), Err(EFBIG));
+        assert_eq!(chunk.read_at(usize::MAX, usize::MAX).as_ref(), []);
+        assert_eq!(chunk.len(), 3);
+
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, true);
+        for name in ["x0z0.nbt", "x0z0.cmp", ".smithy-writable"] {
+            let (ino, fh) = fs.open_name(name, libc::O_RDWR);
+            assert_eq!(fs.write_file(ino, fh, i64::MAX, b"zstd\n"), Err(EFBIG), "{}", name);
+            assert_eq!(fs.write_file(ino, fh, -1, b"zstd\n"), Err(EINVAL), "{}", name);
+            assert_eq!(fs.read_file(ino, fh, i64::MAX, u32::MAX).unwrap().as_ref(), [], "{}", name);
+            assert_eq!(fs.read_file(ino, fh, -1, u32::MAX).err(), Some(EINVAL), "{}", name);
+        }
+        assert!(!fs.dirty_chunks.any());
+    }
+
+    #[test]
+    fn every_deletion_is_notified() {
+        let chunks: Vec<_> = (0..32).map(|x| (x, x % 4)).collect();
+        let path = region_file(&chunks);
+        let mut fs = mount_with(&path, FsOptions { expert: true, ..options(true) });
+        let notifier = RecordingNotifier::install(&fs);
+
+        // an empty header drops every chunk in one reload
+        let (ino, fh) = fs.open_name(".smithy-header", libc::O_WRONLY | libc::O_TRUNC);
+        fs.write_file(ino, fh, 0, &[0; HEADER_LEN]).unwrap();
+        fs.save_file(ino, fh).unwrap();
+        assert_eq!(fs.region.present_chunks().count(), 0);
+
+        let mut notified: Vec<_> = notifier.take().into_iter()
+            .filter(|invalidation| matches!(invalidation, Invalidation::Entry(..)))
+            .collect();
+        notified.sort();
+        let mut expected: Vec<_> = chunks.iter()
+            .flat_map(|(x, z)| ["nbt", "cmp"].map(|ext| Invalidation::Entry(FUSE_ROOT_ID, format!("x{}z{}.{}", x, z, ext))))
+            .collect();
+        expected.sort();
+        assert_eq!(notified, expected);
+    }
+
+    #[test]
+    fn truncating_invalidates_attrs_after_the_reply() {
+        let path = region_file(&[(0, 0)]);
+        let mut fs = mount(&path, true);
+        let notifier = RecordingNotifier::install(&fs);
+        let (ino, fh) = fs.open_name("x0z0.nbt", libc::O_RDWR);
+
+        let (attr, resized) = fs.set_attr(ino, None, (None, None), Some(3), Some(fh)).unwrap();
+        assert_eq!((attr.size, resized), (3, true));
+        // the kernel is still waiting for the reply, an invalidation now could deadlock
+        assert_eq!(notifier.take(), []);
+        fs.after_setattr(ino, resized);
+        assert_eq!(notifier.take(), [Invalidation::Inode(ino, -1, 0)]);
+
+        let (_, resized) = fs.set_attr(ino, Some(0o600), (None, None), None, Some(fh)).unwrap();
+        assert!(!resized);
+        fs.after_setattr(ino, resized);
+        assert_eq!(notifier.take(), []);
+    }
+
+    #[test]
+    fn unlinking_notifies_after_the_reply() {
+        let path = region_file(&[(0, 0), (1, 0)]);
+        let mut fs = mount(&path, true);
+        let notifier = RecordingNotifier::install(&fs);
+
+        let to_delete = fs.unlink_file(FUSE_ROOT_ID, "x0z0.nbt".as_ref()).unwrap();
+        assert_eq!(notifier.take(), []);
+        fs.after_unlink(&to_delete);
+
+        let mut notified = notifier.take();
+        notified.sort();
+        assert_eq!(notified, [
+            Invalidation::Entry(FUSE_ROOT_ID, "x0z0.cmp".to_owned()),
+            Invalidation::Entry(FUSE_ROOT_ID, "x0z0.nbt".to_owned()),
+        ]);
+        // and the deletion is saved
+        assert!(!fs.dirty_chunks.any());
+        let region = RegionFile::new_checked(std::fs::read(&path.0).unwrap(), false, false).unwrap();
+        assert_eq!(region.present_chunks().collect::<Vec<_>>(), [(1, 0)]);
+    }
+
+    #[test]
+    fn chunk_files_are_kept_together() {
+        let path = region_file(&[(0, 0)]);
+        // stored data is what a relabelling would convert
+        let mut fs = mount_with(&path, FsOptions { view: ChunkView::Stored, ..options(true) });
+
+        // only the .cmp is looked up and forgotten, the chunk stays whole
+        let info = fs.lookup_ino("x0z0.cmp").unwrap();
+        fs.lookup_ino("x0z0.cmp").unwrap();
+        fs.forget_lookups(info, 2);
+        let ino = fs.lookup_ino("x0z0.nbt").unwrap();
+        assert!(fs.inodes.contains_key(&info));
+        assert_eq!(fs.lookup_ino("x0z0.cmp"), Ok(info));
+        fs.forget_lookups(info, 1);
+        fs.forget_lookups(ino, 1);
+
+        // a .cmp still open once its chunk is deleted and created again
+        let (info, stale) = fs.open_name("x0z0.cmp", libc::O_WRONLY | libc::O_TRUNC);
+        fs.unlink_file(FUSE_ROOT_ID, "x0z0.nbt".as_ref()).unwrap();
+        assert!(!fs.inodes.contains_key(&ino), "the .nbt was neither looked up nor open");
+        assert!(fs.inodes.contains_key(&info));
+
+        // zlib data labelled as uncompressed, which relabelling from zlib would decompress
+        let stored = codec::compress(CompressionType::Zlib, &nbt::empty_chunk(nbt::DEFAULT_DATA_VERSION)).unwrap();
+        let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "x0z0.nbt".as_ref(), libc::S_IFREG | 0o644, 0, libc::O_WRONLY).unwrap();
+        fs.set_attr(attr.ino, None, (None, None), Some(0), Some(fh)).unwrap();
+        fs.write_file(attr.ino, fh, 0, &stored).unwrap();
+        fs.release_file(attr.ino, fh, None, true).unwrap();
+        // the entry `create` replied with counts as a lookup
+        assert_eq!(fs.inodes[&attr.ino].nlookup, 1);
+        let created = fs.lookup_ino("x0z0.cmp").unwrap();
+        assert_ne!(created, info);
+
+        fs.write_file(info, stale, 0, b"gzip\n").unwrap();
+        fs.release_file(info, stale, None, true).unwrap();
+        fs.forget_lookups(info, 1);
+        assert!(!fs.inodes.contains_key(&info));
+
+        let (created, fh) = fs.open_name("x0z0.cmp", libc::O_RDONLY);
+        assert_eq!(fs.read_all(created, fh), CompressionType::None.make_selector_string().as_bytes());
+        let (chunk, fh) = fs.open_name("x0z0.nbt", libc::O_RDONLY);
+        assert_eq!(fs.read_all(chunk, fh), stored);
+        assert!(!fs.dirty_chunks.any());
+        fs.release_file(chunk, fh, None, false).unwrap();
+
+        // forgetting the created .nbt (and the lookup since) lets it go once it is deleted
+        fs.forget_lookups(chunk, 2);
+        assert_eq!(fs.inodes[&chunk].nlookup, 0);
+        fs.unlink_file(FUSE_ROOT_ID, "x0z0.nbt".as_ref()).unwrap();
+        assert!(!fs.inodes.contains_key(&chunk));
+        // and a kernel forgetting more than it was given is survived
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "x1z0.nbt".as_ref(), libc::S_IFREG | 0o644, 0, libc::O_RDONLY).unwrap();
+        fs.forget_lookups(attr.ino, 5);
+        assert_eq!(fs.inodes[&attr.ino].nlookup, 0);
+    }
+
+    #[test]
+    fn failed_defrag_keeps_the_layout() {
+        let path = region_file(&[(0, 0), (1, 0), (2, 0)]);
+        let mut fs = mount(&path, true);
+        let to_delete = fs.unlink_file(FUSE_ROOT_ID, "x0z0.nbt".as_ref()).unwrap();
+        fs.delete(&to_delete);
+        fs.write_back_dirty();
+        let layout = [(1, 0), (2, 0)].map(|(x, z)| fs.region.chunk_sectors(x, z));
+        let saved = std::fs::read(&path.0).unwrap();
+
+        // where `replace` would write the new file
+        let name = path.0.file_name().unwrap().to_string_lossy();
+        let tmp = TempPath(path.0.with_file_name(format!(".{}.smithy-tmp", name)));
+        std::fs::write(&tmp.0, b"").unwrap();
+
+        assert_eq!(fs.run_ioctl(FUSE_ROOT_ID, ioctl::DEFRAG, 0), Err(EIO));
+        assert_eq!([(1, 0), (2, 0)].map(|(x, z)| fs.region.chunk_sectors(x, z)), layout);
+        assert_eq!(std::fs::read(&path.0).unwrap(), saved);
+
+        // a later save still writes to the sectors the header on disk knows about
+        let (ino, fh) = fs.open_name("x2z0.nbt", libc::O_RDWR);
+        let nbt = fs.read_all(ino, fh);
+        fs.write_file(ino, fh, 0, &nbt).unwrap();
+        fs.save_file(ino, fh).unwrap();
+        let region = RegionFile::new_checked(std::fs::read(&path.0).unwrap(), false, false).unwrap();
+        assert!(region.load_warnings().is_empty());
+        assert_eq!(region.present_chunks().collect::<Vec<_>>(), [(1, 0), (2, 0)]);
+
+        drop(tmp);
+        assert!(fs.run_ioctl(FUSE_ROOT_ID, ioctl::DEFRAG, 0).is_ok());
+    }
+
+    #[test]
+    fn header_replacement_waits_for_unsaved_chunks() {
+        let path = region_file(&[(0, 0), (1, 0)]);
+        let mut fs = mount_with(&path, FsOptions { expert: true, ..options(true) });
+
+        // stored uncompressed, this is too long to save
+        let (info, fh) = fs.open_name("x0z0.cmp", libc::O_WRONLY | libc::O_TRUNC);
+        fs.write_file(info, fh, 0, b"none\n").unwrap();
+        fs.release_file(info, fh, None, true).unwrap();
+        let (ino, fh) = fs.open_name("x0z0.nbt", libc::O_WRONLY);
+        let huge = vec![1; MAX_CHUNK_LEN];
+        fs.write_file(ino, fh, 0, &huge).unwrap();
+
+        let header = std::fs::read(&path.0).unwrap()[..HEADER_LEN].to_vec();
+        let (header_ino, header_fh) = fs.open_name(".smithy-header", libc::O_WRONLY);
+        fs.write_file(header_ino, header_fh, 0, &header).unwrap();
+        assert_eq!(fs.save_file(header_ino, header_fh), Err(EIO));
+
+        assert!(fs.dirty_chunks[coords_to_idx(0, 0)]);
+        let (reopened, fh) = fs.open_name("x0z0.nbt", libc::O_RDONLY);
+        assert_eq!(reopened, ino);
+        assert_eq!(fs.read_all(ino, fh), huge);
+    }
+
+    #[test]
+    fn failed_verification_makes_the_mount_read_only() {
+        let path = region_file(&[(0, 0), (1, 0)]);
+        let mut fs = mount_with(&path, FsOptions { verify_writeback: true, ..options(true) });
+
+        // a chunk left unsaved as too long, which a save on the way to read-only would retry
+        let (info, fh) = fs.open_name("x0z0.cmp", libc::O_WRONLY | libc::O_TRUNC);
+        fs.write_file(info, fh, 0, b"none\n").unwrap();
+        fs.release_file(info, fh, None, true).unwrap();
+        let (ino, fh) = fs.open_name("x0z0.nbt", libc::O_WRONLY);
+        fs.write_file(ino, fh, 0, &vec![1; MAX_CHUNK_LEN]).unwrap();
+        fs.save_file(ino, fh).unwrap();
+        assert!(fs.writable && fs.dirty_chunks.any());
+
+        // and the file no longer holds what was written, in a sector a partial write won't touch
+        // again, without its mtime giving that away
+        let (offset, _) = fs.region.chunk_sectors(1, 0).unwrap();
+        let file = OpenOptions::new().write(true).open(&path.0).unwrap();
+        let mtime = file.metadata().unwrap().modified().unwrap();
+        file.write_all_at(&[0xff; 16], offset as u64 * SECTOR_LEN as u64 + 8).unwrap();
+        file.set_modified(mtime).unwrap();
+        drop(file);
+        let saved = std::fs::read(&path.0).unwrap();
+        fs.verify_written();
+
+        assert!(!fs.writable);
+        assert!(fs.dirty_chunks.any());
+        assert_eq!(std::fs::read(&path.0).unwrap(), saved);
+        assert_eq!(fs.write_file(ino, fh, 0, b"x"), Err(EROFS));
     }
 }
@@ -13,29 +13,171 @@
 * along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::{fs::{File, OpenOptions}, path::Path, time::SystemTime};
+use std::{fs::{self, File, OpenOptions}, io::{self, ErrorKind}, os::unix::fs::{fchown, MetadataExt}, path::{Path, PathBuf}, thread, time::{Duration, SystemTime}};
+
+use log::{info, warn};
 
 pub(crate) struct GuardedFile {
     file: File,
-    known_mtime: SystemTime
+    path: PathBuf,
+    known_mtime: SystemTime,
+    /// Only compare the file's mtime with mtimes it had before, never with the local clock
+    network: bool
 }
 impl GuardedFile {
+    /// Open an existing file. Writable files are locked against other writers (that use advisory
+    /// locks) for as long as they stay open.
     pub(crate) fn new<P: AsRef<Path>>(path: P, writable: bool) -> std::io::Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .write(writable)
             .create(false)
-            .open(path)?;
+            .open(&path)?;
+
+        if writable {
+            file.try_lock()?;
+        }
+
+        let known_mtime = file.metadata()?.modified()?;
+
+        Ok(Self { file, path: path.as_ref().to_path_buf(), known_mtime, network: false })
+    }
+
+    /// Open an existing file as in [`GuardedFile::new`], trying up to `retries` more times while
+    /// another writer holds the lock. The wait starts at `delay` and doubles after every attempt.
+    pub(crate) fn new_retrying<P: AsRef<Path>>(path: P, writable: bool, retries: u32, mut delay: Duration) -> std::io::Result<Self> {
+        let mut attempt = 0;
+        loop {
+            match Self::new(&path, writable) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock && attempt < retries => {
+                    attempt += 1;
+                    info!("{} is locked, trying again in {:?} ({}/{})", path.as_ref().display(), delay, attempt, retries);
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Create a new file, failing if it already exists. It is locked as in [`GuardedFile::new`].
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        file.try_lock()?;
 
         let known_mtime = file.metadata()?.modified()?;
 
-        Ok(Self { file, known_mtime })
+        Ok(Self { file, path: path.as_ref().to_path_buf(), known_mtime, network: false })
+    }
+
+    /// Adapt change detection to a file on a network filesystem, whose mtimes come from the
+    /// server's clock (which may be skewed against ours) and may only have whole-second
+    /// precision. Changes made within the same timestamp as one of ours go unnoticed.
+    pub(crate) fn set_network(&mut self, network: bool) {
+        self.network = network;
+    }
+
+    /// Record the file's mtime after writing it, for network change detection
+    pub(crate) fn written(&mut self) {
+        if !self.network {
+            return;
+        }
+
+        if let Ok(mtime) = self.file.metadata().and_then(|meta| meta.modified()) {
+            self.known_mtime = mtime;
+        }
+    }
+
+    /// Open the same file again with different access, taking or releasing the writer lock. On
+    /// failure the current handle is kept.
+    pub(crate) fn reopen(&mut self, writable: bool) -> std::io::Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(&self.path)?;
+
+        if writable {
+            file.try_lock()?;
+        }
+
+        // dropping the old handle releases its lock
+        self.file = file;
+        Ok(())
+    }
+
+    /// Release the writer lock early, for when nothing will be written anymore
+    pub(crate) fn unlock(&self) -> io::Result<()> {
+        self.file.unlock()
+    }
+
+    /// Replace the whole file: `write` fills a new file next to it, which is then renamed over it
+    /// and kept open (and locked) in its place. Either the old or the new contents survive a crash.
+    pub(crate) fn replace(&mut self, write: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<()> {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = self.path.with_file_name(format!(".{}.smithy-tmp", name));
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+
+        let result = file.try_lock()
+            .map_err(io::Error::from)
+            .and_then(|()| self.copy_access(&file))
+            .and_then(|()| write(&mut file))
+            .and_then(|()| fs::rename(&tmp_path, &self.path));
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        // make the rename itself durable
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+
+        self.known_mtime = file.metadata()?.modified()?;
+        self.file = file;
+        Ok(())
+    }
+
+    /// Give `file` the mode and ownership of the current file, so that whoever could use the
+    /// region (such as a server running as its own user) still can after a replacement
+    fn copy_access(&self, file: &File) -> io::Result<()> {
+        let meta = self.file.metadata()?;
+        file.set_permissions(meta.permissions())?;
+
+        let new_meta = file.metadata()?;
+        if (new_meta.uid(), new_meta.gid()) != (meta.uid(), meta.gid()) {
+            // only root can give files away, but anyone can pick among their own groups
+            if let Err(e) = fchown(file, Some(meta.uid()), Some(meta.gid())) {
+                warn!("Could not keep the region's owner {}:{}: {}", meta.uid(), meta.gid(), e);
+                fchown(file, None, Some(meta.gid())).unwrap_or_else(|e| {
+                    warn!("Could not keep the region's group {}: {}", meta.gid(), e);
+                });
+            }
+        }
+
+        Ok(())
     }
 
     pub(crate) fn get(&self) -> &File {
         &self.file
     }
 
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
     /// (changed, file)
     pub(crate) fn get_mut(&mut self) -> (bool, &mut File) {
         let now = SystemTime::now();
@@ -45,9 +187,34 @@ impl GuardedFile {
             Err(_) => now
         };
 
-        let changed = mtime > self.known_mtime;
-        self.known_mtime = now;
+        let changed = if self.network {
+            mtime != self.known_mtime
+        } else {
+            let changed = mtime > self.known_mtime;
+            self.known_mtime = now;
+            changed
+        };
 
         (changed, &mut self.file)
     }
 }
+
+/// A path in the system's temp directory that no other test uses, removed on drop
+#[cfg(test)]
+pub(crate) struct TempPath(pub(crate) PathBuf);
+#[cfg(test)]
+impl TempPath {
+    pub(crate) fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let unique = NEXT.fetch_add(1, Ordering::Relaxed);
+        Self(std::env::temp_dir().join(format!("smithy-test-{}-{}-{}", std::process::id(), unique, name)))
+    }
+}
+#[cfg(test)]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}